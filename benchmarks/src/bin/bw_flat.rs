@@ -1,19 +1,21 @@
 use benchmarks::{
     data_controllers::{wait_all, FlatController},
-    BandwidthOptions, IovecArgs,
+    BandwidthOptions, IovecArgs, Results,
 };
 use clap::Parser;
 use datatypes::DataType;
 use flat::FlatBuffer;
 use std::net::SocketAddr;
 
-fn benchmark<T, P>(args: IovecArgs, opts: BandwidthOptions, prepare: P) -> Vec<(usize, f32)>
+fn benchmark<T, P>(args: IovecArgs, opts: BandwidthOptions, prepare: P) -> Results
 where
     T: FlatBuffer + Default,
     P: Fn(usize) -> Vec<T>,
 {
     let sockaddr = SocketAddr::from((args.address.octets(), args.port));
     let sm = safe_mpi::init(sockaddr, args.server).expect("Failed to initialize safe_mpi");
+    sm.preconnect().expect("Failed to connect to the other processes");
+    let transport = sm.world().transport_info(if args.server { 1 } else { 0 });
     let world = FlatController::new(sm.world());
 
     let rank = if args.server { 0 } else { 1 };
@@ -21,7 +23,7 @@ where
     let mut rbufs: Vec<Vec<T>> = (0..opts.window_size)
         .map(|_| (0..opts.max_size).map(|_| T::default()).collect())
         .collect();
-    benchmarks::bw(opts, rank, prepare, |rank, window_size, sbuf| {
+    let results = benchmarks::bw(opts, rank, prepare, |rank, window_size, sbuf| {
         world.scope(|scope| {
             let mut reqs = vec![];
             if rank == 0 {
@@ -44,7 +46,8 @@ where
         } else {
             world.send(&ack_msg[..], 0).unwrap();
         }
-    })
+    });
+    (results, transport)
 }
 
 fn main() {
@@ -57,7 +60,5 @@ fn main() {
         DataType::ComplexCompound => panic!("complex compound is not supported"),
     };
 
-    for (size, bw) in results {
-        println!("{} {}", size, bw);
-    }
+    benchmarks::print_results(results);
 }
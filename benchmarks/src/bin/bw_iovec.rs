@@ -1,24 +1,26 @@
 use benchmarks::{
     data_controllers::{wait_all, IovecController},
-    BandwidthOptions, IovecArgs,
+    BandwidthOptions, IovecArgs, Results,
 };
 use clap::Parser;
 use datatypes::DataType;
 use iovec::ChunkSerDe;
 use std::net::SocketAddr;
 
-fn benchmark<T, P>(args: IovecArgs, opts: BandwidthOptions, prepare: P) -> Vec<(usize, f32)>
+fn benchmark<T, P>(args: IovecArgs, opts: BandwidthOptions, prepare: P) -> Results
 where
     T: ChunkSerDe,
     P: Fn(usize) -> Vec<T>,
 {
     let sockaddr = SocketAddr::from((args.address.octets(), args.port));
     let sm = safe_mpi::init(sockaddr, args.server).expect("Failed to initialize safe_mpi");
+    sm.preconnect().expect("Failed to connect to the other processes");
+    let transport = sm.world().transport_info(if args.server { 1 } else { 0 });
     let world = IovecController::new(sm.world());
 
     let rank = if args.server { 0 } else { 1 };
     let ack_msg = vec![0i32];
-    benchmarks::bw(opts, rank, prepare, |rank, window_size, sbuf| {
+    let results = benchmarks::bw(opts, rank, prepare, |rank, window_size, sbuf| {
         world.scope(|scope| {
             let mut reqs = vec![];
             if rank == 0 {
@@ -40,7 +42,8 @@ where
         } else {
             world.send(&ack_msg, 0).unwrap();
         }
-    })
+    });
+    (results, transport)
 }
 
 fn main() {
@@ -53,7 +56,5 @@ fn main() {
         DataType::ComplexCompound => benchmark(args, opts, datatypes::complex_compound),
     };
 
-    for (size, bw) in results {
-        println!("{} {}", size, bw);
-    }
+    benchmarks::print_results(results);
 }
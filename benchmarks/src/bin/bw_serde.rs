@@ -3,7 +3,7 @@ use benchmarks::{
         wait_all, BincodeController, MessagePackController, PostcardController, SerdeController,
         SerdeScope,
     },
-    BandwidthOptions, SerKind, SerdeArgs,
+    BandwidthOptions, Results, SerKind, SerdeArgs,
 };
 use clap::Parser;
 use datatypes::DataType;
@@ -44,17 +44,19 @@ where
     })
 }
 
-fn benchmark<T, P>(args: SerdeArgs, opts: BandwidthOptions, prepare: P) -> Vec<(usize, f32)>
+fn benchmark<T, P>(args: SerdeArgs, opts: BandwidthOptions, prepare: P) -> Results
 where
     T: Serialize + DeserializeOwned,
     P: Fn(usize) -> Vec<T>,
 {
     let sockaddr = SocketAddr::from((args.address.octets(), args.port));
     let sm = safe_mpi::init(sockaddr, args.server).expect("Failed to initialize safe_mpi");
+    sm.preconnect().expect("Failed to connect to the other processes");
+    let transport = sm.world().transport_info(if args.server { 1 } else { 0 });
     let world = sm.world();
 
     let rank = if args.server { 0 } else { 1 };
-    match args.kind {
+    let results = match args.kind {
         SerKind::MessagePack => {
             let comm = MessagePackController::new(world);
             serde_bw(opts, rank, comm, prepare)
@@ -67,7 +69,8 @@ where
             let comm = BincodeController::new(world);
             serde_bw(opts, rank, comm, prepare)
         }
-    }
+    };
+    (results, transport)
 }
 
 fn main() {
@@ -80,7 +83,5 @@ fn main() {
         DataType::ComplexCompound => benchmark(args, opts, datatypes::complex_compound),
     };
 
-    for (size, bw) in results {
-        println!("{} {}", size, bw);
-    }
+    benchmarks::print_results(results);
 }
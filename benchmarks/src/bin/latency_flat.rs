@@ -1,23 +1,25 @@
-use benchmarks::{data_controllers::FlatController, IovecArgs, LatencyOptions};
+use benchmarks::{data_controllers::FlatController, IovecArgs, LatencyOptions, Results};
 use clap::Parser;
 use datatypes::DataType;
 use flat::FlatBuffer;
 use std::net::SocketAddr;
 
-fn benchmark<T, P>(args: IovecArgs, opts: LatencyOptions, prepare: P) -> Vec<(usize, f32)>
+fn benchmark<T, P>(args: IovecArgs, opts: LatencyOptions, prepare: P) -> Results
 where
     T: FlatBuffer + Default,
     P: Fn(usize) -> Vec<T>,
 {
     let sockaddr = SocketAddr::from((args.address.octets(), args.port));
     let sm = safe_mpi::init(sockaddr, args.server).expect("Failed to initialize safe_mpi");
+    sm.preconnect().expect("Failed to connect to the other processes");
+    let transport = sm.world().transport_info(if args.server { 1 } else { 0 });
     let world = FlatController::new(sm.world());
 
     let rank = if args.server { 0 } else { 1 };
     // Set up the receive buffers
     let mut rbuf0: Vec<T> = (0..opts.max_size).map(|_| T::default()).collect();
     let mut rbuf1: Vec<T> = (0..opts.max_size).map(|_| T::default()).collect();
-    benchmarks::latency(
+    let results = benchmarks::latency(
         opts,
         rank.try_into().unwrap(),
         prepare,
@@ -29,7 +31,8 @@ where
             world.recv(&mut rbuf1[..sbuf.len()], 0).unwrap();
             world.send(sbuf, 0).unwrap();
         },
-    )
+    );
+    (results, transport)
 }
 
 fn main() {
@@ -42,7 +45,5 @@ fn main() {
         DataType::ComplexCompound => panic!("complex compound is not supported"),
     };
 
-    for (size, lat) in results {
-        println!("{} {}", size, lat);
-    }
+    benchmarks::print_results(results);
 }
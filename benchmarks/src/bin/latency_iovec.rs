@@ -1,20 +1,22 @@
-use benchmarks::{data_controllers::IovecController, IovecArgs, LatencyOptions};
+use benchmarks::{data_controllers::IovecController, IovecArgs, LatencyOptions, Results};
 use clap::Parser;
 use datatypes::DataType;
 use iovec::ChunkSerDe;
 use std::net::SocketAddr;
 
-fn benchmark<T, P>(args: IovecArgs, opts: LatencyOptions, prepare: P) -> Vec<(usize, f32)>
+fn benchmark<T, P>(args: IovecArgs, opts: LatencyOptions, prepare: P) -> Results
 where
     T: ChunkSerDe,
     P: Fn(usize) -> Vec<T>,
 {
     let sockaddr = SocketAddr::from((args.address.octets(), args.port));
     let sm = safe_mpi::init(sockaddr, args.server).expect("Failed to initialize safe_mpi");
+    sm.preconnect().expect("Failed to connect to the other processes");
+    let transport = sm.world().transport_info(if args.server { 1 } else { 0 });
     let world = IovecController::new(sm.world());
 
     let rank = if args.server { 0 } else { 1 };
-    benchmarks::latency(
+    let results = benchmarks::latency(
         opts,
         rank,
         prepare,
@@ -26,7 +28,8 @@ where
             let _data: Vec<T> = world.recv(0).unwrap();
             world.send(s_buf, 0).unwrap();
         },
-    )
+    );
+    (results, transport)
 }
 
 fn main() {
@@ -39,7 +42,5 @@ fn main() {
         DataType::ComplexCompound => benchmark(args, opts, datatypes::complex_compound),
     };
 
-    for (size, lat) in results {
-        println!("{} {}", size, lat);
-    }
+    benchmarks::print_results(results);
 }
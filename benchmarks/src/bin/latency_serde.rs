@@ -2,7 +2,7 @@ use benchmarks::{
     data_controllers::{
         BincodeController, MessagePackController, PostcardController, SerdeController,
     },
-    LatencyOptions, SerKind, SerdeArgs,
+    LatencyOptions, Results, SerKind, SerdeArgs,
 };
 use clap::Parser;
 use datatypes::DataType;
@@ -36,17 +36,19 @@ where
     )
 }
 
-fn benchmark<T, P>(args: SerdeArgs, opts: LatencyOptions, prepare: P) -> Vec<(usize, f32)>
+fn benchmark<T, P>(args: SerdeArgs, opts: LatencyOptions, prepare: P) -> Results
 where
     T: Serialize + DeserializeOwned,
     P: Fn(usize) -> Vec<T>,
 {
     let sockaddr = SocketAddr::from((args.address.octets(), args.port));
     let sm = safe_mpi::init(sockaddr, args.server).expect("Failed to initialize safe_mpi");
+    sm.preconnect().expect("Failed to connect to the other processes");
+    let transport = sm.world().transport_info(if args.server { 1 } else { 0 });
     let world = sm.world();
 
     let rank = if args.server { 0 } else { 1 };
-    match args.kind {
+    let results = match args.kind {
         SerKind::MessagePack => {
             let comm = MessagePackController::new(world);
             serde_latency(opts, rank, comm, prepare)
@@ -59,7 +61,8 @@ where
             let comm = BincodeController::new(world);
             serde_latency(opts, rank, comm, prepare)
         }
-    }
+    };
+    (results, transport)
 }
 
 fn main() {
@@ -72,7 +75,5 @@ fn main() {
         DataType::ComplexCompound => benchmark(args, opts, datatypes::complex_compound),
     };
 
-    for (size, lat) in results {
-        println!("{} {}", size, lat);
-    }
+    benchmarks::print_results(results);
 }
@@ -1,4 +1,5 @@
 use crate::data_controllers::{
+    peer,
     serde::{SerdeController, SerdeScope},
     Progress,
 };
@@ -29,7 +30,7 @@ impl SerdeController for BincodeController {
         unsafe {
             let buf = bincode::serialize(data).map_err(|_| Error::SerializeError)?;
             let data = [Iov(buf.as_ptr(), buf.len())];
-            self.comm.send(&data, tag)
+            self.comm.send(peer(&self.comm), &data, tag)
         }
     }
 
@@ -37,7 +38,7 @@ impl SerdeController for BincodeController {
     where
         T: Serialize + DeserializeOwned,
     {
-        let buf = self.comm.recv_probe(tag)?;
+        let buf = self.comm.recv_probe(Some(peer(&self.comm)), tag)?;
         // bincode::deserialize(&buf)
         //    .map_err(|_| Error::DeserializeError)
         Ok(bincode::deserialize(&buf).unwrap())
@@ -48,7 +49,7 @@ impl SerdeController for BincodeController {
         F: FnOnce(&mut Self::Scope) -> R,
     {
         f(&mut BincodeScope {
-            comm: self.comm.dup(),
+            comm: self.comm.clone(),
             requests: vec![],
         })
     }
@@ -73,9 +74,11 @@ impl SerdeScope for BincodeScope {
             let i = self.requests.len();
             let data = bincode::serialize(data).map_err(|_| Error::SerializeError)?;
             let data = Some(data);
-            let req = self
-                .comm
-                .isend(Data::Contiguous(data.as_ref().unwrap()), tag)?;
+            let req = self.comm.isend(
+                peer(&self.comm),
+                Data::Contiguous(data.as_ref().unwrap()),
+                tag,
+            )?;
             // This is valid as long as self.data[i] and self.requests[i] are
             // always freed at the same time
             let req: Box<Box<dyn SRequest>> = Box::new(Box::new(req));
@@ -87,7 +90,7 @@ impl SerdeScope for BincodeScope {
 
     fn irecv(&mut self, tag: Tag) -> Result<usize> {
         let i = self.requests.len();
-        let req = self.comm.irecv_probe(tag)?;
+        let req = self.comm.irecv_probe(Some(peer(&self.comm)), tag)?;
         let req: Box<Box<dyn SRequest>> = Box::new(Box::new(req));
         let rptr = Box::into_raw(req) as *mut c_void;
         self.requests.push(RequestData { rptr, _data: None });
@@ -1,5 +1,5 @@
 //! Data controller for types that implement FlatBuffer.
-use crate::data_controllers::Progress;
+use crate::data_controllers::{peer, Progress};
 use flat::FlatBuffer;
 use safe_mpi::{
     communicator::Communicator, Error, Iov, MutIov, Request as SRequest, RequestStatus, Result, Tag,
@@ -32,7 +32,7 @@ impl FlatController {
                 Iov(count_ptr, std::mem::size_of::<usize>()),
                 Iov(data.ptr(), data.size()),
             ];
-            self.comm.send(&iovecs[..], tag)
+            self.comm.send(peer(&self.comm), &iovecs[..], tag)
         }
     }
 
@@ -49,7 +49,8 @@ impl FlatController {
                 MutIov(count.as_mut_ptr() as *mut _, std::mem::size_of::<usize>()),
                 MutIov(data.ptr_mut(), data.size()),
             ];
-            self.comm.recv_iov(&iovecs[..], tag)?;
+            self.comm
+                .recv_iov(Some(peer(&self.comm)), &iovecs[..], tag)?;
             let type_id = type_id.assume_init();
             let count = count.assume_init();
             if type_id != <T as FlatBuffer>::type_id() {
@@ -68,7 +69,7 @@ impl FlatController {
         F: for<'scope> FnOnce(&mut FlatScope<'scope, 'env>) -> R,
     {
         f(&mut FlatScope {
-            comm: self.comm.dup(),
+            comm: self.comm.clone(),
             requests: vec![],
             scope: PhantomData,
             env: PhantomData,
@@ -110,7 +111,7 @@ impl<'scope, 'env> FlatScope<'scope, 'env> {
                 Iov(count as *const u8, std::mem::size_of::<usize>()),
                 Iov(data.ptr(), data.size()),
             ];
-            let req = self.comm.isend_iov(&iovecs, tag)?;
+            let req = self.comm.isend_iov(peer(&self.comm), &iovecs, tag)?;
             let req: Box<Box<dyn SRequest>> = Box::new(Box::new(req));
             let rptr = Box::into_raw(req) as *mut c_void;
             self.requests.push(Request {
@@ -140,7 +141,7 @@ impl<'scope, 'env> FlatScope<'scope, 'env> {
                 MutIov(count as *mut _, std::mem::size_of::<usize>()),
                 MutIov(data.ptr_mut(), data.size()),
             ];
-            let req = self.comm.irecv_iov(&iovecs, tag)?;
+            let req = self.comm.irecv_iov(Some(peer(&self.comm)), &iovecs, tag)?;
             let req: Box<Box<dyn SRequest>> = Box::new(Box::new(req));
             let rptr = Box::into_raw(req) as *mut c_void;
             self.requests.push(Request {
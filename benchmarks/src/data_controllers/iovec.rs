@@ -1,4 +1,4 @@
-use crate::data_controllers::Progress;
+use crate::data_controllers::{peer, Progress};
 use iovec::{Chunk, ChunkSerDe};
 use safe_mpi::{
     communicator::{Communicator, Data},
@@ -30,7 +30,7 @@ impl IovecController {
                     Chunk::Data(data) => Iov(data.as_ptr(), data.len()),
                 })
                 .collect();
-            self.comm.send(&send_data, tag)
+            self.comm.send(peer(&self.comm), &send_data, tag)
         }
     }
 
@@ -38,7 +38,7 @@ impl IovecController {
     where
         T: ChunkSerDe,
     {
-        let buf = self.comm.recv_probe(tag)?;
+        let buf = self.comm.recv_probe(Some(peer(&self.comm)), tag)?;
         // TODO: Should map errors to more specific message
         let (data, _size) = T::deserialize(&buf).map_err(|_| Error::DeserializeError)?;
         Ok(data)
@@ -50,7 +50,7 @@ impl IovecController {
         F: for<'scope> FnOnce(&mut IovecScope<'scope, 'env>) -> R,
     {
         f(&mut IovecScope {
-            comm: self.comm.dup(),
+            comm: self.comm.clone(),
             requests: vec![],
             scope: PhantomData,
             env: PhantomData,
@@ -100,7 +100,9 @@ impl<'scope, 'env> IovecScope<'scope, 'env> {
                 })
                 .collect();
             let send_data = Box::new(send_data);
-            let req = self.comm.isend(Data::Chunked(&send_data[..]), tag)?;
+            let req = self
+                .comm
+                .isend(peer(&self.comm), Data::Chunked(&send_data[..]), tag)?;
             let req: Box<Box<dyn SRequest>> = Box::new(Box::new(req));
             let rptr = Box::into_raw(req) as *mut c_void;
             self.requests.push(Request {
@@ -118,7 +120,7 @@ impl<'scope, 'env> IovecScope<'scope, 'env> {
     /// Returns the request index.
     pub fn irecv(&mut self, tag: Tag) -> Result<usize> {
         let i = self.requests.len();
-        let req = self.comm.irecv_probe(tag)?;
+        let req = self.comm.irecv_probe(Some(peer(&self.comm)), tag)?;
         let req: Box<Box<dyn SRequest>> = Box::new(Box::new(req));
         let rptr = Box::into_raw(req) as *mut c_void;
         self.requests.push(Request { rptr, data: None });
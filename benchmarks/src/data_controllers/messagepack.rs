@@ -1,4 +1,5 @@
 use crate::data_controllers::{
+    peer,
     serde::{SerdeController, SerdeScope},
     Progress,
 };
@@ -26,7 +27,7 @@ impl SerdeController for MessagePackController {
         unsafe {
             let buf = rmp_serde::to_vec(data).map_err(|_| Error::SerializeError)?;
             let data = [Iov(buf.as_ptr(), buf.len())];
-            self.comm.send(&data, tag)
+            self.comm.send(peer(&self.comm), &data, tag)
         }
     }
 
@@ -34,7 +35,7 @@ impl SerdeController for MessagePackController {
     where
         T: Serialize + DeserializeOwned,
     {
-        let buf = self.comm.recv_probe(tag)?;
+        let buf = self.comm.recv_probe(Some(peer(&self.comm)), tag)?;
         rmp_serde::decode::from_slice(&buf).map_err(|_| Error::DeserializeError)
     }
 
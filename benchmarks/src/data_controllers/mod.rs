@@ -11,7 +11,12 @@ pub use self::serde::{SerdeController, SerdeScope};
 mod flat;
 pub use self::flat::FlatController;
 
-use safe_mpi::{RequestStatus, Result};
+use safe_mpi::{communicator::Communicator, RequestStatus, Result};
+
+/// Rank of the process the benchmarks talk to (the next one, wrapping around)
+pub(crate) fn peer(comm: &Communicator) -> usize {
+    (comm.rank() + 1) % comm.size()
+}
 
 pub trait Progress {
     type Request: Copy;
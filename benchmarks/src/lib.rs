@@ -1,4 +1,5 @@
 use clap::{Parser, ValueEnum};
+use safe_mpi::TransportInfo;
 use serde::de::DeserializeOwned;
 use std::net::Ipv4Addr;
 use std::path::Path;
@@ -78,3 +79,21 @@ where
     serde_yaml::from_reader(std::fs::File::open(path).map_err(|_| BenchmarkError::IOError)?)
         .map_err(|_| BenchmarkError::DeserializeError)
 }
+
+/// Results of a benchmark: the value for each message size and the
+/// transports used to reach the other process
+pub type Results = (Vec<(usize, f32)>, safe_mpi::Result<TransportInfo>);
+
+/// Print the results, one `size value` line per message size, followed by
+/// the transports. The blank line in between is where the parsers in
+/// `scripts/benchmark.py` stop.
+pub fn print_results((results, transport): Results) {
+    for (size, value) in results {
+        println!("{} {}", size, value);
+    }
+    println!();
+    match transport {
+        Ok(transport) => print!("{}", transport),
+        Err(err) => println!("Failed to query the transports: {:?}", err),
+    }
+}
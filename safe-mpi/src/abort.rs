@@ -0,0 +1,139 @@
+//! Aborting the whole job.
+//!
+//! `Context::abort()` sends an active message to every process this one
+//! knows about. Its UCP handler records the abort, after which pending and
+//! new operations on the receiving processes fail with `Error::Aborted`
+//! instead of waiting for messages that won't come.
+use crate::{
+    callbacks::abort_recv_callback, failure::PeerState, request::wait_nbx_until, Context, Error,
+    Handle, Result,
+};
+use log::error;
+use std::cell::RefCell;
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::rc::{Rc, Weak};
+use std::sync::Once;
+use std::time::{Duration, Instant};
+use ucx2_sys::{
+    ucp_am_handler_param_t, ucp_am_send_nbx, ucp_worker_flush_nbx, ucp_worker_h,
+    ucp_worker_set_am_recv_handler, UCP_AM_HANDLER_PARAM_FIELD_ARG, UCP_AM_HANDLER_PARAM_FIELD_CB,
+    UCP_AM_HANDLER_PARAM_FIELD_ID, UCS_OK,
+};
+
+/// UCP active message ID of abort messages
+const ABORT_AM_ID: u32 = 2;
+/// How long to try to get the abort messages out
+const ABORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Error code used when aborting because of a panic, the exit code of a
+/// panicking Rust program
+const PANIC_CODE: i32 = 101;
+
+/// Header of abort messages: rank of the aborting process and error code
+pub(crate) type AbortHeader = [u64; 2];
+
+thread_local! {
+    /// Context to abort from the panic hook
+    static PANIC_HANDLE: RefCell<Option<Weak<RefCell<Handle>>>> = const { RefCell::new(None) };
+}
+
+/// Installs the panic hook only once
+static PANIC_HOOK: Once = Once::new();
+
+/// Register the UCP handler for abort messages on `worker`. `peers` has to
+/// outlive the worker.
+pub(crate) unsafe fn register(worker: ucp_worker_h, peers: &Rc<PeerState>) -> Result<()> {
+    let params = ucp_am_handler_param_t {
+        field_mask: (UCP_AM_HANDLER_PARAM_FIELD_ID
+            | UCP_AM_HANDLER_PARAM_FIELD_CB
+            | UCP_AM_HANDLER_PARAM_FIELD_ARG)
+            .into(),
+        id: ABORT_AM_ID,
+        cb: Some(abort_recv_callback),
+        arg: Rc::as_ptr(peers) as *mut c_void,
+        ..Default::default()
+    };
+    let status = ucp_worker_set_am_recv_handler(worker, &params);
+    if status != UCS_OK {
+        return Err(Error::AmHandlerFailure(status));
+    }
+    Ok(())
+}
+
+impl Context {
+    /// Abort the job: tell all other processes, in this world and in jobs
+    /// connected to it, that this process is giving up with `code`, and
+    /// exit with it. Their pending and future operations fail with
+    /// `Error::Aborted(rank, code)`, where `rank` is the world rank of this
+    /// process.
+    pub fn abort(&self, code: i32) -> ! {
+        notify(&mut self.handle.borrow_mut(), code);
+        std::process::exit(code)
+    }
+
+    /// Abort the job when this thread panics, after the panic message has
+    /// been printed, so that the other processes don't wait forever for
+    /// this one. The panic then goes on as usual.
+    pub fn abort_on_panic(&self) {
+        PANIC_HANDLE.with(|handle| *handle.borrow_mut() = Some(Rc::downgrade(&self.handle)));
+        PANIC_HOOK.call_once(|| {
+            let previous = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                previous(info);
+                let handle = PANIC_HANDLE
+                    .try_with(|handle| handle.borrow().as_ref().and_then(Weak::upgrade))
+                    .ok()
+                    .flatten();
+                if let Some(handle) = handle {
+                    // The panic may have happened with the handle borrowed
+                    match handle.try_borrow_mut() {
+                        Ok(mut handle) => notify(&mut handle, PANIC_CODE),
+                        Err(_) => error!("Failed to abort the job after a panic"),
+                    }
+                }
+            }));
+        });
+    }
+}
+
+/// Send the abort message to every other known process and flush it out,
+/// giving up after `ABORT_TIMEOUT`. Failures are only logged, there's
+/// nothing else to do about them at this point.
+fn notify(handle: &mut Handle, code: i32) {
+    error!("Aborting the job with code {}", code);
+    let deadline = Instant::now() + ABORT_TIMEOUT;
+    let worker = Rc::clone(&handle.worker);
+    // Sends may not have completed when giving up, so the header has to
+    // stay valid
+    let header: &'static AbortHeader = Box::leak(Box::new([handle.rank as u64, code as u64]));
+    for rank in 0..handle.addrs.len() {
+        if rank == handle.rank || handle.peers.is_failed(rank) {
+            continue;
+        }
+        let sent = handle.endpoint(rank).and_then(|ep| unsafe {
+            wait_nbx_until(&worker, Some(deadline), |param| {
+                ucp_am_send_nbx(
+                    ep,
+                    ABORT_AM_ID,
+                    header.as_ptr() as *const _,
+                    size_of::<AbortHeader>(),
+                    std::ptr::null(),
+                    0,
+                    param,
+                )
+            })
+        });
+        if let Err(err) = sent {
+            error!("Failed to send abort to process {}: {:?}", rank, err);
+        }
+    }
+    let flushed = unsafe {
+        wait_nbx_until(&worker, Some(deadline), |param| {
+            ucp_worker_flush_nbx(worker.as_raw(), param)
+        })
+    };
+    if let Err(err) = flushed {
+        error!("Failed to flush abort messages: {:?}", err);
+    }
+    handle.peers.abort(handle.rank, code);
+}
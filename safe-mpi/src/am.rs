@@ -0,0 +1,216 @@
+//! Active messages.
+//!
+//! All active messages go through a single UCP handler, with a header
+//! carrying the communicator's context ID, the user's handler ID and the
+//! source rank. The UCP callback only queues messages, since user handlers
+//! are free to communicate and UCP doesn't allow that from inside its
+//! callbacks. Handlers are run from `Communicator::am_progress()`, which also
+//! fetches the data of messages that arrived with the rendezvous protocol.
+use crate::{
+    callbacks::{am_recv_callback, am_recv_data_callback},
+    communicator::Communicator,
+    message::Message,
+    request::wait_nbx,
+    Error, Result, Tag,
+};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of_val;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use ucx2_sys::{
+    ucp_am_handler_param_t, ucp_am_recv_data_nbx, ucp_am_send_nbx, ucp_request_param_t,
+    ucp::Worker, ucp_request_param_t__bindgen_ty_1, ucp_worker_h,
+    ucp_worker_set_am_recv_handler, UCP_AM_HANDLER_PARAM_FIELD_ARG, UCP_AM_HANDLER_PARAM_FIELD_CB,
+    UCP_AM_HANDLER_PARAM_FIELD_ID, UCS_OK,
+};
+
+/// UCP active message ID used for all messages
+const AM_ID: u32 = 0;
+
+/// Header of every active message: context ID, handler ID and source rank
+pub(crate) type AmHeader = [u64; 3];
+
+/// Type-erased user handler, called with the source rank and the raw data
+type Handler = Rc<RefCell<dyn FnMut(usize, &[u8]) -> Result<()>>>;
+
+/// Data of a received message
+pub(crate) enum AmData {
+    /// Data that's already been copied out of UCP
+    Ready(Vec<u8>),
+    /// Rendezvous descriptor, the data still needs to be fetched
+    Rndv { desc: *mut c_void, length: usize },
+}
+
+/// Message waiting to be dispatched
+pub(crate) struct AmMessage {
+    pub header: AmHeader,
+    pub data: AmData,
+}
+
+/// Active message state of the worker, shared with the UCP callback.
+#[derive(Default)]
+pub(crate) struct AmState {
+    /// Messages received by the callback, in order of arrival
+    pending: RefCell<VecDeque<AmMessage>>,
+    /// Handlers by (context ID, handler ID)
+    handlers: RefCell<HashMap<(Tag, u32), Handler>>,
+}
+
+impl AmState {
+    /// Queue a message (called from the UCP callback).
+    pub(crate) fn push(&self, msg: AmMessage) {
+        self.pending.borrow_mut().push_back(msg);
+    }
+
+    /// Remove all handlers of a communicator.
+    pub(crate) fn remove_context(&self, context: Tag) {
+        self.handlers.borrow_mut().retain(|key, _| key.0 != context);
+    }
+
+    /// Run the handlers for all queued messages that have one. Messages
+    /// without a handler (yet) stay queued. Returns the number of messages
+    /// handled.
+    fn dispatch(&self, worker: &Worker) -> Result<usize> {
+        let mut messages: VecDeque<AmMessage> = self.pending.borrow_mut().drain(..).collect();
+        let mut keep = vec![];
+        let mut handled = 0;
+        let result = loop {
+            let msg = match messages.pop_front() {
+                Some(msg) => msg,
+                None => break Ok(handled),
+            };
+            let [context, id, source] = msg.header;
+            let entry = self.handlers.borrow().get(&(context, id as u32)).cloned();
+            // A handler that's already running (i.e. calling `am_progress()`
+            // itself) can't be run again
+            let mut handler = match entry.as_ref().and_then(|h| h.try_borrow_mut().ok()) {
+                Some(handler) => handler,
+                None => {
+                    keep.push(msg);
+                    continue;
+                }
+            };
+            let data = match msg.data {
+                AmData::Ready(data) => data,
+                AmData::Rndv { desc, length } => match unsafe { fetch(worker, desc, length) } {
+                    Ok(data) => data,
+                    Err(err) => break Err(err),
+                },
+            };
+            if let Err(err) = handler(source as usize, &data) {
+                break Err(err);
+            }
+            handled += 1;
+        };
+        // Put back everything that wasn't handled, ahead of anything that
+        // arrived in the meantime
+        let mut pending = self.pending.borrow_mut();
+        for msg in keep.into_iter().chain(messages).rev() {
+            pending.push_front(msg);
+        }
+        result
+    }
+}
+
+/// Register the UCP handler for active messages on `worker`. `state` has to
+/// outlive the worker.
+pub(crate) unsafe fn register(worker: ucp_worker_h, state: &Rc<AmState>) -> Result<()> {
+    let params = ucp_am_handler_param_t {
+        field_mask: (UCP_AM_HANDLER_PARAM_FIELD_ID
+            | UCP_AM_HANDLER_PARAM_FIELD_CB
+            | UCP_AM_HANDLER_PARAM_FIELD_ARG)
+            .into(),
+        id: AM_ID,
+        cb: Some(am_recv_callback),
+        arg: Rc::as_ptr(state) as *mut c_void,
+        ..Default::default()
+    };
+    let status = ucp_worker_set_am_recv_handler(worker, &params);
+    if status != UCS_OK {
+        return Err(Error::AmHandlerFailure(status));
+    }
+    Ok(())
+}
+
+/// Fetch the data of a rendezvous message.
+unsafe fn fetch(worker: &Worker, desc: *mut c_void, length: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; length];
+    wait_nbx(worker, |param| {
+        let param = ucp_request_param_t {
+            cb: ucp_request_param_t__bindgen_ty_1 {
+                recv_am: Some(am_recv_data_callback),
+            },
+            ..*param
+        };
+        ucp_am_recv_data_nbx(worker.as_raw(), desc, data.as_mut_ptr() as *mut _, length, &param)
+    })?;
+    Ok(data)
+}
+
+impl Communicator {
+    /// Register `handler` for active messages with handler ID `id` sent on
+    /// this communicator, replacing any previous handler for the ID. The
+    /// handler is called with the source rank and the decoded message.
+    pub fn register_handler<T, F>(&self, id: u32, mut handler: F)
+    where
+        T: Message,
+        F: FnMut(usize, T) + 'static,
+    {
+        let handler: Handler = Rc::new(RefCell::new(move |source, data: &[u8]| {
+            handler(source, T::decode(data)?);
+            Ok(())
+        }));
+        let am = Rc::clone(&self.handle.borrow().am);
+        am.handlers
+            .borrow_mut()
+            .insert((self.context_id(), id), handler);
+    }
+
+    /// Remove the handler for handler ID `id`.
+    pub fn remove_handler(&self, id: u32) {
+        let am = Rc::clone(&self.handle.borrow().am);
+        am.handlers.borrow_mut().remove(&(self.context_id(), id));
+    }
+
+    /// Send `msg` as an active message to the handler `id` on `dest`.
+    ///
+    /// This returns once the message has been sent. Large messages use the
+    /// rendezvous protocol, which needs the destination to call
+    /// `am_progress()`.
+    pub fn am_send<T: Message>(&self, dest: usize, id: u32, msg: &T) -> Result<()> {
+        self.check_revoked()?;
+        let data = msg.encode()?;
+        let header: AmHeader = [self.context_id(), u64::from(id), self.rank() as u64];
+        let dest = self.peer_world_rank(dest)?;
+        let (worker, ep) = {
+            let mut handle = self.handle.borrow_mut();
+            (Rc::clone(&handle.worker), handle.endpoint(dest)?)
+        };
+        unsafe {
+            wait_nbx(&worker, |param| {
+                ucp_am_send_nbx(
+                    ep,
+                    AM_ID,
+                    header.as_ptr() as *const _,
+                    size_of_val(&header),
+                    data.as_ptr() as *const _,
+                    data.len(),
+                    param,
+                )
+            })
+        }
+        .map_err(|err| self.handle.borrow().peer_error(dest, err))
+    }
+
+    /// Make progress and run the handlers of all received active messages.
+    /// Returns the number of messages handled.
+    pub fn am_progress(&self) -> Result<usize> {
+        let (worker, am) = {
+            let handle = self.handle.borrow();
+            (Rc::clone(&handle.worker), Rc::clone(&handle.am))
+        };
+        worker.progress();
+        am.dispatch(&worker)
+    }
+}
@@ -0,0 +1,104 @@
+//! Remote atomic operations on windows.
+//!
+//! UCP only has network atomics for 32 and 64-bit integers, so these are only
+//! available on windows of types implementing the sealed `Atomic` trait. Every
+//! operation fetches the previous value, which the returned request gives
+//! back once it's complete. Operations fail with `MissingFeature` if the
+//! context wasn't created with the atomics of the element size.
+use crate::{request::AtomicRequest, window::Window, Features, Result};
+use flat::FlatBuffer;
+use std::mem::size_of;
+use ucx2_sys::{
+    ucp_atomic_op_t, UCP_ATOMIC_OP_ADD, UCP_ATOMIC_OP_AND, UCP_ATOMIC_OP_CSWAP, UCP_ATOMIC_OP_OR,
+    UCP_ATOMIC_OP_SWAP, UCP_ATOMIC_OP_XOR,
+};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Element type that supports remote atomic operations.
+pub trait Atomic: FlatBuffer + Copy + Default + private::Sealed {}
+
+macro_rules! impl_atomic {
+    ($ty:ident) => {
+        impl private::Sealed for $ty {}
+        impl Atomic for $ty {}
+    };
+}
+
+impl_atomic!(u32);
+impl_atomic!(u64);
+impl_atomic!(i64);
+
+impl<T: Atomic> Window<T> {
+    /// Atomically add `value` to the element at `offset` on `target`.
+    pub fn fetch_add(
+        &self,
+        target: usize,
+        offset: usize,
+        value: T,
+    ) -> Result<AtomicRequest<'_, T>> {
+        self.atomic(target, offset, UCP_ATOMIC_OP_ADD, value, T::default())
+    }
+
+    /// Atomically replace the element at `offset` on `target` with `new` if
+    /// it's equal to `compare`.
+    pub fn compare_and_swap(
+        &self,
+        target: usize,
+        offset: usize,
+        compare: T,
+        new: T,
+    ) -> Result<AtomicRequest<'_, T>> {
+        self.atomic(target, offset, UCP_ATOMIC_OP_CSWAP, compare, new)
+    }
+
+    /// Atomically replace the element at `offset` on `target` with `value`.
+    pub fn swap(&self, target: usize, offset: usize, value: T) -> Result<AtomicRequest<'_, T>> {
+        self.atomic(target, offset, UCP_ATOMIC_OP_SWAP, value, T::default())
+    }
+
+    /// Atomically AND the element at `offset` on `target` with `value`.
+    pub fn fetch_and(
+        &self,
+        target: usize,
+        offset: usize,
+        value: T,
+    ) -> Result<AtomicRequest<'_, T>> {
+        self.atomic(target, offset, UCP_ATOMIC_OP_AND, value, T::default())
+    }
+
+    /// Atomically OR the element at `offset` on `target` with `value`.
+    pub fn fetch_or(&self, target: usize, offset: usize, value: T) -> Result<AtomicRequest<'_, T>> {
+        self.atomic(target, offset, UCP_ATOMIC_OP_OR, value, T::default())
+    }
+
+    /// Atomically XOR the element at `offset` on `target` with `value`.
+    pub fn fetch_xor(
+        &self,
+        target: usize,
+        offset: usize,
+        value: T,
+    ) -> Result<AtomicRequest<'_, T>> {
+        self.atomic(target, offset, UCP_ATOMIC_OP_XOR, value, T::default())
+    }
+
+    fn atomic(
+        &self,
+        target: usize,
+        offset: usize,
+        opcode: ucp_atomic_op_t,
+        value: T,
+        reply: T,
+    ) -> Result<AtomicRequest<'_, T>> {
+        let feature = if size_of::<T>() == 4 {
+            Features::AMO32
+        } else {
+            Features::AMO64
+        };
+        self.handle().borrow().check_features(feature)?;
+        let (ep, addr, rkey) = self.target(target, offset, 1)?;
+        Ok(unsafe { AtomicRequest::new(self.handle(), ep, opcode, value, reply, addr, rkey) })
+    }
+}
@@ -1,5 +1,16 @@
+use crate::abort::AbortHeader;
+use crate::am::{AmData, AmHeader, AmMessage, AmState};
+use crate::failure::PeerState;
+use crate::Tag;
+use log::error;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem::size_of;
 use std::os::raw::c_void;
-use ucx2_sys::{ucp_tag_recv_info_t, ucs_status_t, UCS_OK};
+use ucx2_sys::{
+    ucp_am_recv_param_t, ucp_conn_request_h, ucp_ep_h, ucp_tag_recv_info_t, ucs_status_t,
+    UCP_AM_RECV_ATTR_FLAG_RNDV, UCS_INPROGRESS, UCS_OK,
+};
 
 pub(crate) unsafe extern "C" fn send_nbx_callback(
     _req: *mut c_void,
@@ -19,3 +30,115 @@ pub(crate) unsafe extern "C" fn tag_recv_nbx_callback(
     let done = user_data as *mut bool;
     *done = status == UCS_OK;
 }
+
+pub(crate) unsafe extern "C" fn am_recv_callback(
+    arg: *mut c_void,
+    header: *const c_void,
+    header_length: usize,
+    data: *mut c_void,
+    length: usize,
+    param: *const ucp_am_recv_param_t,
+) -> ucs_status_t {
+    let state = &*(arg as *const AmState);
+    if header_length != size_of::<AmHeader>() {
+        error!("Dropping active message with invalid header");
+        return UCS_OK;
+    }
+    let header = std::ptr::read_unaligned(header as *const AmHeader);
+    if (*param).recv_attr & u64::from(UCP_AM_RECV_ATTR_FLAG_RNDV) != 0 {
+        // Keep the descriptor, the data is fetched before dispatching
+        state.push(AmMessage {
+            header,
+            data: AmData::Rndv { desc: data, length },
+        });
+        UCS_INPROGRESS
+    } else {
+        let data = if length == 0 {
+            vec![]
+        } else {
+            std::slice::from_raw_parts(data as *const u8, length).to_vec()
+        };
+        state.push(AmMessage {
+            header,
+            data: AmData::Ready(data),
+        });
+        UCS_OK
+    }
+}
+
+pub(crate) unsafe extern "C" fn am_recv_data_callback(
+    _req: *mut c_void,
+    status: ucs_status_t,
+    _length: usize,
+    user_data: *mut c_void,
+) {
+    let done = user_data as *mut bool;
+    *done = status == UCS_OK;
+}
+
+pub(crate) unsafe extern "C" fn stream_recv_callback(
+    _req: *mut c_void,
+    status: ucs_status_t,
+    _length: usize,
+    user_data: *mut c_void,
+) {
+    let done = user_data as *mut bool;
+    *done = status == UCS_OK;
+}
+
+/// Queue an incoming connection request, it's accepted outside the callback.
+pub(crate) unsafe extern "C" fn listener_conn_callback(
+    conn_request: ucp_conn_request_h,
+    arg: *mut c_void,
+) {
+    let requests = &*(arg as *const RefCell<VecDeque<ucp_conn_request_h>>);
+    requests.borrow_mut().push_back(conn_request);
+}
+
+/// Mark the peer of a broken endpoint as failed.
+pub(crate) unsafe extern "C" fn peer_err_callback(
+    arg: *mut c_void,
+    ep: ucp_ep_h,
+    status: ucs_status_t,
+) {
+    let peers = &*(arg as *const PeerState);
+    peers.fail(ep, status);
+}
+
+/// Mark the communicator named in a revoke message as revoked.
+pub(crate) unsafe extern "C" fn revoke_recv_callback(
+    arg: *mut c_void,
+    header: *const c_void,
+    header_length: usize,
+    _data: *mut c_void,
+    _length: usize,
+    _param: *const ucp_am_recv_param_t,
+) -> ucs_status_t {
+    let peers = &*(arg as *const PeerState);
+    if header_length != size_of::<Tag>() {
+        error!("Dropping revoke message with invalid header");
+        return UCS_OK;
+    }
+    peers.revoke(std::ptr::read_unaligned(header as *const Tag));
+    UCS_OK
+}
+
+/// Record the abort of the job by another process.
+pub(crate) unsafe extern "C" fn abort_recv_callback(
+    arg: *mut c_void,
+    header: *const c_void,
+    header_length: usize,
+    _data: *mut c_void,
+    _length: usize,
+    _param: *const ucp_am_recv_param_t,
+) -> ucs_status_t {
+    let peers = &*(arg as *const PeerState);
+    if header_length != size_of::<AbortHeader>() {
+        error!("Dropping abort message with invalid header");
+        return UCS_OK;
+    }
+    let [rank, code] = std::ptr::read_unaligned(header as *const AbortHeader);
+    error!("Process {} aborted the job with code {}", rank, code as i32);
+    peers.abort(rank as usize, code as i32);
+    UCS_OK
+}
@@ -0,0 +1,156 @@
+//! Typed channels over a (peer, tag) pair.
+//!
+//! Messages are sent with `Communicator::send_message()`, which puts the
+//! hashed type ID of the channel's type in front, so a type mismatch between
+//! the two ends is still caught, but within a program that only uses channels
+//! it can't happen.
+use crate::{
+    communicator::Communicator,
+    message::{split_type_id, Message},
+    tag, Error, Request, RequestStatus, Result, Tag,
+};
+use std::marker::PhantomData;
+
+/// Sending half of a channel.
+pub struct Sender<T> {
+    comm: Communicator,
+    peer: usize,
+    tag: Tag,
+    marker: PhantomData<fn(T)>,
+}
+
+/// Receiving half of a channel.
+pub struct Receiver<T> {
+    comm: Communicator,
+    peer: usize,
+    tag: Tag,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl Communicator {
+    /// Create a channel carrying values of type `T` to and from `peer` on
+    /// `tag`. The sender sends to `peer` and the receiver receives from it,
+    /// so `peer` has to create a channel of the same type on the same tag.
+    /// Flat data can be sent by using `Flat<_>` as the type.
+    pub fn channel<T: Message + 'static>(
+        &self,
+        peer: usize,
+        tag: Tag,
+    ) -> Result<(Sender<T>, Receiver<T>)> {
+        // Check the peer and tag now, rather than on every message
+        self.peer_world_rank(peer)?;
+        tag::user(self.context_id(), peer, tag)?;
+        Ok((
+            Sender {
+                comm: self.clone(),
+                peer,
+                tag,
+                marker: PhantomData,
+            },
+            Receiver {
+                comm: self.clone(),
+                peer,
+                tag,
+                marker: PhantomData,
+            },
+        ))
+    }
+}
+
+impl<T: Message + 'static> Sender<T> {
+    /// Send `msg`, blocking until the send has completed.
+    pub fn send(&self, msg: &T) -> Result<()> {
+        self.comm.send_message(self.peer, self.tag, msg)
+    }
+
+    /// Rank of the peer the channel sends to
+    pub fn peer(&self) -> usize {
+        self.peer
+    }
+}
+
+impl<T: Message + 'static> Receiver<T> {
+    /// Receive the next message, blocking until one arrives.
+    pub fn recv(&self) -> Result<T> {
+        loop {
+            if let Some(msg) = self.try_recv()? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Receive the next message if one has arrived.
+    pub fn try_recv(&self) -> Result<Option<T>> {
+        let mut req = self.comm.irecv_probe(Some(self.peer), self.tag)?;
+        unsafe {
+            // Once the probe has matched a message the receive has to run to
+            // completion, since the request can't be cancelled
+            while let RequestStatus::InProgress = req.progress()? {
+                if req.size().is_none() {
+                    return Ok(None);
+                }
+            }
+        }
+        let data = req.data().ok_or(Error::InternalError)?;
+        decode(&data).map(Some)
+    }
+
+    /// Iterator that blocks for each message and never ends.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { receiver: self }
+    }
+
+    /// Iterator over the messages that have already arrived.
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        TryIter { receiver: self }
+    }
+
+    /// Rank of the peer the channel receives from
+    pub fn peer(&self) -> usize {
+        self.peer
+    }
+}
+
+/// Decode a message, checking its type ID header.
+fn decode<T: Message + 'static>(data: &[u8]) -> Result<T> {
+    let (id, data) = split_type_id(data)?;
+    if id != T::type_id() {
+        return Err(Error::MessageTypeMismatch);
+    }
+    T::decode(data)
+}
+
+/// Blocking iterator over a receiver, see `Receiver::iter()`.
+pub struct Iter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<'a, T: Message + 'static> Iterator for Iter<'a, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        Some(self.receiver.recv())
+    }
+}
+
+/// Non-blocking iterator over a receiver, see `Receiver::try_iter()`.
+pub struct TryIter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<'a, T: Message + 'static> Iterator for TryIter<'a, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        self.receiver.try_recv().transpose()
+    }
+}
+
+impl<'a, T: Message + 'static> IntoIterator for &'a Receiver<T> {
+    type Item = Result<T>;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
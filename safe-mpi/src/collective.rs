@@ -0,0 +1,585 @@
+//! Collective operations over all processes in a communicator.
+//!
+//! Everything is built on top of point-to-point messages using binomial trees
+//! rooted at rank 0. Since the tree is fixed, for a given number of processes
+//! the result of a reduction never depends on message arrival order.
+//! Floating-point sums can additionally be run in `ReduceMode::Reproducible`,
+//! which gives bit-identical results for any number of processes and any
+//! distribution of the data across them.
+use crate::{communicator::Communicator, tag, Error, Result, Tag};
+use flat::FlatBuffer;
+
+/// Internal tag for the reduction phase
+const REDUCE_TAG: Tag = 1;
+/// Internal tag for the broadcast phase
+const BCAST_TAG: Tag = 2;
+/// Internal tag for the gather phase
+const GATHER_TAG: Tag = 3;
+
+/// Operation to reduce values with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    Prod,
+    Min,
+    Max,
+}
+
+/// Algorithm used for floating-point sums.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReduceMode {
+    /// Combine values directly in the tree. This is reproducible from run to
+    /// run, but only for the same number of processes.
+    Fast,
+    /// Binned summation, reproducible for any number of processes. This
+    /// requires an extra reduction to find the maximum magnitude and sends
+    /// `FOLDS` values per element. Only `ReduceOp::Sum` on floating-point
+    /// types is affected, everything else is already exact.
+    Reproducible,
+}
+
+/// Element type that can be reduced.
+pub trait Reduce: FlatBuffer + Copy + Default {
+    /// Whether this is a floating-point type (i.e. summation order matters)
+    const FLOAT: bool;
+
+    /// Combine two values, `a` coming from the lower ranks.
+    fn combine(op: ReduceOp, a: Self, b: Self) -> Self;
+
+    /// Convert to f64 for binned summation (only called for `FLOAT` types).
+    fn to_f64(self) -> f64;
+
+    /// Convert back from the result of a binned summation.
+    fn from_f64(val: f64) -> Self;
+}
+
+macro_rules! impl_reduce_int {
+    ($ty:ident) => {
+        impl Reduce for $ty {
+            const FLOAT: bool = false;
+
+            #[inline]
+            fn combine(op: ReduceOp, a: Self, b: Self) -> Self {
+                // Wrapping arithmetic keeps the result independent of the
+                // order values are combined in
+                match op {
+                    ReduceOp::Sum => a.wrapping_add(b),
+                    ReduceOp::Prod => a.wrapping_mul(b),
+                    ReduceOp::Min => a.min(b),
+                    ReduceOp::Max => a.max(b),
+                }
+            }
+
+            #[inline]
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            #[inline]
+            fn from_f64(val: f64) -> Self {
+                val as $ty
+            }
+        }
+    };
+}
+
+macro_rules! impl_reduce_float {
+    ($ty:ident) => {
+        impl Reduce for $ty {
+            const FLOAT: bool = true;
+
+            #[inline]
+            fn combine(op: ReduceOp, a: Self, b: Self) -> Self {
+                // Min and max propagate NaNs so that they're associative
+                match op {
+                    ReduceOp::Sum => a + b,
+                    ReduceOp::Prod => a * b,
+                    ReduceOp::Min if a.is_nan() || b.is_nan() => $ty::NAN,
+                    ReduceOp::Min => a.min(b),
+                    ReduceOp::Max if a.is_nan() || b.is_nan() => $ty::NAN,
+                    ReduceOp::Max => a.max(b),
+                }
+            }
+
+            #[inline]
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            #[inline]
+            fn from_f64(val: f64) -> Self {
+                val as $ty
+            }
+        }
+    };
+}
+
+impl_reduce_int!(i8);
+impl_reduce_int!(i16);
+impl_reduce_int!(i32);
+impl_reduce_int!(i64);
+impl_reduce_int!(isize);
+impl_reduce_int!(u8);
+impl_reduce_int!(u16);
+impl_reduce_int!(u32);
+impl_reduce_int!(u64);
+impl_reduce_int!(usize);
+impl_reduce_float!(f32);
+impl_reduce_float!(f64);
+
+impl Communicator {
+    /// Reduce `data` element-wise across all processes and return the result
+    /// on every process. All processes must pass the same number of elements.
+    pub fn allreduce<T: Reduce>(
+        &self,
+        data: &[T],
+        op: ReduceOp,
+        mode: ReduceMode,
+    ) -> Result<Vec<T>> {
+        if T::FLOAT && op == ReduceOp::Sum && mode == ReduceMode::Reproducible {
+            // One value per process for each element
+            let bits = tag::SOURCE_BITS as i32;
+            let values: Vec<f64> = data.iter().map(|val| val.to_f64()).collect();
+            let max = self.tree_allreduce(&abs_all(&values), ReduceOp::Max)?;
+            let binnings: Vec<Binning> = max.iter().map(|max| Binning::new(*max, bits)).collect();
+            let mut bins = vec![0.0; FOLDS * data.len()];
+            for ((binning, chunk), val) in binnings
+                .iter()
+                .zip(bins.chunks_mut(FOLDS))
+                .zip(values.iter())
+            {
+                binning.deposit(chunk, *val);
+            }
+            let bins = self.tree_allreduce(&bins, ReduceOp::Sum)?;
+            Ok(binnings
+                .iter()
+                .zip(bins.chunks(FOLDS))
+                .map(|(binning, chunk)| T::from_f64(binning.finish(chunk)))
+                .collect())
+        } else {
+            self.tree_allreduce(data, op)
+        }
+    }
+
+    /// Sum all elements of `data` across all processes, i.e. `data` is the
+    /// local part of a distributed vector. In `ReduceMode::Reproducible` the
+    /// result only depends on the global contents of the vector, not on how
+    /// it's split up.
+    pub fn sum<T: Reduce>(&self, data: &[T], mode: ReduceMode) -> Result<T> {
+        if T::FLOAT && mode == ReduceMode::Reproducible {
+            let values: Vec<f64> = data.iter().map(|val| val.to_f64()).collect();
+            self.reproducible_sum(&values).map(T::from_f64)
+        } else {
+            let local = data.iter().fold(T::default(), |acc, val| {
+                T::combine(ReduceOp::Sum, acc, *val)
+            });
+            Ok(self.tree_allreduce(&[local], ReduceOp::Sum)?[0])
+        }
+    }
+
+    /// Dot product of two distributed vectors, where `x` and `y` are the local
+    /// parts.
+    pub fn dot<T: Reduce>(&self, x: &[T], y: &[T], mode: ReduceMode) -> Result<T> {
+        if x.len() != y.len() {
+            return Err(Error::LengthMismatch);
+        }
+        if T::FLOAT && mode == ReduceMode::Reproducible {
+            // Products of f32 values are exact in f64, products of f64
+            // values are rounded the same way on every process
+            let values: Vec<f64> = x
+                .iter()
+                .zip(y.iter())
+                .map(|(a, b)| a.to_f64() * b.to_f64())
+                .collect();
+            self.reproducible_sum(&values).map(T::from_f64)
+        } else {
+            let products: Vec<T> = x
+                .iter()
+                .zip(y.iter())
+                .map(|(a, b)| T::combine(ReduceOp::Prod, *a, *b))
+                .collect();
+            self.sum(&products, ReduceMode::Fast)
+        }
+    }
+
+    /// Binned sum of all local values across all processes.
+    fn reproducible_sum(&self, values: &[f64]) -> Result<f64> {
+        // The bin widths depend on the global count and maximum, neither of
+        // which depends on the data distribution
+        let count = self.tree_allreduce(&[values.len() as u64], ReduceOp::Sum)?[0];
+        let local_max = abs_all(values)
+            .into_iter()
+            .fold(0.0, |acc, val| f64::combine(ReduceOp::Max, acc, val));
+        let max = self.tree_allreduce(&[local_max], ReduceOp::Max)?[0];
+        let bits = (u64::BITS - count.leading_zeros()) as i32;
+        let binning = Binning::new(max, bits);
+        let mut bins = [0.0; FOLDS];
+        for val in values {
+            binning.deposit(&mut bins, *val);
+        }
+        let bins = self.tree_allreduce(&bins, ReduceOp::Sum)?;
+        Ok(binning.finish(&bins))
+    }
+
+    /// Gather `data` from every process, concatenated in rank order, on every
+    /// process. All processes must pass the same number of elements.
+    pub fn allgather<T: FlatBuffer + Copy + Default>(&self, data: &[T]) -> Result<Vec<T>> {
+        self.check_intra()?;
+        let rank = self.rank();
+        let size = self.size();
+        let mut acc = data.to_vec();
+
+        // Gather: acc always holds the data of the ranks [rank, rank + mask)
+        let mut mask = 1;
+        while mask < size {
+            if rank & mask != 0 {
+                self.send_internal(rank - mask, GATHER_TAG, &acc)?;
+                break;
+            } else if rank + mask < size {
+                let count = mask.min(size - rank - mask) * data.len();
+                let other: Vec<T> = self.recv_internal(rank + mask, GATHER_TAG, count)?;
+                acc.extend(other);
+            }
+            mask <<= 1;
+        }
+
+        self.tree_bcast(acc, size * data.len(), 0)
+    }
+
+    /// Exclusive prefix reduction: process `i` gets `data` reduced
+    /// element-wise over processes `0..i`, and process 0 gets `None`. All
+    /// processes must pass the same number of elements.
+    pub fn exscan<T: Reduce>(&self, data: &[T], op: ReduceOp) -> Result<Option<Vec<T>>> {
+        let all = self.allgather(data)?;
+        if data.is_empty() {
+            return Ok((self.rank() > 0).then(Vec::new));
+        }
+        // Combine in rank order, so the result is the same on every process
+        Ok(all
+            .chunks(data.len())
+            .take(self.rank())
+            .map(|block| block.to_vec())
+            .reduce(|mut acc, block| {
+                for (a, b) in acc.iter_mut().zip(block) {
+                    *a = T::combine(op, *a, b);
+                }
+                acc
+            }))
+    }
+
+    /// Reduce to rank 0 with a binomial tree and then broadcast the result
+    /// back out along the same tree.
+    fn tree_allreduce<T: Reduce>(&self, data: &[T], op: ReduceOp) -> Result<Vec<T>> {
+        self.tree_allreduce_with(data, |a, b| T::combine(op, a, b))
+    }
+
+    /// Same as `tree_allreduce()`, but with an arbitrary (associative)
+    /// combining function.
+    pub(crate) fn tree_allreduce_with<T, F>(&self, data: &[T], f: F) -> Result<Vec<T>>
+    where
+        T: FlatBuffer + Copy + Default,
+        F: Fn(T, T) -> T,
+    {
+        self.check_intra()?;
+        let rank = self.rank();
+        let size = self.size();
+        let mut acc = data.to_vec();
+
+        // Reduction: acc always covers the contiguous ranks [rank, rank + mask)
+        let mut mask = 1;
+        while mask < size {
+            if rank & mask != 0 {
+                self.send_internal(rank - mask, REDUCE_TAG, &acc)?;
+                break;
+            } else if rank + mask < size {
+                let other: Vec<T> = self.recv_internal(rank + mask, REDUCE_TAG, acc.len())?;
+                for (a, b) in acc.iter_mut().zip(other) {
+                    *a = f(*a, b);
+                }
+            }
+            mask <<= 1;
+        }
+
+        self.tree_bcast(acc, data.len(), 0)
+    }
+
+    /// Block until all processes in the communicator have called `barrier()`.
+    pub fn barrier(&self) -> Result<()> {
+        self.tree_allreduce_with(&[0u8], |a, _| a).map(|_| ())
+    }
+
+    /// Broadcast `data` from `root` to all other processes. All processes
+    /// must pass the same number of elements.
+    pub fn bcast<T: FlatBuffer + Copy + Default>(&self, data: &mut [T], root: usize) -> Result<()> {
+        if root >= self.size() {
+            return Err(Error::InvalidRank(root));
+        }
+        let result = self.tree_bcast(data.to_vec(), data.len(), root)?;
+        data.copy_from_slice(&result);
+        Ok(())
+    }
+
+    /// Broadcast `count` elements from `root` with a binomial tree. `data` is
+    /// only used on the root.
+    pub(crate) fn tree_bcast<T: FlatBuffer + Copy + Default>(
+        &self,
+        data: Vec<T>,
+        count: usize,
+        root: usize,
+    ) -> Result<Vec<T>> {
+        self.check_intra()?;
+        let size = self.size();
+        // Ranks relative to the root
+        let rank = (self.rank() + size - root) % size;
+        let mut data = data;
+
+        // Receive from the parent, then send down the tree
+        if rank != 0 {
+            let parent = rank & (rank - 1);
+            data = self.recv_internal((parent + root) % size, BCAST_TAG, count)?;
+        }
+        let lowest = if rank == 0 {
+            size.next_power_of_two()
+        } else {
+            rank & rank.wrapping_neg()
+        };
+        let mut mask = lowest >> 1;
+        while mask > 0 {
+            if rank + mask < size {
+                self.send_internal((rank + mask + root) % size, BCAST_TAG, &data)?;
+            }
+            mask >>= 1;
+        }
+        Ok(data)
+    }
+
+    /// Collectives are only supported on intra-communicators.
+    fn check_intra(&self) -> Result<()> {
+        if self.is_inter() {
+            Err(Error::InterCommunicator)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Absolute values of all elements
+fn abs_all(values: &[f64]) -> Vec<f64> {
+    values.iter().map(|val| val.abs()).collect()
+}
+
+/// Number of bins used for reproducible sums
+const FOLDS: usize = 3;
+/// Precision of an f64 (in bits, including the implicit bit)
+const PRECISION: i32 = f64::MANTISSA_DIGITS as i32;
+
+/// Bin boundaries for binned summation.
+///
+/// This is the pre-rounding scheme of Demmel and Nguyen: each value is split
+/// into `FOLDS` parts aligned to fixed boundaries which only depend on the
+/// global maximum magnitude and the maximum number of summands (`2^bits`).
+/// Each part is then a multiple of the bin's ulp and small enough that
+/// summing up to `2^bits` of them is exact, so the bin sums don't depend on
+/// the order of addition.
+struct Binning {
+    /// Extraction constants (1.5 * 2^c) for each bin
+    bounds: [f64; FOLDS],
+    /// Power of two that values were scaled by to avoid overflow
+    scale: i32,
+    /// Global maximum magnitude
+    max: f64,
+}
+
+impl Binning {
+    fn new(max: f64, bits: i32) -> Binning {
+        let bits = bits.max(2);
+        // Smallest e such that max < 2^e
+        let exp = ((max.to_bits() >> 52) & 0x7ff) as i32;
+        let e = if exp == 0 { -1022 } else { exp - 1022 };
+        // Keep the largest boundary (1.5 * 2^(e + bits)) in range
+        let scale = (e + bits - 1020).max(0);
+        let mut bounds = [0.0; FOLDS];
+        for (k, bound) in bounds.iter_mut().enumerate() {
+            let c = e - scale + bits - (k as i32) * (PRECISION - bits);
+            *bound = 1.5 * pow2(c);
+        }
+        Binning { bounds, scale, max }
+    }
+
+    /// Split `val` into the bins.
+    fn deposit(&self, bins: &mut [f64], val: f64) {
+        if !self.max.is_finite() {
+            // Only the non-finite values matter here. Adding these is order
+            // independent (NaN and inf + -inf both end up as NaN).
+            if !val.is_finite() {
+                bins[0] += val;
+            }
+            return;
+        }
+        let mut rem = val * pow2(-self.scale);
+        for (bin, bound) in bins.iter_mut().zip(self.bounds.iter()) {
+            let q = (bound + rem) - bound;
+            *bin += q;
+            rem -= q;
+        }
+    }
+
+    /// Combine the bins into a final value, in a fixed order.
+    fn finish(&self, bins: &[f64]) -> f64 {
+        if !self.max.is_finite() {
+            return bins[0];
+        }
+        bins.iter().fold(0.0, |acc, bin| acc + bin) * pow2(self.scale)
+    }
+}
+
+/// Exact power of two (flushing to zero below the subnormal range)
+fn pow2(exp: i32) -> f64 {
+    if exp > 1023 {
+        f64::INFINITY
+    } else if exp >= -1022 {
+        f64::from_bits(((exp + 1023) as u64) << 52)
+    } else if exp >= -1074 {
+        f64::from_bits(1 << (exp + 1074))
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sum `values` the way `reproducible_sum()` does, with the values split
+    /// into parts of the given lengths like across processes and the bins of
+    /// the parts added up in `order`.
+    fn binned_sum(values: &[f64], parts: &[usize], order: &[usize]) -> f64 {
+        let count = values.len() as u64;
+        let bits = (u64::BITS - count.leading_zeros()) as i32;
+        let max = abs_all(values)
+            .into_iter()
+            .fold(0.0, |acc, val| f64::combine(ReduceOp::Max, acc, val));
+        let binning = Binning::new(max, bits);
+        let mut rest = values;
+        let mut part_bins = vec![];
+        for len in parts {
+            let (part, tail) = rest.split_at(*len);
+            let mut bins = [0.0; FOLDS];
+            for val in part {
+                binning.deposit(&mut bins, *val);
+            }
+            part_bins.push(bins);
+            rest = tail;
+        }
+        assert!(rest.is_empty());
+        let mut bins = [0.0; FOLDS];
+        for i in order {
+            for (bin, part) in bins.iter_mut().zip(part_bins[*i].iter()) {
+                *bin += part;
+            }
+        }
+        binning.finish(&bins)
+    }
+
+    /// Values of widely varying magnitude and sign, each a multiple of
+    /// `2^-20` so that the exact sum can be computed with integers
+    fn values(count: usize) -> (Vec<f64>, i128) {
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut values = vec![];
+        let mut exact = 0;
+        for _ in 0..count {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            let shift = (state >> 58) as u32;
+            let int = ((state >> 8) as i64 >> 10) >> shift;
+            exact += int as i128;
+            values.push(int as f64 * pow2(-20));
+        }
+        (values, exact)
+    }
+
+    #[test]
+    fn binned_sum_is_order_independent() {
+        let (values, _) = values(1000);
+        let expected = binned_sum(&values, &[1000], &[0]);
+        let mut reversed = values.clone();
+        reversed.reverse();
+        assert_eq!(
+            binned_sum(&reversed, &[1000], &[0]).to_bits(),
+            expected.to_bits()
+        );
+        for (parts, order) in [
+            (vec![500, 500], vec![1, 0]),
+            (vec![1, 333, 666], vec![2, 0, 1]),
+            (vec![250; 4], vec![3, 1, 2, 0]),
+            (vec![1; 1000], (0..1000).rev().collect()),
+        ] {
+            assert_eq!(
+                binned_sum(&values, &parts, &order).to_bits(),
+                expected.to_bits()
+            );
+        }
+    }
+
+    #[test]
+    fn binned_sum_is_accurate() {
+        let (values, exact) = values(1000);
+        let exact = exact as f64 * pow2(-20);
+        let sum = binned_sum(&values, &[1000], &[0]);
+        assert!((sum - exact).abs() <= exact.abs() * 2.0 * f64::EPSILON);
+        // Cancellation that a plain sum gets wrong
+        let values = [1e16, 1.0, -1e16];
+        assert_eq!(values.iter().sum::<f64>(), 0.0);
+        assert_eq!(binned_sum(&values, &[3], &[0]), 1.0);
+        assert_eq!(binned_sum(&[0.0; 4], &[4], &[0]), 0.0);
+    }
+
+    #[test]
+    fn binned_sum_non_finite() {
+        let values = [1.0, f64::INFINITY, 2.0];
+        assert_eq!(binned_sum(&values, &[3], &[0]), f64::INFINITY);
+        let values = [f64::NEG_INFINITY, 1.0];
+        assert_eq!(binned_sum(&values, &[1, 1], &[1, 0]), f64::NEG_INFINITY);
+        let values = [f64::INFINITY, 1.0, f64::NEG_INFINITY];
+        assert!(binned_sum(&values, &[2, 1], &[0, 1]).is_nan());
+        // The maximum of values including NaN may itself be NaN
+        let binning = Binning::new(f64::NAN, 2);
+        let mut bins = [0.0; FOLDS];
+        binning.deposit(&mut bins, 1.0);
+        binning.deposit(&mut bins, f64::NAN);
+        assert!(binning.finish(&bins).is_nan());
+    }
+
+    #[test]
+    fn binned_sum_near_overflow() {
+        let half = f64::MAX / 2.0;
+        let values = [half, f64::MAX / 4.0, -f64::MAX / 4.0];
+        assert!(Binning::new(half, 2).scale > 0);
+        assert_eq!(binned_sum(&values, &[3], &[0]), half);
+        assert_eq!(binned_sum(&values, &[1, 2], &[1, 0]), half);
+        let values = [f64::MAX, f64::MAX];
+        assert_eq!(binned_sum(&values, &[2], &[0]), f64::INFINITY);
+    }
+
+    #[test]
+    fn binned_sum_subnormal() {
+        let tiny = |units| f64::from_bits(units);
+        let values = [tiny(3), tiny(5), -tiny(1)];
+        assert_eq!(binned_sum(&values, &[3], &[0]), tiny(7));
+        assert_eq!(binned_sum(&values, &[1, 2], &[1, 0]), tiny(7));
+    }
+
+    #[test]
+    fn pow2_ranges() {
+        assert_eq!(pow2(0), 1.0);
+        assert_eq!(pow2(1023), f64::MAX / (2.0 - f64::EPSILON));
+        assert_eq!(pow2(1024), f64::INFINITY);
+        assert_eq!(pow2(-1022), f64::MIN_POSITIVE);
+        // Subnormals
+        assert_eq!(pow2(-1023), f64::MIN_POSITIVE / 2.0);
+        assert_eq!(pow2(-1074), f64::from_bits(1));
+        // Flushed to zero
+        assert_eq!(pow2(-1075), 0.0);
+    }
+}
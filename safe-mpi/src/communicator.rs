@@ -5,8 +5,11 @@ use crate::{
     request::{
         RecvIovRequest, RecvProbeRequest, Request, RequestStatus, SendIovRequest, SendRequest,
     },
-    Error, Handle, Iov, MutIov, Result, Tag,
+    tag,
+    topology::Topology,
+    Error, Handle, Iov, MutIov, Result, Tag, Watch,
 };
+use flat::FlatBuffer;
 
 /// Data reference type for send request
 pub enum Data<'a> {
@@ -17,42 +20,215 @@ pub enum Data<'a> {
 }
 
 /// Communicator object providing low-level point-to-point API
+///
+/// Cloning a communicator is cheap, but the clone shares the same tag space.
+/// Use `dup()` to get a communicator whose messages can't match any others.
+#[derive(Clone)]
 pub struct Communicator {
+    pub(crate) handle: Rc<RefCell<Handle>>,
+    /// Context ID, included in every tag sent on this communicator
+    context: Rc<ContextId>,
+    /// World ranks of the processes in this communicator
+    pub(crate) group: Rc<Vec<usize>>,
+    /// World ranks of the remote group, for inter-communicators
+    pub(crate) remote: Option<Rc<Vec<usize>>>,
+    /// Rank of this process in the communicator
+    pub(crate) rank: usize,
+    /// Virtual topology attached to the communicator
+    pub(crate) topology: Option<Rc<Topology>>,
+}
+
+/// Context ID owned by a communicator, freed once the last clone is dropped.
+struct ContextId {
     handle: Rc<RefCell<Handle>>,
+    id: Tag,
+}
+
+impl Drop for ContextId {
+    fn drop(&mut self) {
+        let mut handle = self.handle.borrow_mut();
+        handle.am.remove_context(self.id);
+        if self.id != tag::WORLD_CONTEXT {
+            handle.peers.release_context(self.id);
+            handle.release_context(self.id);
+        }
+    }
+}
+
+/// Kind of split for `Communicator::split_type()`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SplitType {
+    /// Group processes running on the same host
+    Shared,
 }
 
 impl Communicator {
-    /// Create a new communicator from a handle
+    /// Create the world communicator from a handle
     pub(crate) fn new(handle: Rc<RefCell<Handle>>) -> Communicator {
-        Communicator { handle }
+        let (rank, size) = {
+            let handle = handle.borrow();
+            (handle.rank, handle.size)
+        };
+        Communicator {
+            context: Rc::new(ContextId {
+                handle: Rc::clone(&handle),
+                id: tag::WORLD_CONTEXT,
+            }),
+            handle,
+            group: Rc::new((0..size).collect()),
+            remote: None,
+            rank,
+            topology: None,
+        }
+    }
+
+    /// Duplicate this communicator, giving the new one a fresh context ID.
+    /// This is collective over all processes in the communicator.
+    pub fn dup(&self) -> Result<Communicator> {
+        let id = self.alloc_context()?;
+        self.claim_context(id);
+        Ok(self.with_group(id, Rc::clone(&self.group), self.rank))
+    }
+
+    /// Split the communicator into disjoint communicators, one for each
+    /// `color`. Ranks in the new communicators are ordered by `key` and then
+    /// by rank in this communicator. Processes passing `None` as the color
+    /// don't get a communicator. This is collective over all processes in the
+    /// communicator.
+    pub fn split(&self, color: Option<u32>, key: i32) -> Result<Option<Communicator>> {
+        let color = color.map(i64::from).unwrap_or(-1);
+        let all = self.allgather(&[color, i64::from(key)])?;
+        // Every process takes part in the allocation, even without a color
+        let id = self.alloc_context()?;
+        if color < 0 {
+            return Ok(None);
+        }
+        let mut members: Vec<(i64, usize)> = all
+            .chunks(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] == color)
+            .map(|(rank, pair)| (pair[1], rank))
+            .collect();
+        members.sort();
+        let rank = members
+            .iter()
+            .position(|(_, rank)| *rank == self.rank)
+            .ok_or(Error::InternalError)?;
+        let group = members.iter().map(|(_, rank)| self.group[*rank]).collect();
+        self.claim_context(id);
+        Ok(Some(self.with_group(id, Rc::new(group), rank)))
+    }
+
+    /// Split the communicator by the given type, keeping the same relative
+    /// rank order. This is collective over all processes in the communicator.
+    pub fn split_type(&self, split_type: SplitType) -> Result<Communicator> {
+        let name = match split_type {
+            SplitType::Shared => {
+                let hostname = nix::unistd::gethostname().map_err(|_| Error::HostnameFailure)?;
+                hostname.to_string_lossy().into_owned().into_bytes()
+            }
+        };
+        // Gather the names padded to the longest one, with their lengths
+        let lens = self.allgather(&[name.len() as u64])?;
+        let max = lens.iter().copied().max().unwrap_or(0) as usize;
+        let mut padded = name;
+        padded.resize(max, 0);
+        let names = self.allgather(&padded)?;
+        let name_of = |rank: usize| &names[rank * max..rank * max + lens[rank] as usize];
+        // Color by the lowest rank on the same host
+        let color = (0..self.size())
+            .find(|rank| name_of(*rank) == name_of(self.rank))
+            .ok_or(Error::InternalError)?;
+        self.split(Some(color as u32), 0)?
+            .ok_or(Error::InternalError)
     }
 
-    // Duplicate this communicator
-    pub fn dup(&self) -> Communicator {
+    /// Create a communicator sharing this one's handle.
+    pub(crate) fn with_group(&self, id: Tag, group: Rc<Vec<usize>>, rank: usize) -> Communicator {
         Communicator {
             handle: Rc::clone(&self.handle),
+            context: Rc::new(ContextId {
+                handle: Rc::clone(&self.handle),
+                id,
+            }),
+            group,
+            remote: None,
+            rank,
+            topology: None,
         }
     }
 
+    /// Agree on a context ID that's free on all processes in this
+    /// communicator. The caller needs to claim it.
+    fn alloc_context(&self) -> Result<Tag> {
+        let free = self.free_contexts()?;
+        first_free_context(&free)
+    }
+
+    /// Bitmap of context IDs that are free on all processes in this
+    /// communicator.
+    pub(crate) fn free_contexts(&self) -> Result<Vec<u64>> {
+        let free = self.handle.borrow().free_contexts.clone();
+        self.tree_allreduce_with(&free, |a, b| a & b)
+    }
+
+    /// Mark a context ID as used by this process.
+    pub(crate) fn claim_context(&self, id: Tag) {
+        self.handle.borrow_mut().claim_context(id);
+    }
+
+    /// Context ID of the communicator
+    pub(crate) fn context_id(&self) -> Tag {
+        self.context.id
+    }
+
+    /// Rank of this process in the communicator
+    pub fn rank(&self) -> usize {
+        self.rank
+    }
+
+    /// Number of processes in the communicator
+    pub fn size(&self) -> usize {
+        self.group.len()
+    }
+
+    /// Whether this is an inter-communicator
+    pub fn is_inter(&self) -> bool {
+        self.remote.is_some()
+    }
+
+    /// Number of processes in the remote group of an inter-communicator
+    pub fn remote_size(&self) -> Option<usize> {
+        self.remote.as_ref().map(|remote| remote.len())
+    }
+
+    /// Return the world rank of a point-to-point peer. For inter-communicators
+    /// peers are in the remote group.
+    pub(crate) fn peer_world_rank(&self, rank: usize) -> Result<usize> {
+        let peers = self.remote.as_ref().unwrap_or(&self.group);
+        peers.get(rank).copied().ok_or(Error::InvalidRank(rank))
+    }
+
     /// Blocking iovec send
-    pub unsafe fn send(&self, data: &[Iov], tag: Tag) -> Result<usize> {
-        let mut req = self.isend_iov(data, tag)?;
+    pub unsafe fn send(&self, dest: usize, data: &[Iov], tag: Tag) -> Result<usize> {
+        let mut req = self.isend_iov(dest, data, tag)?;
         while let RequestStatus::InProgress = req.progress()? {}
         req.size().ok_or(Error::InternalError)
     }
 
-    /// Blocking recv and probe
-    pub fn recv_probe(&self, tag: Tag) -> Result<Vec<u8>> {
+    /// Blocking recv and probe. Passing `None` as the source receives from
+    /// any process.
+    pub fn recv_probe(&self, source: Option<usize>, tag: Tag) -> Result<Vec<u8>> {
         unsafe {
-            let mut req = self.irecv_probe(tag)?;
+            let mut req = self.irecv_probe(source, tag)?;
             while let RequestStatus::InProgress = req.progress()? {}
             req.data().ok_or(Error::InternalError)
         }
     }
 
     /// Blocking iovec recv
-    pub unsafe fn recv_iov(&self, data: &[MutIov], tag: Tag) -> Result<()> {
-        let mut req = self.irecv_iov(data, tag)?;
+    pub unsafe fn recv_iov(&self, source: Option<usize>, data: &[MutIov], tag: Tag) -> Result<()> {
+        let mut req = self.irecv_iov(source, data, tag)?;
         while let RequestStatus::InProgress = req.progress()? {}
         Ok(())
     }
@@ -63,25 +239,125 @@ impl Communicator {
     /// `mem::forget(sreq)` the data reference would be lost and the owning code
     /// could deallocate the original data, causing a segfault sometime later
     /// when other code attempts to make progress.
-    pub unsafe fn isend<'a>(&self, data: Data<'a>, tag: Tag) -> Result<SendRequest<'a>> {
-        SendRequest::new(Rc::clone(&self.handle), data, tag)
+    pub unsafe fn isend<'a>(&self, dest: usize, data: Data<'a>, tag: Tag) -> Result<SendRequest<'a>> {
+        self.check_revoked()?;
+        let dest = self.peer_world_rank(dest)?;
+        let tag = tag::user(self.context.id, self.rank, tag)?;
+        SendRequest::new(Rc::clone(&self.handle), dest, data, tag)
     }
 
     /// Non-blocking send
-    pub unsafe fn isend_iov<'a>(&self, data: &'a [Iov], tag: Tag) -> Result<SendIovRequest<'a>> {
-        SendIovRequest::new(Rc::clone(&self.handle), data, tag)
+    pub unsafe fn isend_iov<'a>(
+        &self,
+        dest: usize,
+        data: &'a [Iov],
+        tag: Tag,
+    ) -> Result<SendIovRequest<'a>> {
+        self.check_revoked()?;
+        let dest = self.peer_world_rank(dest)?;
+        let tag = tag::user(self.context.id, self.rank, tag)?;
+        SendIovRequest::new(Rc::clone(&self.handle), dest, data, tag)
     }
 
     /// Non-blocking receive with probe
     ///
     /// This is safe, when compared with isend, since it doesn't hold any
     /// references to user-provided buffers.
-    pub fn irecv_probe(&self, tag: Tag) -> Result<RecvProbeRequest> {
-        Ok(RecvProbeRequest::new(Rc::clone(&self.handle), tag))
+    pub fn irecv_probe(&self, source: Option<usize>, tag: Tag) -> Result<RecvProbeRequest> {
+        let (tag, mask, watch) = self.recv_tag(source, tag)?;
+        Ok(RecvProbeRequest::new(Rc::clone(&self.handle), tag, mask, watch))
     }
 
     /// Non-blocking receive
-    pub unsafe fn irecv_iov<'a>(&self, data: &'a [MutIov], tag: Tag) -> Result<RecvIovRequest<'a>> {
-        RecvIovRequest::new(Rc::clone(&self.handle), data, tag)
+    pub unsafe fn irecv_iov<'a>(
+        &self,
+        source: Option<usize>,
+        data: &'a [MutIov],
+        tag: Tag,
+    ) -> Result<RecvIovRequest<'a>> {
+        let (tag, mask, watch) = self.recv_tag(source, tag)?;
+        RecvIovRequest::new(Rc::clone(&self.handle), data, tag, mask, watch)
+    }
+
+    /// Return the tag and tag mask for receiving a user message, along with
+    /// what the receive depends on.
+    fn recv_tag(&self, source: Option<usize>, tag: Tag) -> Result<(Tag, Tag, Watch)> {
+        self.check_revoked()?;
+        let peer = source.map(|source| self.peer_world_rank(source)).transpose()?;
+        let tag = tag::user(self.context.id, source.unwrap_or(0), tag)?;
+        Ok((tag, tag::mask(source.is_some()), self.watch(peer)))
+    }
+
+    /// Return what a receive from the world rank `peer` on this
+    /// communicator depends on.
+    fn watch(&self, peer: Option<usize>) -> Watch {
+        Watch {
+            peer,
+            context: Some(self.context.id),
+        }
+    }
+
+    /// Blocking send of a slice to `dest` using an internal tag.
+    pub(crate) fn send_internal<T: FlatBuffer>(&self, dest: usize, tag: Tag, data: &[T]) -> Result<()> {
+        unsafe {
+            let iov = [Iov(data.ptr(), data.size())];
+            let mut req = self.isend_internal(dest, tag, &iov)?;
+            while let RequestStatus::InProgress = req.progress()? {}
+            Ok(())
+        }
+    }
+
+    /// Non-blocking iovec send to `dest` using an internal tag.
+    pub(crate) unsafe fn isend_internal<'a>(
+        &self,
+        dest: usize,
+        tag: Tag,
+        data: &'a [Iov],
+    ) -> Result<SendIovRequest<'a>> {
+        self.check_revoked()?;
+        let dest = self.peer_world_rank(dest)?;
+        let tag = tag::internal(self.context.id, self.rank, tag);
+        SendIovRequest::new(Rc::clone(&self.handle), dest, data, tag)
+    }
+
+    /// Blocking receive of exactly `count` elements from `source` using an
+    /// internal tag.
+    pub(crate) fn recv_internal<T: FlatBuffer + Copy + Default>(
+        &self,
+        source: usize,
+        tag: Tag,
+        count: usize,
+    ) -> Result<Vec<T>> {
+        unsafe {
+            let mut data = vec![T::default(); count];
+            let iov = [MutIov(data.ptr_mut(), data.size())];
+            let mut req = self.irecv_internal(source, tag, &iov)?;
+            while let RequestStatus::InProgress = req.progress()? {}
+            drop(req);
+            Ok(data)
+        }
     }
+
+    /// Non-blocking receive from `source` into `data` using an internal tag.
+    pub(crate) unsafe fn irecv_internal<'a>(
+        &self,
+        source: usize,
+        tag: Tag,
+        data: &'a [MutIov],
+    ) -> Result<RecvIovRequest<'a>> {
+        self.check_revoked()?;
+        let watch = self.watch(self.peer_world_rank(source).ok());
+        let tag = tag::internal(self.context.id, source, tag);
+        RecvIovRequest::new(Rc::clone(&self.handle), data, tag, tag::mask(true), watch)
+    }
+}
+
+/// Return the lowest context ID set in a bitmap of free IDs.
+pub(crate) fn first_free_context(free: &[u64]) -> Result<Tag> {
+    let (i, word) = free
+        .iter()
+        .enumerate()
+        .find(|(_, word)| **word != 0)
+        .ok_or(Error::ContextExhausted)?;
+    Ok((i * 64) as Tag + Tag::from(word.trailing_zeros()))
 }
@@ -1,19 +1,19 @@
 //! UCX context handle
 use std::cell::RefCell;
-use std::mem::MaybeUninit;
 use std::rc::Rc;
 // use log::{debug, info};
 use crate::communicator::Communicator;
-use crate::status_to_string;
-use crate::Handle;
+use crate::{Error, Handle, Result};
+use log::debug;
 use ucx2_sys::{
-    ucp_ep_create, ucp_ep_h, ucp_ep_params_t, UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE,
-    UCP_EP_PARAM_FIELD_REMOTE_ADDRESS, UCP_ERR_HANDLING_MODE_PEER, UCS_OK,
+    ucp::Endpoint, ucp_ep_params_t, UCP_EP_PARAM_FIELD_ERR_HANDLER,
+    UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE, UCP_EP_PARAM_FIELD_REMOTE_ADDRESS,
+    UCP_ERR_HANDLING_MODE_PEER,
 };
 
 pub struct Context {
     /// Handle with ucx info
-    handle: Rc<RefCell<Handle>>,
+    pub(crate) handle: Rc<RefCell<Handle>>,
 }
 
 impl Context {
@@ -21,28 +21,36 @@ impl Context {
         Context { handle }
     }
 
-    /// Return the world communicator.
-    #[allow(clippy::uninit_assumed_init)]
+    /// Return the world communicator. Endpoints to the other processes are
+    /// only created once they're communicated with, see `preconnect()`.
     pub fn world(&self) -> Communicator {
-        unsafe {
-            // Now create the single endpoint (this will change for multiple processes)
-            let mut endpoint = MaybeUninit::<ucp_ep_h>::uninit();
-            let params = ucp_ep_params_t {
-                field_mask: (UCP_EP_PARAM_FIELD_REMOTE_ADDRESS | UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE).into(),
-                err_mode: UCP_ERR_HANDLING_MODE_PEER,
-                address: self.handle.borrow().other_addr.as_ptr() as *const _,
-                ..Default::default()
-            };
-            let status = ucp_ep_create(self.handle.borrow().worker, &params, endpoint.as_mut_ptr());
-            if status != UCS_OK {
-                panic!(
-                    "Failed to create endpoint for worker: {}",
-                    status_to_string(status)
-                );
-            }
-            let endpoint = endpoint.assume_init();
-            let _ = self.handle.borrow_mut().endpoint.insert(endpoint);
-            Communicator::new(Rc::clone(&self.handle))
+        Communicator::new(Rc::clone(&self.handle))
+    }
+
+    /// Create the endpoints to all processes in the world up front, instead
+    /// of on first communication. This avoids the connection setup cost in
+    /// the first message to each process, e.g. in benchmarks.
+    pub fn preconnect(&self) -> Result<()> {
+        let mut handle = self.handle.borrow_mut();
+        for rank in 0..handle.size {
+            handle.endpoint(rank)?;
         }
+        Ok(())
     }
 }
+
+/// Create the endpoint for process `rank`.
+pub(crate) fn create_endpoint(handle: &Handle, rank: usize) -> Result<Endpoint> {
+    debug!("Creating endpoint for process {}", rank);
+    let params = ucp_ep_params_t {
+        field_mask: (UCP_EP_PARAM_FIELD_REMOTE_ADDRESS
+            | UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE
+            | UCP_EP_PARAM_FIELD_ERR_HANDLER)
+            .into(),
+        err_mode: UCP_ERR_HANDLING_MODE_PEER,
+        err_handler: handle.peers.err_handler(),
+        address: handle.addrs[rank].as_ptr() as *const _,
+        ..Default::default()
+    };
+    unsafe { Endpoint::new(&handle.worker, &params).map_err(Error::EndpointFailure) }
+}
@@ -0,0 +1,285 @@
+//! Block-distributed multi-dimensional arrays with ghost cells.
+//!
+//! The global index space is split into blocks over the processes of a
+//! Cartesian communicator. Each process stores its block surrounded by `halo`
+//! layers of ghost cells, which `exchange_halos()` fills from the neighbors.
+//! Faces are sent straight out of the local storage as iovecs (one per
+//! contiguous run), so nothing needs to be packed.
+use crate::{communicator::Communicator, Error, Iov, MutIov, Request, RequestStatus, Result, Tag};
+use flat::FlatBuffer;
+use std::mem::size_of;
+use std::ops::Range;
+
+/// Internal tag for halo exchange. The low bits hold the dimension and the
+/// direction the face was sent in.
+const HALO_TAG: Tag = 1 << 29;
+
+/// Distributed array over the processes of a Cartesian communicator.
+pub struct DistArray<T> {
+    comm: Communicator,
+    /// Global extent along each dimension
+    global: Vec<usize>,
+    /// Global index of the first locally owned element
+    offset: Vec<usize>,
+    /// Extent of the locally owned block
+    local: Vec<usize>,
+    /// Number of ghost layers on each side
+    halo: usize,
+    /// Local block including ghost cells, in row-major order
+    data: Vec<T>,
+}
+
+impl<T: FlatBuffer + Copy + Default> DistArray<T> {
+    /// Create a distributed array of size `global`, with `halo` ghost layers.
+    /// `comm` must have a Cartesian topology with the same number of
+    /// dimensions. Each dimension is split into nearly equal blocks, which
+    /// all have to be at least `halo` elements wide.
+    pub fn new(comm: &Communicator, global: &[usize], halo: usize) -> Result<DistArray<T>> {
+        let dims = comm.cart_dims()?;
+        if dims.len() != global.len() {
+            return Err(Error::LengthMismatch);
+        }
+        let coords = comm.cart_coords(comm.rank())?;
+        let mut offset = vec![];
+        let mut local = vec![];
+        for ((n, procs), coord) in global.iter().zip(dims).zip(coords) {
+            let (base, extra) = (n / procs, n % procs);
+            offset.push(coord * base + coord.min(extra));
+            local.push(base + usize::from(coord < extra));
+        }
+        if local.iter().any(|len| *len < halo) {
+            return Err(Error::InvalidHalo);
+        }
+        let total = local.iter().map(|len| len + 2 * halo).product();
+        Ok(DistArray {
+            comm: comm.clone(),
+            global: global.to_vec(),
+            offset,
+            local,
+            halo,
+            data: vec![T::default(); total],
+        })
+    }
+
+    /// Global extent of the array
+    pub fn global_shape(&self) -> &[usize] {
+        &self.global
+    }
+
+    /// Extent of the locally owned block
+    pub fn local_shape(&self) -> &[usize] {
+        &self.local
+    }
+
+    /// Global index of the first locally owned element
+    pub fn offset(&self) -> &[usize] {
+        &self.offset
+    }
+
+    /// Number of ghost layers on each side
+    pub fn halo(&self) -> usize {
+        self.halo
+    }
+
+    /// Extent of the local storage, including ghost cells
+    pub fn padded_shape(&self) -> Vec<usize> {
+        self.local.iter().map(|len| len + 2 * self.halo).collect()
+    }
+
+    /// Local storage including ghost cells, in row-major order
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    /// Local storage including ghost cells, in row-major order
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    /// Return the element at `index` relative to the start of the owned
+    /// block. Indices down to `-halo` and up to `local + halo - 1` address
+    /// ghost cells.
+    pub fn get(&self, index: &[isize]) -> Option<&T> {
+        self.position(index).map(|pos| &self.data[pos])
+    }
+
+    /// Mutable version of `get()`.
+    pub fn get_mut(&mut self, index: &[isize]) -> Option<&mut T> {
+        self.position(index).map(|pos| &mut self.data[pos])
+    }
+
+    /// Fill the ghost cells with the owned data of the neighboring processes.
+    /// Ghost cells past a non-periodic edge are left alone. This is
+    /// collective over all processes in the communicator.
+    ///
+    /// Dimensions are exchanged one after the other, with each face covering
+    /// the ghost cells of the dimensions before it, so corner and edge ghost
+    /// cells are filled as well.
+    pub fn exchange_halos(&mut self) -> Result<()> {
+        if self.halo == 0 {
+            return Ok(());
+        }
+        for dim in 0..self.local.len() {
+            let (minus, plus) = self.comm.cart_shift(dim, 1)?;
+            self.exchange_dim(dim, minus, plus)?;
+        }
+        Ok(())
+    }
+
+    /// Exchange the faces along `dim` with the neighbors at `minus` and
+    /// `plus`.
+    fn exchange_dim(
+        &mut self,
+        dim: usize,
+        minus: Option<usize>,
+        plus: Option<usize>,
+    ) -> Result<()> {
+        let (halo, padded) = (self.halo, self.padded_shape());
+        let len = self.local[dim];
+        // Ranges for everything but `dim`
+        let ranges: Vec<Range<usize>> = (0..self.local.len())
+            .map(|d| {
+                if d < dim {
+                    0..padded[d]
+                } else {
+                    halo..halo + self.local[d]
+                }
+            })
+            .collect();
+        let face = |range: Range<usize>| {
+            let mut ranges = ranges.clone();
+            ranges[dim] = range;
+            runs(&padded, &ranges)
+        };
+        // Faces sent down (to minus) end up in the upper ghost cells and
+        // the other way around
+        let down = HALO_TAG | (2 * dim) as Tag;
+        let up = down | 1;
+        let low_ghost = face(0..halo);
+        let high_ghost = face(halo + len..2 * halo + len);
+        let low_face = face(halo..2 * halo);
+        let high_face = face(len..halo + len);
+
+        unsafe {
+            let ptr = self.data.as_mut_ptr();
+            let recv_iovs = |runs: &[(usize, usize)]| -> Vec<MutIov> {
+                runs.iter()
+                    .map(|(start, count)| {
+                        MutIov(ptr.add(*start) as *mut u8, count * size_of::<T>())
+                    })
+                    .collect()
+            };
+            let send_iovs = |runs: &[(usize, usize)]| -> Vec<Iov> {
+                runs.iter()
+                    .map(|(start, count)| Iov(ptr.add(*start) as *const u8, count * size_of::<T>()))
+                    .collect()
+            };
+            let low_recv = recv_iovs(&low_ghost);
+            let high_recv = recv_iovs(&high_ghost);
+            let low_send = send_iovs(&low_face);
+            let high_send = send_iovs(&high_face);
+
+            // Dropping the requests when a send fails cancels the
+            // receives, which would write into `self.data` otherwise
+            let mut recvs = vec![];
+            let mut sends = vec![];
+            if let Some(minus) = minus {
+                recvs.push(self.comm.irecv_internal(minus, up, &low_recv)?);
+            }
+            if let Some(plus) = plus {
+                recvs.push(self.comm.irecv_internal(plus, down, &high_recv)?);
+            }
+            if let Some(minus) = minus {
+                sends.push(self.comm.isend_internal(minus, down, &low_send)?);
+            }
+            if let Some(plus) = plus {
+                sends.push(self.comm.isend_internal(plus, up, &high_send)?);
+            }
+            for req in recvs.iter_mut() {
+                while let RequestStatus::InProgress = req.progress()? {}
+            }
+            for req in sends.iter_mut() {
+                while let RequestStatus::InProgress = req.progress()? {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Position in the local storage of an index relative to the owned block
+    fn position(&self, index: &[isize]) -> Option<usize> {
+        if index.len() != self.local.len() {
+            return None;
+        }
+        let halo = self.halo as isize;
+        let mut pos = 0;
+        for (i, len) in index.iter().zip(self.local.iter()) {
+            let padded = *len as isize + 2 * halo;
+            let i = i + halo;
+            if !(0..padded).contains(&i) {
+                return None;
+            }
+            pos = pos * padded as usize + i as usize;
+        }
+        Some(pos)
+    }
+}
+
+/// Split the region given by `ranges` of a row-major array of size `shape`
+/// into contiguous (start, count) runs.
+fn runs(shape: &[usize], ranges: &[Range<usize>]) -> Vec<(usize, usize)> {
+    let ndims = shape.len();
+    if ranges.iter().any(|range| range.is_empty()) {
+        return vec![];
+    }
+    let last = ranges[ndims - 1].clone();
+    let mut out = vec![];
+    // Odometer over all but the last dimension
+    let mut index: Vec<usize> = ranges[..ndims - 1]
+        .iter()
+        .map(|range| range.start)
+        .collect();
+    loop {
+        let row = index
+            .iter()
+            .zip(shape.iter())
+            .fold(0, |acc, (i, len)| acc * len + i);
+        out.push((row * shape[ndims - 1] + last.start, last.len()));
+        let mut d = ndims - 1;
+        loop {
+            if d == 0 {
+                return out;
+            }
+            d -= 1;
+            index[d] += 1;
+            if index[d] < ranges[d].end {
+                break;
+            }
+            index[d] = ranges[d].start;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_world;
+
+    #[test]
+    fn failed_send_cancels_receives() {
+        let context = init_world("127.0.0.1:0".parse().unwrap(), 0, 1).unwrap();
+        let comm = context.world().cart_create(&[1], &[true]).unwrap().unwrap();
+        let mut array = DistArray::<u64>::new(&comm, &[4], 1).unwrap();
+        // There's no rank 1, so the first send fails after both receives
+        // have been posted
+        assert!(matches!(
+            array.exchange_dim(0, Some(1), Some(0)),
+            Err(Error::InvalidRank(1))
+        ));
+        // The receive from rank 0 is gone: a face sent now stays unexpected
+        // instead of landing in the ghost cells
+        let down = HALO_TAG;
+        comm.send_internal(0, down, &[7u64]).unwrap();
+        assert_eq!(comm.recv_internal::<u64>(0, down, 1).unwrap(), [7]);
+        assert!(array.as_slice().iter().all(|x| *x == 0));
+    }
+}
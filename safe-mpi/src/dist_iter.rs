@@ -0,0 +1,177 @@
+//! Data-parallel iterators over distributed collections.
+//!
+//! Each process holds its own partition of the items and runs the adapters
+//! (`map()`, `filter()`) locally. Only the final step, `reduce()`,
+//! `collect_on()`, `count()` or `sum()`, communicates. Items are encoded with
+//! `Message`, so anything serde can handle works, as does `Flat<_>`.
+use crate::{communicator::Communicator, message::Message, Error, Reduce, ReduceMode, Result, Tag};
+use std::mem::size_of;
+
+/// Internal tag for the length of an encoded partition
+const DIST_LEN_TAG: Tag = 1 << 28;
+/// Internal tag for the data of an encoded partition
+const DIST_DATA_TAG: Tag = DIST_LEN_TAG | 1;
+
+/// Iterator over the items of a distributed collection, see
+/// `Communicator::dist_iter()`.
+pub struct DistIter<'a, T> {
+    comm: &'a Communicator,
+    items: Vec<T>,
+}
+
+impl Communicator {
+    /// Start a distributed iterator over `items`, the local partition of
+    /// this process. The final step of the iterator is collective over all
+    /// processes in the communicator.
+    pub fn dist_iter<I: IntoIterator>(&self, items: I) -> DistIter<'_, I::Item> {
+        DistIter {
+            comm: self,
+            items: items.into_iter().collect(),
+        }
+    }
+
+    /// Blocking send of a byte buffer of any length using internal tags.
+    fn send_bytes(&self, dest: usize, data: &[u8]) -> Result<()> {
+        self.send_internal(dest, DIST_LEN_TAG, &[data.len() as u64])?;
+        self.send_internal(dest, DIST_DATA_TAG, data)
+    }
+
+    /// Blocking receive of a buffer sent with `send_bytes()`.
+    fn recv_bytes(&self, source: usize) -> Result<Vec<u8>> {
+        let len: Vec<u64> = self.recv_internal(source, DIST_LEN_TAG, 1)?;
+        self.recv_internal(source, DIST_DATA_TAG, len[0] as usize)
+    }
+}
+
+impl<'a, T> DistIter<'a, T> {
+    /// Apply `f` to every item.
+    pub fn map<U, F: FnMut(T) -> U>(self, f: F) -> DistIter<'a, U> {
+        DistIter {
+            comm: self.comm,
+            items: self.items.into_iter().map(f).collect(),
+        }
+    }
+
+    /// Keep only the items that `pred` returns true for.
+    pub fn filter<F: FnMut(&T) -> bool>(self, pred: F) -> DistIter<'a, T> {
+        DistIter {
+            comm: self.comm,
+            items: self.items.into_iter().filter(pred).collect(),
+        }
+    }
+
+    /// Return the local partition without communicating.
+    pub fn local(self) -> Vec<T> {
+        self.items
+    }
+
+    /// Count the items across all processes.
+    pub fn count(self) -> Result<usize> {
+        self.comm
+            .sum(&[self.items.len() as u64], ReduceMode::Fast)
+            .map(|count| count as usize)
+    }
+}
+
+impl<'a, T: Reduce> DistIter<'a, T> {
+    /// Sum the items across all processes. In `ReduceMode::Reproducible` the
+    /// result doesn't depend on how the items are split up.
+    pub fn sum(self, mode: ReduceMode) -> Result<T> {
+        self.comm.sum(&self.items, mode)
+    }
+}
+
+impl<'a, T: Message> DistIter<'a, T> {
+    /// Combine all items across all processes with `op` and return the
+    /// result on every process, or `None` if there are no items at all.
+    /// Items are combined in rank order, so `op` has to be associative but
+    /// not necessarily commutative.
+    pub fn reduce<F: Fn(T, T) -> T>(self, op: F) -> Result<Option<T>> {
+        let comm = self.comm;
+        if comm.is_inter() {
+            return Err(Error::InterCommunicator);
+        }
+        let rank = comm.rank();
+        let size = comm.size();
+        let mut acc = self.items.into_iter().reduce(&op);
+
+        // Binomial tree to rank 0: acc always covers the ranks
+        // [rank, rank + mask)
+        let mut mask = 1;
+        while mask < size {
+            if rank & mask != 0 {
+                comm.send_bytes(rank - mask, &encode_items(acc.as_slice())?)?;
+                break;
+            } else if rank + mask < size {
+                let other = decode_items(&comm.recv_bytes(rank + mask)?)?;
+                acc = acc.into_iter().chain(other).reduce(&op);
+            }
+            mask <<= 1;
+        }
+
+        let data = if rank == 0 {
+            encode_items(acc.as_slice())?
+        } else {
+            vec![]
+        };
+        let len = comm.tree_bcast(vec![data.len() as u64], 1, 0)?[0];
+        let data = comm.tree_bcast(data, len as usize, 0)?;
+        Ok(decode_items(&data)?.pop())
+    }
+
+    /// Gather all items on `root`, in rank order. Returns `None` on all
+    /// other processes.
+    pub fn collect_on(self, root: usize) -> Result<Option<Vec<T>>> {
+        let comm = self.comm;
+        if comm.is_inter() {
+            return Err(Error::InterCommunicator);
+        }
+        if root >= comm.size() {
+            return Err(Error::InvalidRank(root));
+        }
+        if comm.rank() != root {
+            comm.send_bytes(root, &encode_items(&self.items)?)?;
+            return Ok(None);
+        }
+        let mut out = vec![];
+        let mut local = Some(self.items);
+        for source in 0..comm.size() {
+            if source == root {
+                out.extend(local.take().unwrap());
+            } else {
+                out.extend(decode_items(&comm.recv_bytes(source)?)?);
+            }
+        }
+        Ok(Some(out))
+    }
+}
+
+/// Encode items one after the other, each preceded by its length.
+fn encode_items<T: Message>(items: &[T]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    for item in items {
+        let data = item.encode()?;
+        out.extend_from_slice(&(data.len() as u64).to_ne_bytes());
+        out.extend(data);
+    }
+    Ok(out)
+}
+
+/// Decode items encoded with `encode_items()`.
+fn decode_items<T: Message>(mut data: &[u8]) -> Result<Vec<T>> {
+    let mut out = vec![];
+    while !data.is_empty() {
+        if data.len() < size_of::<u64>() {
+            return Err(Error::DeserializeError);
+        }
+        let (len, rest) = data.split_at(size_of::<u64>());
+        let len = u64::from_ne_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(Error::DeserializeError);
+        }
+        let (item, rest) = rest.split_at(len);
+        out.push(T::decode(item)?);
+        data = rest;
+    }
+    Ok(out)
+}
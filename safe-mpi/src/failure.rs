@@ -0,0 +1,165 @@
+//! Detection of failed processes.
+//!
+//! Every endpoint is created with an error handler, which UCP calls from
+//! inside worker progress once the connection to the peer is broken. The
+//! handler marks the peer as failed, after which pending and new operations
+//! involving it fail with `Error::PeerFailed` instead of a generic
+//! `FailedRequest` or waiting forever. Communicators revoked with
+//! `Communicator::revoke()` and aborts from `Context::abort()` are tracked
+//! here as well.
+use crate::{callbacks::peer_err_callback, status_to_string, Context, Error, Result, Tag};
+use log::{error, warn};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::os::raw::c_void;
+use std::rc::Rc;
+use ucx2_sys::{ucp_ep_h, ucp_err_handler_t, ucs_status_t};
+
+/// Callback run when a peer fails
+type FailureCallback = Box<dyn FnMut(usize)>;
+
+/// Failure state of the peers, shared with the UCP error handler.
+#[derive(Default)]
+pub(crate) struct PeerState {
+    /// Rank of the process each endpoint is connected to
+    ranks: RefCell<HashMap<ucp_ep_h, usize>>,
+    /// Ranks of the processes that have failed
+    failed: RefCell<BTreeSet<usize>>,
+    /// User callbacks, see `Context::on_peer_failure()`
+    callbacks: RefCell<Vec<FailureCallback>>,
+    /// Context IDs of revoked communicators
+    revoked: RefCell<HashSet<Tag>>,
+    /// Number of agreements started on each context ID
+    agreements: RefCell<HashMap<Tag, u64>>,
+    /// Rank and error code of the process that aborted the job
+    aborted: Cell<Option<(usize, i32)>>,
+}
+
+/// What a pending receive depends on, so that it can fail instead of
+/// waiting for a message that can't arrive anymore.
+#[derive(Copy, Clone, Default)]
+pub(crate) struct Watch {
+    /// World rank of the source, unless receiving from any process
+    pub peer: Option<usize>,
+    /// Context ID of the communicator, unless the receive has to work on a
+    /// revoked communicator as well
+    pub context: Option<Tag>,
+}
+
+impl PeerState {
+    /// Return the error handler to create endpoints with. The state has to
+    /// outlive the endpoints.
+    pub(crate) fn err_handler(self: &Rc<Self>) -> ucp_err_handler_t {
+        ucp_err_handler_t {
+            cb: Some(peer_err_callback),
+            arg: Rc::as_ptr(self) as *mut c_void,
+        }
+    }
+
+    /// Record that `ep` is connected to process `rank`.
+    pub(crate) fn add(&self, ep: ucp_ep_h, rank: usize) {
+        self.ranks.borrow_mut().insert(ep, rank);
+    }
+
+    /// Mark the process behind `ep` as failed (called from the UCP callback).
+    pub(crate) fn fail(&self, ep: ucp_ep_h, status: ucs_status_t) {
+        let rank = match self.ranks.borrow().get(&ep) {
+            Some(rank) => *rank,
+            None => {
+                warn!("Error on an unknown endpoint: {}", status_to_string(status));
+                return;
+            }
+        };
+        error!("Process {} failed: {}", rank, status_to_string(status));
+        if !self.failed.borrow_mut().insert(rank) {
+            return;
+        }
+        // Take the callbacks out while running them, so that they can
+        // register new ones
+        let mut callbacks = self.callbacks.take();
+        for callback in callbacks.iter_mut() {
+            callback(rank);
+        }
+        let mut current = self.callbacks.borrow_mut();
+        callbacks.append(&mut current);
+        *current = callbacks;
+    }
+
+    /// Return true if process `rank` has failed.
+    pub(crate) fn is_failed(&self, rank: usize) -> bool {
+        self.failed.borrow().contains(&rank)
+    }
+
+    /// Return the ranks of all failed processes, in order.
+    pub(crate) fn failed(&self) -> Vec<usize> {
+        self.failed.borrow().iter().copied().collect()
+    }
+
+    /// Return an error if whatever `watch` depends on has failed or has been
+    /// revoked.
+    pub(crate) fn check(&self, watch: &Watch) -> Result<()> {
+        self.check_aborted()?;
+        if let Some(peer) = watch.peer.filter(|peer| self.is_failed(*peer)) {
+            return Err(Error::PeerFailed(peer));
+        }
+        match watch.context {
+            Some(context) if self.is_revoked(context) => Err(Error::Revoked),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record that process `rank` aborted the job with `code`. Only the
+    /// first abort is kept.
+    pub(crate) fn abort(&self, rank: usize, code: i32) {
+        if self.aborted.get().is_none() {
+            self.aborted.set(Some((rank, code)));
+        }
+    }
+
+    /// Return `Error::Aborted` if the job has been aborted.
+    pub(crate) fn check_aborted(&self) -> Result<()> {
+        match self.aborted.get() {
+            Some((rank, code)) => Err(Error::Aborted(rank, code)),
+            None => Ok(()),
+        }
+    }
+
+    /// Mark the communicator with `context` as revoked.
+    pub(crate) fn revoke(&self, context: Tag) {
+        self.revoked.borrow_mut().insert(context);
+    }
+
+    /// Return true if the communicator with `context` has been revoked.
+    pub(crate) fn is_revoked(&self, context: Tag) -> bool {
+        self.revoked.borrow().contains(&context)
+    }
+
+    /// Return the sequence number of the next agreement on `context`.
+    pub(crate) fn next_agreement(&self, context: Tag) -> u64 {
+        let mut agreements = self.agreements.borrow_mut();
+        let seq = agreements.entry(context).or_default();
+        *seq += 1;
+        *seq - 1
+    }
+
+    /// Forget everything about `context`, once it's free to be reused.
+    pub(crate) fn release_context(&self, context: Tag) {
+        self.revoked.borrow_mut().remove(&context);
+        self.agreements.borrow_mut().remove(&context);
+    }
+}
+
+impl Context {
+    /// Register `f` to be called with the world rank of every process that
+    /// fails. It's called from inside UCX progress, so it must not
+    /// communicate itself.
+    pub fn on_peer_failure<F: FnMut(usize) + 'static>(&self, f: F) {
+        let peers = Rc::clone(&self.handle.borrow().peers);
+        peers.callbacks.borrow_mut().push(Box::new(f));
+    }
+
+    /// Return the world ranks of the processes known to have failed.
+    pub fn failed_peers(&self) -> Vec<usize> {
+        self.handle.borrow().peers.failed()
+    }
+}
@@ -0,0 +1,122 @@
+//! Master/worker task farm.
+//!
+//! The master hands out work items one at a time to whichever worker asks
+//! for one. A worker's request for more work carries the result of its
+//! previous item, so once every worker has been told to stop all results are
+//! in and nothing else is needed for termination.
+use crate::{communicator::Communicator, Error, Result, Tag};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+/// Tag used for all farm messages, on the farm's own communicator
+const FARM_TAG: Tag = 0;
+
+/// Work distribution over the processes of a communicator, with one master
+/// and all other processes as workers.
+pub struct TaskFarm<In, Out> {
+    comm: Communicator,
+    master: usize,
+    ordered: bool,
+    marker: PhantomData<fn(In) -> Out>,
+}
+
+impl<In, Out> TaskFarm<In, Out>
+where
+    In: Serialize + DeserializeOwned + 'static,
+    Out: Serialize + DeserializeOwned + 'static,
+{
+    /// Create a farm with `master` handing out the work. This duplicates
+    /// `comm`, so it's collective over all processes in the communicator.
+    pub fn new(comm: &Communicator, master: usize) -> Result<TaskFarm<In, Out>> {
+        if master >= comm.size() {
+            return Err(Error::InvalidRank(master));
+        }
+        Ok(TaskFarm {
+            comm: comm.dup()?,
+            master,
+            ordered: false,
+            marker: PhantomData,
+        })
+    }
+
+    /// Return results in the order of the items, instead of the order they
+    /// were finished in.
+    pub fn ordered(mut self, ordered: bool) -> TaskFarm<In, Out> {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Return true if this process is the master.
+    pub fn is_master(&self) -> bool {
+        self.comm.rank() == self.master
+    }
+
+    /// Run the farm: the master distributes `items` and gets the results,
+    /// workers apply `f` and get `None`. `items` is ignored on workers. With
+    /// a single process the master does all the work itself.
+    pub fn run<I, F>(&self, items: I, f: F) -> Result<Option<Vec<Out>>>
+    where
+        I: IntoIterator<Item = In>,
+        F: FnMut(In) -> Out,
+    {
+        if !self.is_master() {
+            self.worker(f)?;
+            Ok(None)
+        } else if self.comm.size() == 1 {
+            Ok(Some(items.into_iter().map(f).collect()))
+        } else {
+            self.master(items).map(Some)
+        }
+    }
+
+    /// Hand out `items` to the workers until they have all been processed,
+    /// and return the results. Must be called on the master only, while all
+    /// other processes call `worker()`.
+    pub fn master<I: IntoIterator<Item = In>>(&self, items: I) -> Result<Vec<Out>> {
+        if !self.is_master() {
+            return Err(Error::InvalidRank(self.comm.rank()));
+        }
+        let mut items = items.into_iter().enumerate();
+        let mut results = vec![];
+        let mut workers = self.comm.size() - 1;
+        while workers > 0 {
+            let msg = self.comm.recv_any(None, FARM_TAG)?;
+            if let Some(result) = msg.decode::<Option<(u64, Out)>>()? {
+                results.push(result);
+            }
+            let next = items.next().map(|(i, item)| (i as u64, item));
+            if next.is_none() {
+                workers -= 1;
+            }
+            self.comm.send_message(msg.source(), FARM_TAG, &next)?;
+        }
+        if self.ordered {
+            results.sort_by_key(|(i, _)| *i);
+        }
+        Ok(results.into_iter().map(|(_, out)| out).collect())
+    }
+
+    /// Ask the master for work and apply `f` to each item, until the master
+    /// runs out. Returns the number of items processed.
+    pub fn worker<F: FnMut(In) -> Out>(&self, mut f: F) -> Result<usize> {
+        if self.is_master() {
+            return Err(Error::InvalidRank(self.comm.rank()));
+        }
+        let mut result: Option<(u64, Out)> = None;
+        let mut count = 0;
+        loop {
+            self.comm.send_message(self.master, FARM_TAG, &result)?;
+            let item = self
+                .comm
+                .recv_any(Some(self.master), FARM_TAG)?
+                .decode::<Option<(u64, In)>>()?;
+            match item {
+                Some((i, item)) => {
+                    result = Some((i, f(item)));
+                    count += 1;
+                }
+                None => return Ok(count),
+            }
+        }
+    }
+}
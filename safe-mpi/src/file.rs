@@ -0,0 +1,192 @@
+//! Collective I/O to a file shared by all processes.
+//!
+//! In a collective write each process contributes a block, and the blocks are
+//! laid out back to back in rank order, starting at a common offset. The
+//! position of each block comes from an exclusive scan over the block sizes.
+//! With aggregation enabled, a few processes gather the blocks of their
+//! neighbors and write them as one large contiguous piece instead.
+use crate::{communicator::Communicator, Error, ReduceMode, ReduceOp, Result, Tag};
+use flat::FlatBuffer;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Internal tag for data sent to or from an aggregator
+const FILE_TAG: Tag = 1 << 27;
+
+/// File opened by all processes of a communicator.
+pub struct File {
+    comm: Communicator,
+    file: std::fs::File,
+    /// Number of aggregators, 0 if every process does its own I/O
+    aggregators: usize,
+}
+
+impl File {
+    /// Open the file at `path` for reading and writing on all processes,
+    /// creating it if it doesn't exist. This is collective over all processes
+    /// in the communicator, and fails everywhere if it fails anywhere.
+    pub fn open_collective<P: AsRef<Path>>(comm: &Communicator, path: P) -> Result<File> {
+        let comm = comm.dup()?;
+        // Only one process creates the file, so the others don't race it
+        let created = if comm.rank() == 0 {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .map(Some)
+        } else {
+            Ok(None)
+        };
+        agree(
+            &comm,
+            created.as_ref().map(|_| ()).map_err(|err| err.kind()),
+        )?;
+        let file = match created {
+            Ok(Some(file)) => Ok(file),
+            _ => OpenOptions::new().read(true).write(true).open(&path),
+        };
+        agree(&comm, file.as_ref().map(|_| ()).map_err(|err| err.kind()))?;
+        Ok(File {
+            comm,
+            file: file.unwrap(),
+            aggregators: 0,
+        })
+    }
+
+    /// Use `aggregators` processes to do all the I/O, or let every process do
+    /// its own I/O with 0 (the default). Processes are split into contiguous
+    /// groups of nearly equal size, each with one aggregator.
+    pub fn set_aggregators(&mut self, aggregators: usize) {
+        self.aggregators = aggregators.min(self.comm.size());
+    }
+
+    /// Return the size of the file in bytes.
+    pub fn size(&self) -> Result<u64> {
+        self.file
+            .metadata()
+            .map(|meta| meta.len())
+            .map_err(|err| Error::IoFailure(err.kind()))
+    }
+
+    /// Write the block of every process to the file, in rank order starting
+    /// at byte `offset`, which must be the same on all processes. Returns the
+    /// total number of bytes written.
+    pub fn write_at_all<T: FlatBuffer + Copy + Default>(
+        &self,
+        offset: u64,
+        data: &[T],
+    ) -> Result<u64> {
+        let bytes = unsafe { std::slice::from_raw_parts(data.ptr(), data.size()) };
+        let (start, total, sizes) = self.layout(bytes.len())?;
+        if self.aggregators == 0 {
+            let result = self.file.write_all_at(bytes, offset + start);
+            agree(&self.comm, result.map_err(|err| err.kind()))?;
+            return Ok(total);
+        }
+        let (leader, members) = self.group();
+        let result = if self.comm.rank() == leader {
+            let mut block = bytes.to_vec();
+            for member in members.skip(1) {
+                let size = sizes[member] as usize;
+                block.extend(self.comm.recv_internal::<u8>(member, FILE_TAG, size)?);
+            }
+            self.file.write_all_at(&block, offset + start)
+        } else {
+            self.comm.send_internal(leader, FILE_TAG, bytes)?;
+            Ok(())
+        };
+        agree(&self.comm, result.map_err(|err| err.kind()))?;
+        Ok(total)
+    }
+
+    /// Read the block of every process from the file, laid out as by
+    /// `write_at_all()` with the same `offset`. Returns the total number of
+    /// bytes read.
+    pub fn read_at_all<T: FlatBuffer + Copy + Default>(
+        &self,
+        offset: u64,
+        data: &mut [T],
+    ) -> Result<u64> {
+        let bytes = unsafe { std::slice::from_raw_parts_mut(data.ptr_mut(), data.size()) };
+        let (start, total, sizes) = self.layout(bytes.len())?;
+        if self.aggregators == 0 {
+            let result = self.file.read_exact_at(bytes, offset + start);
+            agree(&self.comm, result.map_err(|err| err.kind()))?;
+            return Ok(total);
+        }
+        let (leader, members) = self.group();
+        let result = if self.comm.rank() == leader {
+            let sizes: Vec<usize> = members.map(|member| sizes[member] as usize).collect();
+            let mut block = vec![0u8; sizes.iter().sum()];
+            // Members are still sent their (zeroed) data on failure, so that
+            // they don't wait forever
+            let result = self.file.read_exact_at(&mut block, offset + start);
+            let (own, mut rest) = block.split_at(sizes[0]);
+            bytes.copy_from_slice(own);
+            for (member, size) in self.group().1.skip(1).zip(&sizes[1..]) {
+                let (part, tail) = rest.split_at(*size);
+                self.comm.send_internal(member, FILE_TAG, part)?;
+                rest = tail;
+            }
+            result
+        } else {
+            let part: Vec<u8> = self.comm.recv_internal(leader, FILE_TAG, bytes.len())?;
+            bytes.copy_from_slice(&part);
+            Ok(())
+        };
+        agree(&self.comm, result.map_err(|err| err.kind()))?;
+        Ok(total)
+    }
+
+    /// Flush all writes to the storage device. This is collective over all
+    /// processes in the communicator.
+    pub fn sync_all(&self) -> Result<()> {
+        let result = self.file.sync_all();
+        agree(&self.comm, result.map_err(|err| err.kind()))
+    }
+
+    /// Return the offset of this process's block relative to the start of
+    /// the write, the total size of all blocks and, with aggregation, the
+    /// size of each block. An aggregator's offset is that of its whole
+    /// group, since it's the first process in it.
+    fn layout(&self, len: usize) -> Result<(u64, u64, Vec<u64>)> {
+        let len = len as u64;
+        if self.aggregators > 0 {
+            let sizes = self.comm.allgather(&[len])?;
+            let start = sizes[..self.comm.rank()].iter().sum();
+            let total = sizes.iter().sum();
+            return Ok((start, total, sizes));
+        }
+        let start = self
+            .comm
+            .exscan(&[len], ReduceOp::Sum)?
+            .map_or(0, |start| start[0]);
+        let total = self.comm.sum(&[len], ReduceMode::Fast)?;
+        Ok((start, total, vec![]))
+    }
+
+    /// Return the aggregator of this process's group and all ranks in the
+    /// group, aggregator first.
+    fn group(&self) -> (usize, impl Iterator<Item = usize>) {
+        let (size, aggregators) = (self.comm.size(), self.aggregators);
+        let group = self.comm.rank() * aggregators / size;
+        let first = (group * size).div_ceil(aggregators);
+        let last = ((group + 1) * size).div_ceil(aggregators);
+        (first, first..last)
+    }
+}
+
+/// Agree on the outcome of a local I/O operation across all processes, so
+/// that it either succeeds everywhere or fails everywhere.
+fn agree(comm: &Communicator, result: std::result::Result<(), ErrorKind>) -> Result<()> {
+    let ok = comm.allreduce(&[u8::from(result.is_ok())], ReduceOp::Min, ReduceMode::Fast)?[0];
+    match result {
+        Err(kind) => Err(Error::IoFailure(kind)),
+        Ok(()) if ok == 0 => Err(Error::IoFailure(ErrorKind::Other)),
+        Ok(()) => Ok(()),
+    }
+}
@@ -0,0 +1,143 @@
+//! Orderly shutdown.
+//!
+//! Dropping the context closes all endpoints by force, which can lose
+//! messages that are still in flight. `Context::finalize()` first flushes
+//! everything this process sent, then waits for all other processes to get
+//! to the same point with a dissemination barrier over the world, so that
+//! only O(log n) endpoints are needed for it, and finally closes the
+//! endpoints in flush mode. Every step gives up at a common deadline.
+use crate::{
+    callbacks::tag_recv_nbx_callback, listener::close_error, request::wait_nbx_until, tag, Context,
+    Error, Result, Tag,
+};
+use log::{debug, info, warn};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use ucx2_sys::{
+    ucp_request_param_t, ucp_request_param_t__bindgen_ty_1, ucp_tag_recv_nbx, ucp_tag_send_nbx,
+    ucp_worker_flush_nbx, UCP_EP_CLOSE_FLAG_FORCE,
+};
+
+/// How long `finalize()` waits for the other processes
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Internal tag of the shutdown handshake, on the world context
+const FINALIZE_TAG: Tag = 1 << 25;
+
+impl Context {
+    /// Shut down communication: flush all outstanding operations, wait for
+    /// all processes in the world to call `finalize()` too and close the
+    /// endpoints without dropping messages in flight. Returns
+    /// `RequestTimeout` instead of hanging if this takes too long, e.g.
+    /// because some process never gets here, or `PeerFailed` if another
+    /// process failed on the way. This is collective over all processes in
+    /// the world.
+    pub fn finalize(self) -> Result<()> {
+        let deadline = Instant::now() + FINALIZE_TIMEOUT;
+        let worker = Rc::clone(&self.handle.borrow().worker);
+        info!("Finalizing");
+        let result = unsafe {
+            wait_nbx_until(&worker, Some(deadline), |param| {
+                ucp_worker_flush_nbx(worker.as_raw(), param)
+            })
+        }
+        .and_then(|_| self.handshake(deadline));
+        // Close the endpoints even if the handshake failed, but then by force
+        let closed = self.close_endpoints(deadline, result.is_err());
+        result.and(closed)
+    }
+
+    /// Dissemination barrier over the world: in round k every process
+    /// notifies the one 2^k ranks ahead and waits for the one 2^k ranks
+    /// behind. Failed processes are skipped.
+    fn handshake(&self, deadline: Instant) -> Result<()> {
+        let (worker, rank, size) = {
+            let handle = self.handle.borrow();
+            (Rc::clone(&handle.worker), handle.rank, handle.size)
+        };
+        let mut distance = 1;
+        while distance < size {
+            let dest = (rank + distance) % size;
+            let source = (rank + size - distance) % size;
+
+            let ep = self.handle.borrow_mut().endpoint(dest);
+            let sent = ep.and_then(|ep| unsafe {
+                let tag = tag::internal(tag::WORLD_CONTEXT, rank, FINALIZE_TAG);
+                wait_nbx_until(&worker, Some(deadline), |param| {
+                    ucp_tag_send_nbx(ep, std::ptr::null(), 0, tag, param)
+                })
+            });
+            match sent.map_err(|err| self.handle.borrow().peer_error(dest, err)) {
+                Ok(()) | Err(Error::PeerFailed(_)) => (),
+                Err(err) => return Err(err),
+            }
+
+            if !self.handle.borrow().peers.is_failed(source) {
+                let received = unsafe {
+                    let tag = tag::internal(tag::WORLD_CONTEXT, source, FINALIZE_TAG);
+                    wait_nbx_until(&worker, Some(deadline), |param| {
+                        let param = ucp_request_param_t {
+                            cb: ucp_request_param_t__bindgen_ty_1 {
+                                recv: Some(tag_recv_nbx_callback),
+                            },
+                            ..*param
+                        };
+                        ucp_tag_recv_nbx(
+                            worker.as_raw(),
+                            std::ptr::null_mut(),
+                            0,
+                            tag,
+                            tag::mask(true),
+                            &param,
+                        )
+                    })
+                };
+                match received.map_err(|err| self.handle.borrow().peer_error(source, err)) {
+                    Ok(()) | Err(Error::PeerFailed(_)) => (),
+                    Err(err) => return Err(err),
+                }
+            }
+            distance <<= 1;
+        }
+        Ok(())
+    }
+
+    /// Close all endpoints, in flush mode unless `force` is set or the peer
+    /// has failed. Endpoints still used by the remote keys of a window are
+    /// left to be closed by force once the window is dropped.
+    fn close_endpoints(&self, deadline: Instant, force: bool) -> Result<()> {
+        let (endpoints, peers) = {
+            let mut handle = self.handle.borrow_mut();
+            let len = handle.endpoints.len();
+            let endpoints = std::mem::replace(
+                &mut handle.endpoints,
+                (0..len).map(|_| None).collect(),
+            );
+            (endpoints, Rc::clone(&handle.peers))
+        };
+        let mut result = Ok(());
+        for (rank, ep) in endpoints.into_iter().enumerate() {
+            // Closed here instead of by force on drop
+            let ep = match ep.map(Rc::try_unwrap) {
+                Some(Ok(ep)) => ep,
+                Some(Err(_)) => {
+                    warn!("Endpoint for process {} is still used by a window", rank);
+                    continue;
+                }
+                None => continue,
+            };
+            let force = force || peers.is_failed(rank);
+            let flags = if force { UCP_EP_CLOSE_FLAG_FORCE } else { 0 };
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match ep.close(flags, timeout).map_err(close_error) {
+                Ok(()) => (),
+                // The peer may already be gone after the handshake, once
+                // everything has been delivered
+                Err(Error::FailedRequest(status)) => {
+                    debug!("Closing endpoint for process {} failed: {}", rank, status)
+                }
+                Err(err) => result = result.and(Err(err)),
+            }
+        }
+        result
+    }
+}
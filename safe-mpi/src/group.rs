@@ -0,0 +1,381 @@
+//! Process groups and inter-communicators.
+use crate::{
+    communicator::{first_free_context, Communicator},
+    Error, Result, Tag,
+};
+use std::rc::Rc;
+use ucx2_sys::ucs_status_t;
+
+/// Internal tag bit for the leader exchange when creating inter-communicators
+const INTERCOMM_TAG: Tag = 1 << 31;
+
+/// Ordered set of processes.
+///
+/// Groups are local objects, creating and combining them doesn't involve any
+/// communication. Use `Communicator::create_group()` to get a communicator
+/// for a group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    /// World ranks of the members, in group rank order
+    ranks: Vec<usize>,
+    /// World rank of this process
+    world_rank: usize,
+}
+
+impl Group {
+    /// Number of processes in the group
+    pub fn size(&self) -> usize {
+        self.ranks.len()
+    }
+
+    /// Rank of this process in the group, if it's a member
+    pub fn rank(&self) -> Option<usize> {
+        self.position(self.world_rank)
+    }
+
+    /// Create a group from the processes at `ranks` in this group, in that
+    /// order.
+    pub fn incl(&self, ranks: &[usize]) -> Result<Group> {
+        let mut out = vec![];
+        for rank in ranks {
+            let world_rank = *self.ranks.get(*rank).ok_or(Error::InvalidRank(*rank))?;
+            if out.contains(&world_rank) {
+                return Err(Error::InvalidRank(*rank));
+            }
+            out.push(world_rank);
+        }
+        Ok(self.with_ranks(out))
+    }
+
+    /// Create a group from this group without the processes at `ranks`.
+    pub fn excl(&self, ranks: &[usize]) -> Result<Group> {
+        if let Some(rank) = ranks.iter().find(|rank| **rank >= self.size()) {
+            return Err(Error::InvalidRank(*rank));
+        }
+        let out = self
+            .ranks
+            .iter()
+            .enumerate()
+            .filter(|(rank, _)| !ranks.contains(rank))
+            .map(|(_, world_rank)| *world_rank)
+            .collect();
+        Ok(self.with_ranks(out))
+    }
+
+    /// All processes in this group, followed by those in `other` that aren't
+    /// in this group.
+    pub fn union(&self, other: &Group) -> Group {
+        let mut out = self.ranks.clone();
+        out.extend(other.ranks.iter().filter(|rank| !self.ranks.contains(rank)));
+        self.with_ranks(out)
+    }
+
+    /// Processes in this group that are also in `other`, in the order of
+    /// this group.
+    pub fn intersection(&self, other: &Group) -> Group {
+        let out = self
+            .ranks
+            .iter()
+            .filter(|rank| other.ranks.contains(rank))
+            .copied()
+            .collect();
+        self.with_ranks(out)
+    }
+
+    /// Processes in this group that aren't in `other`.
+    pub fn difference(&self, other: &Group) -> Group {
+        let out = self
+            .ranks
+            .iter()
+            .filter(|rank| !other.ranks.contains(rank))
+            .copied()
+            .collect();
+        self.with_ranks(out)
+    }
+
+    /// Translate `ranks` in this group to the corresponding ranks in `other`.
+    /// Processes that aren't in `other` are returned as `None`.
+    pub fn translate_ranks(&self, ranks: &[usize], other: &Group) -> Result<Vec<Option<usize>>> {
+        ranks
+            .iter()
+            .map(|rank| {
+                let world_rank = *self.ranks.get(*rank).ok_or(Error::InvalidRank(*rank))?;
+                Ok(other.position(world_rank))
+            })
+            .collect()
+    }
+
+    /// Group rank of a world rank
+    fn position(&self, world_rank: usize) -> Option<usize> {
+        self.ranks.iter().position(|rank| *rank == world_rank)
+    }
+
+    fn with_ranks(&self, ranks: Vec<usize>) -> Group {
+        Group {
+            ranks,
+            world_rank: self.world_rank,
+        }
+    }
+}
+
+impl Communicator {
+    /// Return the group of this communicator (the local group for
+    /// inter-communicators).
+    pub fn group(&self) -> Group {
+        Group {
+            ranks: self.group.to_vec(),
+            world_rank: self.group[self.rank],
+        }
+    }
+
+    /// Return the remote group of an inter-communicator.
+    pub fn remote_group(&self) -> Option<Group> {
+        self.remote.as_ref().map(|remote| Group {
+            ranks: remote.to_vec(),
+            world_rank: self.group[self.rank],
+        })
+    }
+
+    /// Create a communicator for `group`, which must be a subset of this
+    /// communicator's group. This is collective over all processes in this
+    /// communicator and they must all pass the same group. Processes outside
+    /// of the group get `None`.
+    pub fn create_group(&self, group: &Group) -> Result<Option<Communicator>> {
+        if let Some(rank) = group.ranks.iter().find(|rank| !self.group.contains(rank)) {
+            return Err(Error::InvalidRank(*rank));
+        }
+        let key = group.position(self.group[self.rank]);
+        self.split(key.map(|_| 0), key.unwrap_or(0) as i32)
+    }
+
+    /// Create an inter-communicator between the group of this communicator
+    /// and a disjoint remote group.
+    ///
+    /// This is collective over both groups. The leaders (`local_leader` in
+    /// this communicator and `remote_leader` on the other side) exchange
+    /// group information over `peer_comm`, where `remote_leader` is a rank.
+    /// `tag` separates concurrent calls between the same leaders. If the
+    /// local leader fails, every process in the group returns its error.
+    pub fn create_intercomm(
+        &self,
+        local_leader: usize,
+        peer_comm: &Communicator,
+        remote_leader: usize,
+        tag: Tag,
+    ) -> Result<Communicator> {
+        // Same on every process, so everyone fails here together
+        if tag & INTERCOMM_TAG != 0 {
+            return Err(Error::InvalidTag(tag));
+        }
+        if local_leader >= self.size() {
+            return Err(Error::InvalidRank(local_leader));
+        }
+        // Context IDs free on the local side
+        let free = self.free_contexts()?;
+
+        // [status, context ID or error payload, remote group size]
+        let mut header = [0u64; 3];
+        let mut remote = vec![];
+        if self.rank == local_leader {
+            header = match self.exchange_groups(peer_comm, remote_leader, tag, &free) {
+                Ok((id, ranks)) => {
+                    remote = ranks;
+                    [STATUS_OK, id, remote.len() as u64]
+                }
+                Err(err) => {
+                    let (status, payload) = encode_error(err);
+                    [status, payload, 0]
+                }
+            };
+        }
+        // The leader always gets here, so that the others don't wait for it
+        // forever when it fails
+        self.bcast(&mut header, local_leader)?;
+        if header[0] != STATUS_OK {
+            return Err(decode_error(header[0], header[1]));
+        }
+        remote.resize(header[2] as usize, 0);
+        self.bcast(&mut remote, local_leader)?;
+
+        let id = header[1];
+        self.claim_context(id);
+        let mut comm = self.with_group(id, Rc::clone(&self.group), self.rank);
+        comm.remote = Some(Rc::new(remote.iter().map(|rank| *rank as usize).collect()));
+        Ok(comm)
+    }
+
+    /// Exchange the groups and free context IDs with the remote leader, on
+    /// the local leader. Returns the context ID of the inter-communicator and
+    /// the world ranks of the remote group.
+    fn exchange_groups(
+        &self,
+        peer_comm: &Communicator,
+        remote_leader: usize,
+        tag: Tag,
+        free: &[u64],
+    ) -> Result<(Tag, Vec<u64>)> {
+        if peer_comm.is_inter() {
+            return Err(Error::InterCommunicator);
+        }
+        let other = *peer_comm
+            .group
+            .get(remote_leader)
+            .ok_or(Error::InvalidRank(remote_leader))?;
+        // The side with the lower world rank sends first
+        let send_first = self.group[self.rank] < other;
+        let tag = INTERCOMM_TAG | tag;
+        let local: Vec<u64> = self.group.iter().map(|rank| *rank as u64).collect();
+        let remote = leader_exchange(peer_comm, remote_leader, tag, send_first, &local)?;
+        let remote_free = leader_exchange(peer_comm, remote_leader, tag, send_first, free)?;
+        if remote.iter().any(|rank| local.contains(rank)) {
+            return Err(Error::OverlappingGroups);
+        }
+        let free: Vec<u64> = free
+            .iter()
+            .zip(remote_free.iter())
+            .map(|(a, b)| a & b)
+            .collect();
+        Ok((first_free_context(&free)?, remote))
+    }
+}
+
+// Statuses broadcast by the local leader in `create_intercomm()`, with
+// the errors it can forward to the rest of the group
+const STATUS_OK: u64 = 0;
+const STATUS_INTER_COMMUNICATOR: u64 = 1;
+const STATUS_INVALID_RANK: u64 = 2;
+const STATUS_OVERLAPPING_GROUPS: u64 = 3;
+const STATUS_CONTEXT_EXHAUSTED: u64 = 4;
+const STATUS_PEER_FAILED: u64 = 5;
+const STATUS_REVOKED: u64 = 6;
+const STATUS_FAILED_REQUEST: u64 = 7;
+const STATUS_REQUEST_TIMEOUT: u64 = 8;
+/// Any other error, which the other processes see as `ConnectFailure`
+const STATUS_OTHER: u64 = 9;
+
+/// Turn an error of the local leader into a status and payload to broadcast.
+fn encode_error(err: Error) -> (u64, u64) {
+    match err {
+        Error::InterCommunicator => (STATUS_INTER_COMMUNICATOR, 0),
+        Error::InvalidRank(rank) => (STATUS_INVALID_RANK, rank as u64),
+        Error::OverlappingGroups => (STATUS_OVERLAPPING_GROUPS, 0),
+        Error::ContextExhausted => (STATUS_CONTEXT_EXHAUSTED, 0),
+        Error::PeerFailed(rank) => (STATUS_PEER_FAILED, rank as u64),
+        Error::Revoked => (STATUS_REVOKED, 0),
+        Error::FailedRequest(status) => (STATUS_FAILED_REQUEST, status as u64),
+        Error::RequestTimeout => (STATUS_REQUEST_TIMEOUT, 0),
+        _ => (STATUS_OTHER, 0),
+    }
+}
+
+/// Turn a status and payload from the local leader back into the error.
+fn decode_error(status: u64, payload: u64) -> Error {
+    match status {
+        STATUS_INTER_COMMUNICATOR => Error::InterCommunicator,
+        STATUS_INVALID_RANK => Error::InvalidRank(payload as usize),
+        STATUS_OVERLAPPING_GROUPS => Error::OverlappingGroups,
+        STATUS_CONTEXT_EXHAUSTED => Error::ContextExhausted,
+        STATUS_PEER_FAILED => Error::PeerFailed(payload as usize),
+        STATUS_REVOKED => Error::Revoked,
+        STATUS_FAILED_REQUEST => Error::FailedRequest(payload as ucs_status_t),
+        STATUS_REQUEST_TIMEOUT => Error::RequestTimeout,
+        _ => Error::ConnectFailure,
+    }
+}
+
+/// Exchange data of unknown length between the two leaders.
+fn leader_exchange(
+    peer_comm: &Communicator,
+    other: usize,
+    tag: Tag,
+    send_first: bool,
+    data: &[u64],
+) -> Result<Vec<u64>> {
+    let len = [data.len() as u64];
+    if send_first {
+        peer_comm.send_internal(other, tag, &len)?;
+        peer_comm.send_internal(other, tag, data)?;
+    }
+    let other_len: Vec<u64> = peer_comm.recv_internal(other, tag, 1)?;
+    let other_data = peer_comm.recv_internal(other, tag, other_len[0] as usize)?;
+    if !send_first {
+        peer_comm.send_internal(other, tag, &len)?;
+        peer_comm.send_internal(other, tag, data)?;
+    }
+    Ok(other_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Group of the world ranks `ranks`, seen from world rank `world_rank`
+    fn group(ranks: &[usize], world_rank: usize) -> Group {
+        Group {
+            ranks: ranks.to_vec(),
+            world_rank,
+        }
+    }
+
+    #[test]
+    fn incl() {
+        let g = group(&[10, 11, 12, 13], 12);
+        let sub = g.incl(&[3, 2]).unwrap();
+        assert_eq!(sub, group(&[13, 12], 12));
+        assert_eq!(sub.rank(), Some(1));
+        assert_eq!(g.incl(&[]).unwrap().size(), 0);
+        assert!(matches!(g.incl(&[4]), Err(Error::InvalidRank(4))));
+        assert!(matches!(g.incl(&[1, 1]), Err(Error::InvalidRank(1))));
+    }
+
+    #[test]
+    fn excl() {
+        let g = group(&[10, 11, 12, 13], 12);
+        let sub = g.excl(&[0, 2]).unwrap();
+        assert_eq!(sub, group(&[11, 13], 12));
+        assert_eq!(sub.rank(), None);
+        assert_eq!(g.excl(&[]).unwrap(), g);
+        assert!(matches!(g.excl(&[4]), Err(Error::InvalidRank(4))));
+    }
+
+    #[test]
+    fn union() {
+        let a = group(&[3, 1, 2], 1);
+        let b = group(&[4, 2, 0], 1);
+        assert_eq!(a.union(&b), group(&[3, 1, 2, 4, 0], 1));
+        assert_eq!(b.union(&a), group(&[4, 2, 0, 3, 1], 1));
+        assert_eq!(a.union(&a), a);
+    }
+
+    #[test]
+    fn intersection() {
+        let a = group(&[3, 1, 2], 1);
+        let b = group(&[2, 4, 3], 1);
+        assert_eq!(a.intersection(&b), group(&[3, 2], 1));
+        assert_eq!(b.intersection(&a), group(&[2, 3], 1));
+        assert_eq!(a.intersection(&group(&[5], 1)).size(), 0);
+    }
+
+    #[test]
+    fn difference() {
+        let a = group(&[3, 1, 2], 1);
+        let b = group(&[2, 4, 3], 1);
+        assert_eq!(a.difference(&b), group(&[1], 1));
+        assert_eq!(b.difference(&a), group(&[4], 1));
+        assert_eq!(a.difference(&a).size(), 0);
+    }
+
+    #[test]
+    fn translate_ranks() {
+        let a = group(&[3, 1, 2], 1);
+        let b = group(&[2, 4, 3], 1);
+        assert_eq!(
+            a.translate_ranks(&[0, 1, 2], &b).unwrap(),
+            [Some(2), None, Some(0)]
+        );
+        assert!(matches!(
+            a.translate_ranks(&[3], &b),
+            Err(Error::InvalidRank(3))
+        ));
+    }
+}
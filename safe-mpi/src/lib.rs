@@ -1,49 +1,86 @@
 use log::{debug, error, info};
 use serde_json;
 use std::cell::RefCell;
-use std::ffi::CStr;
 use std::io::Write;
-use std::mem::MaybeUninit;
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::process::Child;
 use std::rc::Rc;
 use std::result::Result as StandardResult;
+use std::time::Duration;
 use ucx2_sys::{
-    rust_ucp_init,
+    ucp::{self, Endpoint, UcpContext, Worker},
     ucp_address_t,
-    ucp_cleanup,
-    ucp_context_h,
-    ucp_ep_close_nb,
     ucp_ep_h,
+    ucp_err_handler_t,
     ucp_params_t,
     ucp_tag_t,
-    ucp_worker_create,
-    ucp_worker_destroy,
-    ucp_worker_get_address,
-    ucp_worker_h,
     ucp_worker_params_t,
-    ucp_worker_release_address,
-    ucs_status_string,
     ucs_status_t,
-    // UCP_EP_CLOSE_MODE_FLUSH,
-    UCP_EP_CLOSE_MODE_FORCE,
-    UCP_FEATURE_STREAM,
-    UCP_FEATURE_TAG,
+    ucs_thread_mode_t,
+    UCP_PARAM_FIELD_ESTIMATED_NUM_EPS,
     UCP_PARAM_FIELD_FEATURES,
+    UCP_PARAM_FIELD_REQUEST_SIZE,
     UCP_WORKER_PARAM_FIELD_THREAD_MODE,
-    UCS_OK,
-    UCS_THREAD_MODE_SINGLE,
 };
 
 pub type Tag = ucp_tag_t;
 
+/// Number of times to retry connecting to the server during initialization
+const CONNECT_RETRIES: usize = 100;
+/// Delay between connection attempts
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 pub mod communicator;
 mod context;
-use context::Context;
+use context::{create_endpoint, Context};
 mod util;
-use util::wait_loop;
 mod callbacks;
 mod request;
-pub use request::{Request, RequestStatus};
+pub use request::{AtomicRequest, Request, RequestStatus};
+mod tag;
+mod collective;
+pub use collective::{Reduce, ReduceMode, ReduceOp};
+mod group;
+pub use group::Group;
+mod topology;
+pub use topology::Neighbors;
+mod dist_array;
+pub use dist_array::DistArray;
+mod window;
+pub use window::{LockType, Window};
+mod atomic;
+pub use atomic::Atomic;
+mod message;
+pub use message::{Chunked, Flat, Message};
+mod am;
+use am::AmState;
+mod rpc;
+pub use rpc::{Method, PendingCall, Rpc};
+mod channel;
+pub use channel::{Iter, Receiver, Sender, TryIter};
+mod router;
+pub use router::{AnyMessage, TypeRouter};
+mod farm;
+pub use farm::TaskFarm;
+mod dist_iter;
+pub use dist_iter::DistIter;
+mod file;
+pub use file::File;
+mod port;
+pub use port::{lookup_name, publish_name, unpublish_name, Port};
+mod spawn;
+mod listener;
+use listener::Listener;
+pub use listener::init_listener;
+mod failure;
+use failure::{PeerState, Watch};
+mod ulfm;
+mod finalize;
+mod abort;
+mod options;
+pub use options::{Features, InitOptions, Logging, ThreadLevel};
+mod transport;
+pub use transport::{Lane, Transport, TransportInfo};
 
 #[derive(Debug, Copy, Clone)]
 pub enum Error {
@@ -61,6 +98,66 @@ pub enum Error {
     MessageTypeMismatch,
     /// Invalid count of elements received in a message (no partial receives allowed)
     MessageCountMismatch,
+    /// Tag doesn't fit in the bits available for user tags
+    InvalidTag(Tag),
+    /// Rank is not a valid peer for this operation
+    InvalidRank(usize),
+    /// More processes than the source rank bits of a tag can tell apart
+    TooManyProcesses(usize),
+    /// Buffers passed to an operation have mismatched lengths
+    LengthMismatch,
+    /// No context IDs left to create a new communicator with
+    ContextExhausted,
+    /// Failed to get the host name
+    HostnameFailure,
+    /// Operation isn't supported on an inter-communicator
+    InterCommunicator,
+    /// Groups passed to an operation have to be disjoint
+    OverlappingGroups,
+    /// Topology arguments don't describe a valid topology for the communicator
+    InvalidTopology,
+    /// Communicator doesn't have the kind of topology the operation needs
+    MissingTopology,
+    /// Halo is wider than a process's block of a distributed array
+    InvalidHalo,
+    /// Failed to register memory for a window
+    MemMapFailure(ucs_status_t),
+    /// Failed to pack or unpack a remote key
+    RemoteKeyFailure(ucs_status_t),
+    /// Access falls outside of the target's window
+    OutOfBounds,
+    /// Element type is aligned too strictly for window memory
+    UnsupportedType,
+    /// Operation needs a UCP feature that wasn't requested in `InitOptions`
+    MissingFeature(Features),
+    /// Window lock or unlock doesn't match the current lock state
+    LockState,
+    /// Failed to set the active message handler of the worker
+    AmHandlerFailure(ucs_status_t),
+    /// Remote procedure failed with the given error code
+    RpcFailure(i32),
+    /// No remote procedure is registered under the method ID
+    UnknownMethod(u32),
+    /// Remote procedure's response couldn't be encoded by the serving process
+    BadResponse,
+    /// File operation failed on this or another process
+    IoFailure(std::io::ErrorKind),
+    /// Failed to connect to or accept a connection from another job
+    ConnectFailure,
+    /// Failed to create a listener for incoming connections
+    ListenerFailure(ucs_status_t),
+    /// Failed to create an endpoint
+    EndpointFailure(ucs_status_t),
+    /// Process with the given world rank has failed
+    PeerFailed(usize),
+    /// Communicator has been revoked
+    Revoked,
+    /// Job was aborted by the process with the given rank, with an error code
+    Aborted(usize, i32),
+    /// Failed to read or modify the UCX configuration
+    ConfigFailure(ucs_status_t),
+    /// Failed to query the attributes of a worker or endpoint
+    QueryFailure(ucs_status_t),
 }
 
 /// Immutable iovec
@@ -71,151 +168,333 @@ pub struct MutIov(pub *mut u8, pub usize);
 
 /// Handle containing the internal UCP context data and other code.
 pub(crate) struct Handle {
-    pub context: ucp_context_h,
-    pub worker: ucp_worker_h,
-    pub other_addr: Vec<u8>,
-    pub endpoint: Option<ucp_ep_h>,
+    pub context: Rc<UcpContext>,
+    pub worker: Rc<Worker>,
+    /// Worker addresses of all processes, indexed by rank, followed by those
+    /// of processes in other jobs that have been connected to
+    pub addrs: Vec<Vec<u8>>,
+    /// Endpoints for each process, created from its address on first use.
+    /// Remote keys share them, so that they stay open while a key is alive.
+    pub endpoints: Vec<Option<Rc<Endpoint>>>,
+    /// Rank of this process (the server is always rank 0)
+    pub rank: usize,
+    /// Number of processes
+    pub size: usize,
+    /// Bitmap of context IDs that aren't in use by any communicator
+    pub free_contexts: Vec<u64>,
+    /// Active message handlers and queue, used by the worker's callback
+    pub am: Rc<AmState>,
+    /// Failed processes, updated by the endpoints' error handler
+    pub peers: Rc<PeerState>,
+    /// Processes started with `Context::spawn()`
+    pub children: Vec<Child>,
+    /// Listener other jobs can connect to, on rank 0 after `init_listener()`
+    pub listener: Option<Listener>,
+    /// UCP features the context was created with
+    pub features: Features,
+}
+
+impl Handle {
+    /// Return the endpoint used to reach `rank`, creating it if this is the
+    /// first communication with the process.
+    pub(crate) fn endpoint(&mut self, rank: usize) -> Result<ucp_ep_h> {
+        self.connect(rank).map(|ep| ep.as_raw())
+    }
+
+    /// Like `endpoint()`, but return the owned endpoint.
+    pub(crate) fn connect(&mut self, rank: usize) -> Result<&Rc<Endpoint>> {
+        self.peers.check_aborted()?;
+        if self.peers.is_failed(rank) {
+            return Err(Error::PeerFailed(rank));
+        }
+        let slot = self.endpoints.get(rank).ok_or(Error::InvalidRank(rank))?;
+        if slot.is_none() {
+            let endpoint = create_endpoint(self, rank)?;
+            self.peers.add(endpoint.as_raw(), rank);
+            self.endpoints[rank] = Some(Rc::new(endpoint));
+        }
+        self.endpoints[rank].as_ref().ok_or(Error::InternalError)
+    }
+
+    /// Turn the error of an operation involving process `rank` into
+    /// `PeerFailed` if the process has failed.
+    pub(crate) fn peer_error(&self, rank: usize, err: Error) -> Error {
+        if self.peers.is_failed(rank) {
+            Error::PeerFailed(rank)
+        } else {
+            err
+        }
+    }
+
+    /// Add processes of another job and return the indices they were added
+    /// at. Their endpoints are created on first use as well.
+    pub(crate) fn add_peers(&mut self, addrs: Vec<Vec<u8>>) -> Vec<usize> {
+        let start = self.addrs.len();
+        for addr in addrs {
+            self.addrs.push(addr);
+            self.endpoints.push(None);
+        }
+        (start..self.addrs.len()).collect()
+    }
+
+    /// Return `MissingFeature` unless the context was created with all of
+    /// `features`.
+    pub(crate) fn check_features(&self, features: Features) -> Result<()> {
+        if self.features.contains(features) {
+            Ok(())
+        } else {
+            Err(Error::MissingFeature(features))
+        }
+    }
+
+    /// Mark a context ID as used.
+    pub(crate) fn claim_context(&mut self, id: Tag) {
+        self.free_contexts[(id / 64) as usize] &= !(1 << (id % 64));
+    }
+
+    /// Mark a context ID as free again.
+    pub(crate) fn release_context(&mut self, id: Tag) {
+        self.free_contexts[(id / 64) as usize] |= 1 << (id % 64);
+    }
+}
+
+/// Create the bitmap of free context IDs, with the world context taken.
+fn initial_free_contexts() -> Vec<u64> {
+    let mut free = vec![u64::MAX; (1 << tag::CONTEXT_BITS) / 64];
+    free[0] &= !(1 << tag::WORLD_CONTEXT);
+    free
 }
 
 impl Drop for Handle {
     fn drop(&mut self) {
-        unsafe {
-            if let Some(endpoint) = self.endpoint {
-                // For some reason UCP_EP_CLOSE_MODE_FLUSH is causing an
-                // infinite loop with two nodes
-                // let req = ucp_ep_close_nb(endpoint, UCP_EP_CLOSE_MODE_FLUSH);
-                let req = ucp_ep_close_nb(endpoint, UCP_EP_CLOSE_MODE_FORCE);
-                wait_loop(self.worker, req, || false).unwrap();
+        // Flushing can wait forever for peers that have already exited, so
+        // whatever `Context::finalize()` hasn't closed is closed by force as
+        // the endpoints are dropped. The worker and the context go with the
+        // last reference to them.
+        self.listener = None;
+        self.endpoints.clear();
+        for child in self.children.iter_mut() {
+            if let Err(err) = child.wait() {
+                error!("Failed to wait for spawned process: {}", err);
             }
-            ucp_worker_destroy(self.worker);
-            ucp_cleanup(self.context);
         }
     }
 }
 
 pub type Result<T> = StandardResult<T, Error>;
 
-/// Initialize the safe mpi context.
-#[allow(clippy::uninit_assumed_init)]
+/// Initialize the safe mpi context for two processes. The server is rank 0
+/// and the client is rank 1.
 pub fn init(sockaddr: SocketAddr, server: bool) -> Result<Context> {
-    // Initialize logging
-    env_logger::init();
-    unsafe {
-        let mut context = MaybeUninit::<ucp_context_h>::uninit();
-        let params = ucp_params_t {
-            field_mask: UCP_PARAM_FIELD_FEATURES.into(),
-            features: (UCP_FEATURE_TAG | UCP_FEATURE_STREAM).into(),
-            ..Default::default()
-        };
-        let status = rust_ucp_init(&params, std::ptr::null(), context.as_mut_ptr());
-        if status != UCS_OK {
+    InitOptions::default().init(sockaddr, server)
+}
+
+/// Initialize the safe mpi context from the `SAFE_MPI_ADDR`, `SAFE_MPI_RANK`
+/// and `SAFE_MPI_SIZE` environment variables, as set for processes started
+/// with `Context::spawn()`.
+pub fn init_env() -> Result<Context> {
+    InitOptions::default().init_env()
+}
+
+/// Initialize the safe mpi context as process `rank` out of `size`. Rank 0
+/// listens on `sockaddr` for the address exchange and all other processes
+/// connect to it.
+pub fn init_world(sockaddr: SocketAddr, rank: usize, size: usize) -> Result<Context> {
+    InitOptions::default().init_world(sockaddr, rank, size)
+}
+
+/// Set up the context with the address exchange over a side TCP connection.
+unsafe fn setup_world(
+    options: &InitOptions,
+    sockaddr: SocketAddr,
+    rank: usize,
+    size: usize,
+) -> Result<Context> {
+    setup(options, rank, size, |worker, _| {
+        let addrs = exchange_addrs(worker, rank, size, sockaddr)?;
+        Ok((addrs, (0..size).map(|_| None).collect()))
+    })
+}
+
+/// Worker addresses of all processes, with the endpoints to them that were
+/// created while exchanging the addresses
+pub(crate) type Connection = (Vec<Vec<u8>>, Vec<Option<Endpoint>>);
+
+/// Create the UCP context and worker and build the handle. `connect` gets
+/// the addresses of all processes, along with any endpoints it created on
+/// the way, which have to use the error handler it's passed.
+unsafe fn setup<F>(options: &InitOptions, rank: usize, size: usize, connect: F) -> Result<Context>
+where
+    F: FnOnce(&Rc<Worker>, ucp_err_handler_t) -> Result<Connection>,
+{
+    if size > 1 << tag::SOURCE_BITS {
+        return Err(Error::TooManyProcesses(size));
+    }
+    if rank >= size {
+        return Err(Error::InvalidRank(rank));
+    }
+    options.init_logging();
+    let mut params = ucp_params_t {
+        field_mask: UCP_PARAM_FIELD_FEATURES.into(),
+        features: options.enabled_features().bits(),
+        ..Default::default()
+    };
+    if let Some(count) = options.estimated_endpoints {
+        params.field_mask |= u64::from(UCP_PARAM_FIELD_ESTIMATED_NUM_EPS);
+        params.estimated_num_eps = count;
+    }
+    if let Some(size) = options.request_size {
+        params.field_mask |= u64::from(UCP_PARAM_FIELD_REQUEST_SIZE);
+        params.request_size = size;
+    }
+    let config = options.read_config()?;
+    let context = match UcpContext::new(&params, Some(&config)) {
+        Ok(context) => Rc::new(context),
+        Err(status) => {
             error!("Failed to create context: {}", status_to_string(status));
-            Err(Error::InitFailure)
-        } else {
-            let context = context.assume_init();
-            let worker = create_worker(context)?;
-            let other_addr = exchange_addrs(context, worker, server, sockaddr)?;
-            Ok(Context::new(Rc::new(RefCell::new(Handle {
-                context,
-                worker,
-                other_addr,
-                endpoint: None,
-            }))))
+            return Err(Error::InitFailure);
+        }
+    };
+    let worker = Rc::new(create_worker(&context, options.thread_level.thread_mode())?);
+    let am = Rc::new(AmState::default());
+    am::register(worker.as_raw(), &am)?;
+    let peers = Rc::new(PeerState::default());
+    ulfm::register(worker.as_raw(), &peers)?;
+    abort::register(worker.as_raw(), &peers)?;
+    let (addrs, endpoints) = connect(&worker, peers.err_handler())?;
+    for (rank, endpoint) in endpoints.iter().enumerate() {
+        if let Some(endpoint) = endpoint {
+            peers.add(endpoint.as_raw(), rank);
         }
     }
+    Ok(Context::new(Rc::new(RefCell::new(Handle {
+        context,
+        worker,
+        addrs,
+        endpoints: endpoints.into_iter().map(|ep| ep.map(Rc::new)).collect(),
+        rank,
+        size,
+        free_contexts: initial_free_contexts(),
+        am,
+        peers,
+        children: vec![],
+        listener: None,
+        features: options.enabled_features(),
+    }))))
 }
 
 /// Create the worker.
-#[allow(clippy::uninit_assumed_init)]
-unsafe fn create_worker(context: ucp_context_h) -> Result<ucp_worker_h> {
-    // First create the worker
-    let mut worker = MaybeUninit::<ucp_worker_h>::uninit();
+unsafe fn create_worker(
+    context: &Rc<UcpContext>,
+    thread_mode: ucs_thread_mode_t,
+) -> Result<Worker> {
     let params = ucp_worker_params_t {
         field_mask: UCP_WORKER_PARAM_FIELD_THREAD_MODE.into(),
-        // One thread for now
-        thread_mode: UCS_THREAD_MODE_SINGLE,
+        thread_mode,
         ..Default::default()
     };
-    let status = ucp_worker_create(context, &params, worker.as_mut_ptr());
-    if status != UCS_OK {
-        Err(Error::WorkerCreateFailed(status))
-    } else {
-        Ok(worker.assume_init())
-    }
+    Worker::new(context, &params).map_err(Error::WorkerCreateFailed)
 }
 
-/// Exchange addresses between the two processes.
+/// Exchange addresses between all processes.
 unsafe fn exchange_addrs(
-    _context: ucp_context_h,
-    worker: ucp_worker_h,
-    server: bool,
+    worker: &Worker,
+    rank: usize,
+    size: usize,
     sockaddr: SocketAddr,
-) -> Result<Vec<u8>> {
-    // Get the address of the worker
-    let mut address = MaybeUninit::<*mut ucp_address_t>::uninit();
-    let mut addrlen = MaybeUninit::<usize>::uninit();
-    let status = ucp_worker_get_address(worker, address.as_mut_ptr(), addrlen.as_mut_ptr());
-    if status != UCS_OK {
-        return Err(Error::WorkerAddressFailure(status));
-    }
-    let address = address.assume_init();
-    let addrlen = addrlen.assume_init();
-    // Address of the other process
+) -> Result<Vec<Vec<u8>>> {
+    let address = worker_address(worker)?;
+    // Addresses of all processes
     info!("Starting address exchange");
-    let other_addr = get_other_addr(server, sockaddr, address, addrlen)?;
+    let addrs = get_all_addrs(
+        rank,
+        size,
+        sockaddr,
+        address.as_ptr() as *const ucp_address_t,
+        address.len(),
+    )?;
     info!("Address exchange complete");
-    ucp_worker_release_address(worker, address);
-    Ok(other_addr)
+    Ok(addrs)
+}
+
+/// Return the address of the worker.
+pub(crate) fn worker_address(worker: &Worker) -> Result<Vec<u8>> {
+    worker.address().map_err(Error::WorkerAddressFailure)
 }
 
-/// Do the actual exchange and return the address of the other process.
-unsafe fn get_other_addr(
-    server: bool,
+/// Do the actual exchange and return the addresses of all processes, indexed
+/// by rank.
+unsafe fn get_all_addrs(
+    rank: usize,
+    size: usize,
     sockaddr: SocketAddr,
     address: *const ucp_address_t,
     addrlen: usize,
-) -> Result<Vec<u8>> {
+) -> Result<Vec<Vec<u8>>> {
     // TODO: Use bincode here
     let saddr = std::slice::from_raw_parts(address as *const u8, addrlen);
-    // TODO: There has to be a better way to do this, instead of using two
-    //       connections in a row.
-    if server {
+    if rank == 0 {
         let listener = TcpListener::bind(sockaddr).expect("Failed to bind TCP listener");
-        // First connection
-        let (mut stream, _) = listener
-            .accept()
-            .expect("Failed to accept a client connection");
-        // Receive the other address and then send ours
-        let addr_bytes: Vec<u8> =
-            serde_json::from_reader(&mut stream).expect("Failed to parse incoming address data");
-        debug!("addr_bytes: {:?}", addr_bytes);
-        // Second connection
-        // Now to send the server's address
-        serde_json::to_writer(&mut stream, saddr).expect("Failed to send address data");
-        stream.flush().expect("Failed to flush stream");
-        Ok(addr_bytes)
+        let mut addrs = vec![vec![]; size];
+        addrs[0] = saddr.to_vec();
+        // Receive the address of every other process first
+        let mut streams = vec![];
+        for _ in 1..size {
+            let (mut stream, _) = listener
+                .accept()
+                .expect("Failed to accept a client connection");
+            let (other_rank, addr_bytes): (usize, Vec<u8>) = serde_json::from_reader(&mut stream)
+                .expect("Failed to parse incoming address data");
+            debug!("addr_bytes for rank {}: {:?}", other_rank, addr_bytes);
+            if other_rank == 0 || other_rank >= size || !addrs[other_rank].is_empty() {
+                return Err(Error::InvalidRank(other_rank));
+            }
+            addrs[other_rank] = addr_bytes;
+            streams.push(stream);
+        }
+        // Now send the full table back out
+        for mut stream in streams {
+            serde_json::to_writer(&mut stream, &addrs).expect("Failed to send address data");
+            stream.flush().expect("Failed to flush stream");
+        }
+        Ok(addrs)
     } else {
-        // First connection
-        let mut stream = TcpStream::connect(sockaddr)
-            .expect("Failed to connect to server for ucp address exchange");
-        // Send our address and then receive the other one
-        serde_json::to_writer(&mut stream, saddr).expect("Failed to send address data");
+        let mut stream = connect_retry(sockaddr);
+        // Send our address and then receive all of them
+        serde_json::to_writer(&mut stream, &(rank, saddr)).expect("Failed to send address data");
         stream.flush().expect("Failed to flush stream");
         stream
             .shutdown(Shutdown::Write)
             .expect("Failed to shutdown stream");
         info!("Wrote address data");
-        let addr_bytes =
+        let addrs: Vec<Vec<u8>> =
             serde_json::from_reader(&mut stream).expect("Failed to parse incoming address data");
-        Ok(addr_bytes)
+        if addrs.len() != size {
+            return Err(Error::InternalError);
+        }
+        Ok(addrs)
     }
 }
 
-pub(crate) fn status_to_string(status: ucs_status_t) -> String {
-    unsafe {
-        CStr::from_ptr(ucs_status_string(status))
-            .to_str()
-            .expect("Failed to convert status string")
-            .to_string()
+/// Connect to the server, retrying for a while in case it isn't listening yet.
+fn connect_retry(sockaddr: SocketAddr) -> TcpStream {
+    let mut tries = 0;
+    loop {
+        match TcpStream::connect(sockaddr) {
+            Ok(stream) => return stream,
+            Err(err) if tries < CONNECT_RETRIES => {
+                debug!("Failed to connect to server, retrying: {}", err);
+                std::thread::sleep(CONNECT_RETRY_DELAY);
+                tries += 1;
+            }
+            Err(err) => panic!(
+                "Failed to connect to server for ucp address exchange: {}",
+                err
+            ),
+        }
     }
 }
+
+pub(crate) fn status_to_string(status: ucs_status_t) -> String {
+    ucp::status_string(status)
+}
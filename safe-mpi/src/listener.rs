@@ -0,0 +1,327 @@
+//! Startup through a UCP listener instead of a side TCP connection.
+//!
+//! Rank 0 creates a listener on the given socket address and every other
+//! process connects to it directly with a client-server endpoint. The worker
+//! addresses are then exchanged with stream messages over those endpoints,
+//! and the endpoints are kept as the ones between rank 0 and the others.
+//! Clients can connect in any order, and a client that sends a bad or
+//! duplicate rank is dropped without affecting the others.
+//!
+//! The listener stays open for the lifetime of the context, so that other
+//! jobs can connect to rank 0 later on with
+//! `Communicator::connect_listener()`, see `port.rs`. Connection requests
+//! are queued until they're accepted and any left over when the context is
+//! dropped are rejected.
+use crate::{
+    callbacks::{listener_conn_callback, stream_recv_callback},
+    request::wait_nbx,
+    setup, status_to_string,
+    util::to_u64s,
+    worker_address, Connection, Context, Error, InitOptions, Result, CONNECT_RETRIES,
+    CONNECT_RETRY_DELAY,
+};
+use flat::FlatBuffer;
+use log::{debug, info, warn};
+use nix::sys::socket::{SockaddrLike, SockaddrStorage};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::net::SocketAddr;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::time::Duration;
+use ucx2_sys::{
+    ucp::{self, Endpoint, Worker},
+    ucp_conn_request_h, ucp_ep_params_t, ucp_err_handler_t, ucp_listener_conn_handler_t,
+    ucp_listener_params_t, ucp_request_param_t, ucp_request_param_t__bindgen_ty_1,
+    ucp_stream_recv_nbx, ucp_stream_send_nbx, ucs_sock_addr_t, ucs_status_t,
+    UCP_EP_PARAMS_FLAGS_CLIENT_SERVER, UCP_EP_PARAM_FIELD_CONN_REQUEST,
+    UCP_EP_PARAM_FIELD_ERR_HANDLER, UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE, UCP_EP_PARAM_FIELD_FLAGS,
+    UCP_EP_PARAM_FIELD_SOCK_ADDR, UCP_ERR_HANDLING_MODE_PEER,
+    UCP_LISTENER_PARAM_FIELD_CONN_HANDLER, UCP_LISTENER_PARAM_FIELD_SOCK_ADDR,
+    UCP_OP_ATTR_FIELD_FLAGS, UCP_STREAM_RECV_FLAG_WAITALL, UCS_ERR_TIMED_OUT,
+};
+
+/// How long `close()` waits for an endpoint to flush before giving up
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Initialize the safe mpi context as process `rank` out of `size`, with
+/// rank 0 listening on `sockaddr` with a UCP listener and all other
+/// processes connecting to it through UCP.
+pub fn init_listener(sockaddr: SocketAddr, rank: usize, size: usize) -> Result<Context> {
+    InitOptions::default().init_listener(sockaddr, rank, size)
+}
+
+/// Set up the context with the address exchange through a UCP listener.
+pub(crate) unsafe fn setup_listener(
+    options: &InitOptions,
+    sockaddr: SocketAddr,
+    rank: usize,
+    size: usize,
+) -> Result<Context> {
+    let mut kept = None;
+    let context = setup(options, rank, size, |worker, err_handler| {
+        let address = worker_address(worker)?;
+        info!("Starting address exchange through the listener");
+        if rank == 0 {
+            let listener = Listener::new(worker, err_handler, sockaddr)?;
+            let connection = serve(&listener, size, address)?;
+            kept = Some(listener);
+            Ok(connection)
+        } else {
+            join(worker, err_handler, rank, size, sockaddr, address)
+        }
+    })?;
+    context.handle.borrow_mut().listener = kept;
+    Ok(context)
+}
+
+/// UCP listener with the queue of connection requests it has received.
+pub(crate) struct Listener {
+    // The listener goes before the queue its callback fills
+    listener: ucp::Listener,
+    /// Filled by the listener callback, boxed so that it doesn't move
+    requests: Box<RefCell<VecDeque<ucp_conn_request_h>>>,
+    /// Error handler for the accepted endpoints
+    err_handler: ucp_err_handler_t,
+}
+
+impl Listener {
+    /// Start listening on `sockaddr`.
+    unsafe fn new(
+        worker: &Rc<Worker>,
+        err_handler: ucp_err_handler_t,
+        sockaddr: SocketAddr,
+    ) -> Result<Listener> {
+        let requests = Box::new(RefCell::new(VecDeque::new()));
+        let addr = SockaddrStorage::from(sockaddr);
+        let params = ucp_listener_params_t {
+            field_mask: (UCP_LISTENER_PARAM_FIELD_SOCK_ADDR
+                | UCP_LISTENER_PARAM_FIELD_CONN_HANDLER)
+                .into(),
+            sockaddr: ucs_sock_addr_t {
+                addr: addr.as_ptr() as *const _,
+                addrlen: addr.len(),
+            },
+            conn_handler: ucp_listener_conn_handler_t {
+                cb: Some(listener_conn_callback),
+                arg: &*requests as *const _ as *mut c_void,
+            },
+            ..Default::default()
+        };
+        Ok(Listener {
+            listener: ucp::Listener::new(worker, &params).map_err(Error::ListenerFailure)?,
+            requests,
+            err_handler,
+        })
+    }
+
+    /// Wait for the next connection request, in arrival order, and create
+    /// the endpoint for it.
+    pub(crate) unsafe fn accept(&self) -> Result<Endpoint> {
+        loop {
+            let worker = self.listener.worker();
+            if let Some(request) = self.requests.borrow_mut().pop_front() {
+                return accept(worker, self.err_handler, request);
+            }
+            worker.progress();
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        for request in self.requests.borrow_mut().drain(..) {
+            if let Err(status) = unsafe { self.listener.reject(request) } {
+                warn!(
+                    "Failed to reject a connection request: {}",
+                    status_to_string(status)
+                );
+            }
+        }
+    }
+}
+
+/// Accept a connection from every other process and send them the table of
+/// all addresses.
+unsafe fn serve(listener: &Listener, size: usize, address: Vec<u8>) -> Result<Connection> {
+    let mut addrs = vec![vec![]; size];
+    addrs[0] = address;
+    let mut endpoints: Vec<Option<Endpoint>> = (0..size).map(|_| None).collect();
+    let mut accepted = 1;
+    while accepted < size {
+        let ep = match listener.accept() {
+            Ok(ep) => ep,
+            Err(err) => {
+                warn!("Failed to accept a connection: {:?}", err);
+                continue;
+            }
+        };
+        // A bad client is dropped, closing its endpoint, and the others
+        // carry on
+        match recv_client(&ep, size, &endpoints) {
+            Ok((rank, addr)) => {
+                debug!("Accepted connection from rank {}", rank);
+                addrs[rank] = addr;
+                endpoints[rank] = Some(ep);
+                accepted += 1;
+            }
+            Err(err) => warn!("Dropping a connection: {:?}", err),
+        }
+    }
+
+    // Send the table out as the address lengths followed by their bytes
+    let lens: Vec<u64> = addrs.iter().map(|addr| addr.len() as u64).collect();
+    let bytes = addrs.concat();
+    for ep in endpoints.iter().flatten() {
+        stream_send(ep, &lens)?;
+        stream_send(ep, &bytes)?;
+    }
+    info!("Address exchange complete");
+    Ok((addrs, endpoints))
+}
+
+/// Receive the rank and the address of a client.
+unsafe fn recv_client(
+    ep: &Endpoint,
+    size: usize,
+    endpoints: &[Option<Endpoint>],
+) -> Result<(usize, Vec<u8>)> {
+    // [rank, address length], then the address
+    let header = to_u64s(&stream_recv(ep, 2 * size_of::<u64>())?);
+    let rank = header[0] as usize;
+    if rank == 0 || rank >= size || endpoints[rank].is_some() {
+        return Err(Error::InvalidRank(rank));
+    }
+    Ok((rank, stream_recv(ep, header[1] as usize)?))
+}
+
+/// Connect to rank 0, retrying for a while in case it isn't listening yet,
+/// and get the table of all addresses.
+unsafe fn join(
+    worker: &Rc<Worker>,
+    err_handler: ucp_err_handler_t,
+    rank: usize,
+    size: usize,
+    sockaddr: SocketAddr,
+    address: Vec<u8>,
+) -> Result<Connection> {
+    let header = [rank as u64, address.len() as u64];
+    let mut tries = 0;
+    let ep = loop {
+        let ep = connect(worker, err_handler, sockaddr)?;
+        match stream_send(&ep, &header).and_then(|_| stream_send(&ep, &address)) {
+            Ok(()) => break ep,
+            Err(err) if tries < CONNECT_RETRIES => {
+                debug!("Failed to connect to the listener, retrying: {:?}", err);
+                drop(ep);
+                std::thread::sleep(CONNECT_RETRY_DELAY);
+                tries += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    let lens = to_u64s(&stream_recv(&ep, size * size_of::<u64>())?);
+    let bytes = stream_recv(&ep, lens.iter().sum::<u64>() as usize)?;
+    let mut addrs = vec![];
+    let mut rest = &bytes[..];
+    for len in lens {
+        let (addr, tail) = rest.split_at(len as usize);
+        addrs.push(addr.to_vec());
+        rest = tail;
+    }
+    let mut endpoints: Vec<Option<Endpoint>> = (0..size).map(|_| None).collect();
+    endpoints[0] = Some(ep);
+    info!("Address exchange complete");
+    Ok((addrs, endpoints))
+}
+
+/// Create the server side endpoint for a connection request.
+unsafe fn accept(
+    worker: &Rc<Worker>,
+    err_handler: ucp_err_handler_t,
+    request: ucp_conn_request_h,
+) -> Result<Endpoint> {
+    let params = ucp_ep_params_t {
+        field_mask: (UCP_EP_PARAM_FIELD_CONN_REQUEST
+            | UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE
+            | UCP_EP_PARAM_FIELD_ERR_HANDLER)
+            .into(),
+        err_mode: UCP_ERR_HANDLING_MODE_PEER,
+        err_handler,
+        conn_request: request,
+        ..Default::default()
+    };
+    Endpoint::new(worker, &params).map_err(Error::EndpointFailure)
+}
+
+/// Create a client endpoint connecting to `sockaddr`.
+pub(crate) unsafe fn connect(
+    worker: &Rc<Worker>,
+    err_handler: ucp_err_handler_t,
+    sockaddr: SocketAddr,
+) -> Result<Endpoint> {
+    let addr = SockaddrStorage::from(sockaddr);
+    let params = ucp_ep_params_t {
+        field_mask: (UCP_EP_PARAM_FIELD_SOCK_ADDR
+            | UCP_EP_PARAM_FIELD_FLAGS
+            | UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE
+            | UCP_EP_PARAM_FIELD_ERR_HANDLER)
+            .into(),
+        err_mode: UCP_ERR_HANDLING_MODE_PEER,
+        err_handler,
+        flags: UCP_EP_PARAMS_FLAGS_CLIENT_SERVER,
+        sockaddr: ucs_sock_addr_t {
+            addr: addr.as_ptr() as *const _,
+            addrlen: addr.len(),
+        },
+        ..Default::default()
+    };
+    Endpoint::new(worker, &params).map_err(Error::EndpointFailure)
+}
+
+/// Close `ep` once everything sent on it has been delivered, giving up with
+/// `RequestTimeout` after `CLOSE_TIMEOUT`.
+pub(crate) fn close(ep: Endpoint) -> Result<()> {
+    ep.close(0, CLOSE_TIMEOUT).map_err(close_error)
+}
+
+/// Turn the status of a failed endpoint close into an error.
+pub(crate) fn close_error(status: ucs_status_t) -> Error {
+    match status {
+        UCS_ERR_TIMED_OUT => Error::RequestTimeout,
+        status => Error::FailedRequest(status),
+    }
+}
+
+/// Blocking stream send of flat data.
+pub(crate) unsafe fn stream_send<T: FlatBuffer>(ep: &Endpoint, data: &[T]) -> Result<()> {
+    wait_nbx(ep.worker(), |param| {
+        ucp_stream_send_nbx(ep.as_raw(), data.ptr() as *const _, data.size(), param)
+    })
+}
+
+/// Blocking stream receive of exactly `len` bytes.
+pub(crate) unsafe fn stream_recv(ep: &Endpoint, len: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    let mut length = 0;
+    wait_nbx(ep.worker(), |param| {
+        let param = ucp_request_param_t {
+            op_attr_mask: param.op_attr_mask | UCP_OP_ATTR_FIELD_FLAGS,
+            flags: UCP_STREAM_RECV_FLAG_WAITALL,
+            cb: ucp_request_param_t__bindgen_ty_1 {
+                recv_stream: Some(stream_recv_callback),
+            },
+            ..*param
+        };
+        ucp_stream_recv_nbx(
+            ep.as_raw(),
+            data.as_mut_ptr() as *mut _,
+            len,
+            &mut length,
+            &param,
+        )
+    })?;
+    Ok(data)
+}
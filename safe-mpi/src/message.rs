@@ -0,0 +1,183 @@
+//! Encoding of typed messages.
+//!
+//! Anything implementing serde's traits is encoded with bincode. Flat data can
+//! be sent as raw bytes without any serialization step by wrapping it in
+//! `Flat`, and `ChunkSerDe` data by wrapping it in `Chunked`. Typed messages
+//! start with a type ID header; for these two wrappers the header and the
+//! rest of the layout are the same as the benchmarks' `FlatController` and
+//! `IovecController` use, so messages from either can be received as typed
+//! messages.
+use crate::{Error, Result};
+use flat::FlatBuffer;
+use iovec::{hash_type_id, Chunk, ChunkSerDe};
+use serde::{de::DeserializeOwned, Serialize};
+use std::mem::size_of;
+
+/// Type that can be sent in a typed message.
+pub trait Message: Sized {
+    /// Encode the value into bytes
+    fn encode(&self) -> Result<Vec<u8>>;
+    /// Decode a value from bytes
+    fn decode(data: &[u8]) -> Result<Self>;
+
+    /// Type ID header sent in front of the encoded value, read back as a
+    /// native-endian `u64`
+    fn type_id() -> u64
+    where
+        Self: 'static,
+    {
+        hash_type_id::<Self>()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Message for T {
+    fn encode(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|_| Error::SerializeError)
+    }
+
+    fn decode(data: &[u8]) -> Result<T> {
+        bincode::deserialize(data).map_err(|_| Error::DeserializeError)
+    }
+}
+
+/// Elements sent as their raw bytes, after the element count.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Flat<T>(pub Vec<T>);
+
+impl<T: FlatBuffer + Copy + Default> Message for Flat<T> {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let data = &self.0[..];
+        let mut out = data.count().to_ne_bytes().to_vec();
+        out.extend_from_slice(unsafe { std::slice::from_raw_parts(data.ptr(), data.size()) });
+        Ok(out)
+    }
+
+    fn decode(data: &[u8]) -> Result<Flat<T>> {
+        if data.len() < size_of::<usize>() {
+            return Err(Error::MessageCountMismatch);
+        }
+        let (count, data) = data.split_at(size_of::<usize>());
+        let count = usize::from_ne_bytes(count.try_into().unwrap());
+        if count.checked_mul(size_of::<T>()) != Some(data.len()) {
+            return Err(Error::MessageCountMismatch);
+        }
+        let mut out = vec![T::default(); count];
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), out[..].ptr_mut(), data.len());
+        }
+        Ok(Flat(out))
+    }
+
+    /// The element type's `FlatBuffer::type_id()`
+    fn type_id() -> u64 {
+        <T as FlatBuffer>::type_id()
+    }
+}
+
+/// Elements encoded with `ChunkSerDe`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Chunked<T>(pub Vec<T>);
+
+impl<T: ChunkSerDe + 'static> Message for Chunked<T> {
+    /// Encode everything but the big-endian type ID that `ChunkSerDe` starts
+    /// with, which is the same as the message header.
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut chunks = vec![];
+        T::serialize(&self.0, &mut chunks).map_err(|_| Error::SerializeError)?;
+        let data: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| match chunk {
+                Chunk::Slice(slice) => slice.iter(),
+                Chunk::Data(data) => data.iter(),
+            })
+            .copied()
+            .collect();
+        data.get(size_of::<u64>()..)
+            .map(|data| data.to_vec())
+            .ok_or(Error::SerializeError)
+    }
+
+    fn decode(data: &[u8]) -> Result<Chunked<T>> {
+        let mut buf = hash_type_id::<T>().to_be_bytes().to_vec();
+        buf.extend_from_slice(data);
+        let (out, rest) = T::deserialize(&buf).map_err(|err| match err {
+            iovec::Error::TypeMismatch => Error::MessageTypeMismatch,
+            _ => Error::DeserializeError,
+        })?;
+        if !rest.is_empty() {
+            return Err(Error::MessageCountMismatch);
+        }
+        Ok(Chunked(out))
+    }
+
+    /// The type ID `ChunkSerDe` writes, as its bytes read back
+    fn type_id() -> u64 {
+        u64::from_ne_bytes(hash_type_id::<T>().to_be_bytes())
+    }
+}
+
+/// Split the type ID header off a typed message.
+pub(crate) fn split_type_id(data: &[u8]) -> Result<(u64, &[u8])> {
+    if data.len() < size_of::<u64>() {
+        return Err(Error::MessageTypeMismatch);
+    }
+    let (header, data) = data.split_at(size_of::<u64>());
+    Ok((u64::from_ne_bytes(header.try_into().unwrap()), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Message as `Communicator::send_message()` sends it
+    fn wire<T: Message + 'static>(msg: &T) -> Vec<u8> {
+        let mut data = T::type_id().to_ne_bytes().to_vec();
+        data.extend(msg.encode().unwrap());
+        data
+    }
+
+    #[test]
+    fn flat_matches_flat_controller() {
+        let values = [1.5f64, -2.0, 3.25];
+        // Type ID, count and the raw elements
+        let mut expected = <f64 as FlatBuffer>::type_id().to_ne_bytes().to_vec();
+        expected.extend(values.len().to_ne_bytes());
+        for val in values {
+            expected.extend(val.to_ne_bytes());
+        }
+        let msg = Flat(values.to_vec());
+        assert_eq!(wire(&msg), expected);
+        let (id, data) = split_type_id(&expected).unwrap();
+        assert_eq!(id, Flat::<f64>::type_id());
+        assert_eq!(Flat::<f64>::decode(data).unwrap(), msg);
+        assert!(matches!(
+            Flat::<f64>::decode(&data[..data.len() - 1]),
+            Err(Error::MessageCountMismatch)
+        ));
+    }
+
+    #[test]
+    fn chunked_matches_iovec_controller() {
+        let values = [7i32, -8, 9];
+        let mut chunks = vec![];
+        <i32 as ChunkSerDe>::serialize(&values, &mut chunks).unwrap();
+        let expected: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| match chunk {
+                Chunk::Slice(slice) => slice.to_vec(),
+                Chunk::Data(data) => data.clone(),
+            })
+            .collect();
+        let msg = Chunked(values.to_vec());
+        assert_eq!(wire(&msg), expected);
+        let (id, data) = split_type_id(&expected).unwrap();
+        assert_eq!(id, Chunked::<i32>::type_id());
+        assert_eq!(Chunked::<i32>::decode(data).unwrap(), msg);
+    }
+
+    #[test]
+    fn type_ids_differ() {
+        assert_ne!(Flat::<i32>::type_id(), Chunked::<i32>::type_id());
+        assert_ne!(Flat::<i32>::type_id(), <Vec<i32> as Message>::type_id());
+    }
+}
@@ -0,0 +1,233 @@
+//! Options for initializing the safe mpi context.
+//!
+//! `InitOptions` replaces environment variables like `UCX_TLS` or
+//! `UCX_RNDV_THRESH` for tuning UCX from the program itself. Overrides are
+//! applied on top of the configuration read from the environment, so the
+//! environment still works for everything that isn't set here.
+use crate::{listener, spawn, Context, Error, Result};
+use log::LevelFilter;
+use std::net::SocketAddr;
+use std::ops::BitOr;
+use ucx2_sys::{
+    ucp::Config, ucs_thread_mode_t, UCP_FEATURE_AM, UCP_FEATURE_AMO32, UCP_FEATURE_AMO64,
+    UCP_FEATURE_RMA, UCP_FEATURE_STREAM, UCP_FEATURE_TAG, UCP_FEATURE_WAKEUP,
+    UCS_THREAD_MODE_MULTI, UCS_THREAD_MODE_SERIALIZED, UCS_THREAD_MODE_SINGLE,
+};
+
+/// Set of UCP features to request from the context.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Features(u64);
+
+impl Features {
+    /// Tag matching, always enabled since everything is built on it
+    pub const TAG: Features = Features(UCP_FEATURE_TAG as u64);
+    /// Stream messages, needed by `init_listener()`
+    pub const STREAM: Features = Features(UCP_FEATURE_STREAM as u64);
+    /// Remote memory access, needed by `Window`
+    pub const RMA: Features = Features(UCP_FEATURE_RMA as u64);
+    /// 32-bit atomics, needed by `Atomic` on `u32` windows
+    pub const AMO32: Features = Features(UCP_FEATURE_AMO32 as u64);
+    /// 64-bit atomics, needed by `Atomic` and window locks
+    pub const AMO64: Features = Features(UCP_FEATURE_AMO64 as u64);
+    /// Active messages, always enabled since they're used for RPCs, revoke
+    /// and abort
+    pub const AM: Features = Features(UCP_FEATURE_AM as u64);
+    /// Wakeup on events
+    pub const WAKEUP: Features = Features(UCP_FEATURE_WAKEUP as u64);
+
+    /// Return the raw UCP feature bits.
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Return true if all features in `other` are included.
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for Features {
+    /// Everything the crate can use
+    fn default() -> Features {
+        Features::TAG
+            | Features::STREAM
+            | Features::RMA
+            | Features::AMO32
+            | Features::AMO64
+            | Features::AM
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+/// How threads may use the context, passed on as the UCP worker thread mode.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ThreadLevel {
+    /// Only one thread ever uses the worker
+    #[default]
+    Single,
+    /// Several threads use the worker, but never at the same time
+    Serialized,
+    /// Several threads use the worker concurrently. The context and
+    /// communicators aren't `Send`, so this has no use until they are and
+    /// only adds locking to the worker.
+    Multi,
+}
+
+impl ThreadLevel {
+    pub(crate) fn thread_mode(self) -> ucs_thread_mode_t {
+        match self {
+            ThreadLevel::Single => UCS_THREAD_MODE_SINGLE,
+            ThreadLevel::Serialized => UCS_THREAD_MODE_SERIALIZED,
+            ThreadLevel::Multi => UCS_THREAD_MODE_MULTI,
+        }
+    }
+
+    pub(crate) fn from_thread_mode(thread_mode: ucs_thread_mode_t) -> ThreadLevel {
+        match thread_mode {
+            UCS_THREAD_MODE_SERIALIZED => ThreadLevel::Serialized,
+            UCS_THREAD_MODE_MULTI => ThreadLevel::Multi,
+            _ => ThreadLevel::Single,
+        }
+    }
+}
+
+/// What to do about logging during initialization.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Logging {
+    /// Set up env_logger from `RUST_LOG`, unless the application already
+    /// installed a logger
+    #[default]
+    Env,
+    /// Set up env_logger with a fixed level, unless the application already
+    /// installed a logger
+    Level(LevelFilter),
+    /// Leave logging to the application
+    Off,
+}
+
+/// Options for initializing the safe mpi context, built with chained calls
+/// and then passed to one of the `init*()` methods.
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    /// UCX configuration overrides
+    pub(crate) config: Vec<(String, String)>,
+    pub(crate) features: Features,
+    pub(crate) thread_level: ThreadLevel,
+    pub(crate) estimated_endpoints: Option<usize>,
+    pub(crate) request_size: Option<usize>,
+    pub(crate) logging: Logging,
+}
+
+impl InitOptions {
+    /// Create options with the defaults, which is what the `init*()`
+    /// functions use.
+    pub fn new() -> InitOptions {
+        InitOptions::default()
+    }
+
+    /// Set the UCX configuration variable `key` to `value`, with the name
+    /// not including the `UCX_` prefix, e.g. `config("TLS", "rc,sm")`.
+    pub fn config(mut self, key: &str, value: &str) -> InitOptions {
+        self.config.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Request `features` from UCP. Tag matching and active messages are
+    /// always added.
+    pub fn features(mut self, features: Features) -> InitOptions {
+        self.features = features;
+        self
+    }
+
+    /// Set the thread level of the worker.
+    pub fn thread_level(mut self, thread_level: ThreadLevel) -> InitOptions {
+        self.thread_level = thread_level;
+        self
+    }
+
+    /// Tell UCP how many endpoints to expect, so that it can pick
+    /// transports that scale to that many.
+    pub fn estimated_endpoints(mut self, count: usize) -> InitOptions {
+        self.estimated_endpoints = Some(count);
+        self
+    }
+
+    /// Reserve `size` bytes in every UCP request for the application.
+    pub fn request_size(mut self, size: usize) -> InitOptions {
+        self.request_size = Some(size);
+        self
+    }
+
+    /// Set what to do about logging.
+    pub fn logging(mut self, logging: Logging) -> InitOptions {
+        self.logging = logging;
+        self
+    }
+
+    /// Initialize the safe mpi context for two processes, see `init()`.
+    pub fn init(&self, sockaddr: SocketAddr, server: bool) -> Result<Context> {
+        self.init_world(sockaddr, if server { 0 } else { 1 }, 2)
+    }
+
+    /// Initialize the safe mpi context from the environment, see
+    /// `init_env()`.
+    pub fn init_env(&self) -> Result<Context> {
+        let var = |name| std::env::var(name).map_err(|_| Error::InitFailure);
+        let sockaddr = var(spawn::ADDR_VAR)?
+            .parse()
+            .map_err(|_| Error::InitFailure)?;
+        let rank = var(spawn::RANK_VAR)?
+            .parse()
+            .map_err(|_| Error::InitFailure)?;
+        let size = var(spawn::SIZE_VAR)?
+            .parse()
+            .map_err(|_| Error::InitFailure)?;
+        self.init_world(sockaddr, rank, size)
+    }
+
+    /// Initialize the safe mpi context as process `rank` out of `size`, see
+    /// `init_world()`.
+    pub fn init_world(&self, sockaddr: SocketAddr, rank: usize, size: usize) -> Result<Context> {
+        unsafe { crate::setup_world(self, sockaddr, rank, size) }
+    }
+
+    /// Initialize the safe mpi context through a UCP listener, see
+    /// `init_listener()`.
+    pub fn init_listener(&self, sockaddr: SocketAddr, rank: usize, size: usize) -> Result<Context> {
+        // The addresses are exchanged with stream messages
+        let options = self.clone().features(self.features | Features::STREAM);
+        unsafe { listener::setup_listener(&options, sockaddr, rank, size) }
+    }
+
+    /// Return the features to create the context with.
+    pub(crate) fn enabled_features(&self) -> Features {
+        self.features | Features::TAG | Features::AM
+    }
+
+    /// Set up logging as requested. A logger installed by the application
+    /// is left alone.
+    pub(crate) fn init_logging(&self) {
+        let _ = match self.logging {
+            Logging::Env => env_logger::try_init(),
+            Logging::Level(level) => env_logger::Builder::new().filter_level(level).try_init(),
+            Logging::Off => Ok(()),
+        };
+    }
+
+    /// Read the UCX configuration from the environment and apply the
+    /// overrides.
+    pub(crate) fn read_config(&self) -> Result<Config> {
+        let mut config = Config::read().map_err(Error::ConfigFailure)?;
+        for (key, value) in &self.config {
+            config.modify(key, value).map_err(Error::ConfigFailure)?;
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,270 @@
+//! Connecting independently started jobs.
+//!
+//! One job opens a port, which is a TCP listener whose "host:port" name is
+//! handed to the other job, e.g. through the file-based name service. The
+//! roots of the two jobs then swap the worker addresses of their processes
+//! and their free context IDs over TCP, the same way the addresses are
+//! exchanged during `init_world()`. Every process adds endpoints for the
+//! other job's processes and the result is an inter-communicator.
+//!
+//! A job started with `init_listener()` can also be connected to without a
+//! port: rank 0 keeps its UCP listener and the roots swap the same info with
+//! stream messages over a client-server endpoint to the listener's address.
+use crate::{
+    communicator::{first_free_context, Communicator},
+    listener::{self, stream_recv, stream_send},
+    util::to_u64s,
+    Context, Error, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::mem::size_of;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::rc::Rc;
+use ucx2_sys::ucp::Endpoint;
+
+/// Environment variable with the directory used by the name service
+const NAME_DIR_VAR: &str = "SAFE_MPI_NAME_DIR";
+
+/// Port that other jobs can connect to.
+pub struct Port {
+    listener: TcpListener,
+    name: String,
+}
+
+impl Port {
+    /// Name to pass to `Communicator::connect()`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Data the roots exchange
+#[derive(Serialize, Deserialize)]
+struct JobInfo {
+    /// Worker addresses of the processes in the communicator, in rank order
+    addrs: Vec<Vec<u8>>,
+    /// Bitmap of context IDs free on all of them
+    free: Vec<u64>,
+}
+
+impl Context {
+    /// Open a port on all interfaces of this host, for other jobs to
+    /// connect to.
+    pub fn open_port(&self) -> Result<Port> {
+        let listener =
+            TcpListener::bind("0.0.0.0:0").map_err(|err| Error::IoFailure(err.kind()))?;
+        let port = listener
+            .local_addr()
+            .map_err(|err| Error::IoFailure(err.kind()))?
+            .port();
+        let host = nix::unistd::gethostname().map_err(|_| Error::HostnameFailure)?;
+        Ok(Port {
+            listener,
+            name: format!("{}:{}", host.to_string_lossy(), port),
+        })
+    }
+}
+
+impl Communicator {
+    /// Accept a connection from another job on `port` and return an
+    /// inter-communicator with this communicator's processes as the local
+    /// group. The port is only used on `root`. This is collective over all
+    /// processes in the communicator.
+    pub fn accept(&self, port: Option<&Port>, root: usize) -> Result<Communicator> {
+        self.join(root, |info| {
+            let port = port.ok_or(Error::ConnectFailure)?;
+            let (mut stream, _) = port
+                .listener
+                .accept()
+                .map_err(|err| Error::IoFailure(err.kind()))?;
+            let remote = recv_info(&mut stream)?;
+            send_info(&mut stream, info)?;
+            Ok(remote)
+        })
+    }
+
+    /// Connect to the job that opened the port named `port_name` and return
+    /// an inter-communicator with this communicator's processes as the local
+    /// group. The name is only used on `root`. This is collective over all
+    /// processes in the communicator.
+    pub fn connect(&self, port_name: &str, root: usize) -> Result<Communicator> {
+        self.join(root, |info| {
+            let mut stream =
+                TcpStream::connect(port_name).map_err(|err| Error::IoFailure(err.kind()))?;
+            send_info(&mut stream, info)?;
+            recv_info(&mut stream)
+        })
+    }
+
+    /// Accept a connection from another job on the UCP listener of a job
+    /// started with `init_listener()` and return an inter-communicator with
+    /// this communicator's processes as the local group. Only `root` uses
+    /// the listener, so it has to be world rank 0. Connection requests are
+    /// accepted in arrival order. This is collective over all processes in
+    /// the communicator.
+    pub fn accept_listener(&self, root: usize) -> Result<Communicator> {
+        self.join(root, |info| {
+            // Taken out while waiting, since progress can run callbacks
+            let listener = self
+                .handle
+                .borrow_mut()
+                .listener
+                .take()
+                .ok_or(Error::ConnectFailure)?;
+            let result = unsafe {
+                listener.accept().and_then(|ep| {
+                    let remote = stream_recv_info(&ep)?;
+                    stream_send_info(&ep, info)?;
+                    listener::close(ep)?;
+                    Ok(remote)
+                })
+            };
+            self.handle.borrow_mut().listener = Some(listener);
+            result
+        })
+    }
+
+    /// Connect to the UCP listener of a job started with `init_listener()`
+    /// on `sockaddr` and return an inter-communicator with this
+    /// communicator's processes as the local group. The address is only
+    /// used on `root`. This is collective over all processes in the
+    /// communicator.
+    pub fn connect_listener(&self, sockaddr: SocketAddr, root: usize) -> Result<Communicator> {
+        self.join(root, |info| {
+            let (worker, err_handler) = {
+                let handle = self.handle.borrow();
+                (Rc::clone(&handle.worker), handle.peers.err_handler())
+            };
+            unsafe {
+                let ep = listener::connect(&worker, err_handler, sockaddr)?;
+                stream_send_info(&ep, info)?;
+                let remote = stream_recv_info(&ep)?;
+                listener::close(ep)?;
+                Ok(remote)
+            }
+        })
+    }
+
+    /// Build the inter-communicator, with `exchange` swapping the job info
+    /// with the other root.
+    fn join<F>(&self, root: usize, exchange: F) -> Result<Communicator>
+    where
+        F: FnOnce(&JobInfo) -> Result<JobInfo>,
+    {
+        if self.is_inter() {
+            return Err(Error::InterCommunicator);
+        }
+        if root >= self.size() {
+            return Err(Error::InvalidRank(root));
+        }
+        let free = self.free_contexts()?;
+
+        // [success, context ID, remote group size]
+        let mut header = [0u64; 3];
+        let mut remote = vec![];
+        let mut result = Ok(());
+        if self.rank() == root {
+            let info = JobInfo {
+                addrs: {
+                    let handle = self.handle.borrow();
+                    self.group
+                        .iter()
+                        .map(|rank| handle.addrs[*rank].clone())
+                        .collect()
+                },
+                free: free.clone(),
+            };
+            result = exchange(&info).and_then(|other| {
+                let free: Vec<u64> = free.iter().zip(other.free).map(|(a, b)| a & b).collect();
+                header = [1, first_free_context(&free)?, other.addrs.len() as u64];
+                remote = other.addrs;
+                Ok(())
+            });
+        }
+        self.bcast(&mut header, root)?;
+        if header[0] == 0 {
+            result?;
+            return Err(Error::ConnectFailure);
+        }
+
+        // Send the addresses out as their lengths followed by their bytes
+        let mut lens: Vec<u64> = remote.iter().map(|addr| addr.len() as u64).collect();
+        lens.resize(header[2] as usize, 0);
+        self.bcast(&mut lens, root)?;
+        let mut bytes = remote.concat();
+        bytes.resize(lens.iter().sum::<u64>() as usize, 0);
+        self.bcast(&mut bytes, root)?;
+        let mut addrs = vec![];
+        let mut rest = &bytes[..];
+        for len in lens {
+            let (addr, tail) = rest.split_at(len as usize);
+            addrs.push(addr.to_vec());
+            rest = tail;
+        }
+
+        let id = header[1];
+        self.claim_context(id);
+        let remote = self.handle.borrow_mut().add_peers(addrs);
+        let mut comm = self.with_group(id, Rc::clone(&self.group), self.rank());
+        comm.remote = Some(Rc::new(remote));
+        Ok(comm)
+    }
+}
+
+/// Write the job info to the other root.
+fn send_info(stream: &mut TcpStream, info: &JobInfo) -> Result<()> {
+    bincode::serialize_into(stream, info).map_err(|_| Error::SerializeError)
+}
+
+/// Read the job info from the other root.
+fn recv_info(stream: &mut TcpStream) -> Result<JobInfo> {
+    bincode::deserialize_from(stream).map_err(|_| Error::DeserializeError)
+}
+
+/// Send the job info to the other root over a stream endpoint, as its
+/// length followed by the encoded info.
+unsafe fn stream_send_info(ep: &Endpoint, info: &JobInfo) -> Result<()> {
+    let data = bincode::serialize(info).map_err(|_| Error::SerializeError)?;
+    stream_send(ep, &[data.len() as u64])?;
+    stream_send(ep, &data)
+}
+
+/// Receive the job info from the other root over a stream endpoint.
+unsafe fn stream_recv_info(ep: &Endpoint) -> Result<JobInfo> {
+    let len = to_u64s(&stream_recv(ep, size_of::<u64>())?)[0];
+    let data = stream_recv(ep, len as usize)?;
+    bincode::deserialize(&data).map_err(|_| Error::DeserializeError)
+}
+
+/// Publish `port_name` under `service`, so that other jobs on this host can
+/// find it with `lookup_name()`.
+pub fn publish_name(service: &str, port_name: &str) -> Result<()> {
+    let dir = name_dir();
+    fs::create_dir_all(&dir).map_err(|err| Error::IoFailure(err.kind()))?;
+    // Write to a temporary file first, so a lookup never sees a partial name
+    let tmp = dir.join(format!(".{}.{}", service, std::process::id()));
+    fs::write(&tmp, port_name)
+        .and_then(|_| fs::rename(&tmp, dir.join(service)))
+        .map_err(|err| Error::IoFailure(err.kind()))
+}
+
+/// Remove the name published under `service`.
+pub fn unpublish_name(service: &str) -> Result<()> {
+    fs::remove_file(name_dir().join(service)).map_err(|err| Error::IoFailure(err.kind()))
+}
+
+/// Return the port name published under `service`. Fails with
+/// `IoFailure(NotFound)` if nothing has been published (yet).
+pub fn lookup_name(service: &str) -> Result<String> {
+    fs::read_to_string(name_dir().join(service)).map_err(|err| Error::IoFailure(err.kind()))
+}
+
+/// Directory holding the published names, `$SAFE_MPI_NAME_DIR` or a
+/// directory in the system's temporary directory.
+fn name_dir() -> PathBuf {
+    std::env::var_os(NAME_DIR_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("safe-mpi-names"))
+}
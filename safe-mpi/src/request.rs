@@ -1,21 +1,22 @@
 use crate::{
     callbacks::{send_nbx_callback, tag_recv_nbx_callback},
     communicator::Data,
-    Error, Handle, Iov, MutIov, Result, Tag,
+    tag, Error, Handle, Iov, MutIov, Result, Tag, Watch,
 };
+use flat::FlatBuffer;
 use log::info;
 use std::cell::RefCell;
 use std::marker::PhantomData;
-use std::mem::MaybeUninit;
-use std::os::raw::c_void;
 use std::rc::Rc;
+use std::time::Instant;
 use ucx2_sys::{
-    rust_ucp_dt_make_contig, rust_ucs_ptr_is_err, rust_ucs_ptr_is_ptr, rust_ucs_ptr_status,
-    ucp_dt_iov, ucp_request_free, ucp_request_param_t, ucp_tag_msg_recv_nbx, ucp_tag_probe_nb,
-    ucp_tag_recv_info_t, ucp_tag_recv_nbx, ucp_tag_send_nbx, ucp_worker_h, ucp_worker_progress,
+    ucp::{self, RequestPtr, Worker},
+    ucp_atomic_op_nbx, ucp_atomic_op_t, ucp_dt_iov, ucp_ep_h, ucp_request_param_t, ucp_rkey_h,
+    ucp_tag_msg_recv_nbx, ucp_tag_recv_nbx,
+    ucp_tag_send_nbx, ucs_status_ptr_t,
     ucp_request_param_t__bindgen_ty_1, UCP_DATATYPE_IOV, UCP_OP_ATTR_FIELD_CALLBACK,
-    UCP_OP_ATTR_FIELD_DATATYPE, UCP_OP_ATTR_FIELD_USER_DATA, UCP_OP_ATTR_FLAG_NO_IMM_CMPL,
-    UCS_INPROGRESS, UCS_OK,
+    UCP_OP_ATTR_FIELD_DATATYPE, UCP_OP_ATTR_FIELD_REPLY_BUFFER, UCP_OP_ATTR_FIELD_USER_DATA,
+    UCP_OP_ATTR_FLAG_NO_IMM_CMPL, UCS_INPROGRESS, UCS_OK,
 };
 
 /// Status for a communication request.
@@ -38,11 +39,13 @@ pub trait Request {
 pub struct SendIovRequest<'a> {
     /// Boolean indicating completion (allocated with Box)
     complete: *mut bool,
-    req: *mut c_void,
+    req: RequestPtr,
     /// Amount of data sent in the request (in bytes)
     req_size: usize,
     /// Handle to ucx objects
     handle: Rc<RefCell<Handle>>,
+    /// World rank of the destination
+    dest: usize,
     /// iovecs, if used for this request
     _iov: Option<Vec<ucp_dt_iov>>,
     marker: PhantomData<&'a ()>,
@@ -52,11 +55,12 @@ impl<'a> SendIovRequest<'a> {
     #[allow(clippy::uninit_assumed_init)]
     pub(crate) unsafe fn new(
         handle: Rc<RefCell<Handle>>,
+        dest: usize,
         // data: Data<'a>,
         data: &'a [Iov],
         tag: Tag,
     ) -> Result<SendIovRequest<'a>> {
-        let endpoint = handle.borrow().endpoint.unwrap();
+        let endpoint = handle.borrow_mut().endpoint(dest)?;
         let (ptr, len, req_size, datatype, iov) = {
             let datatype = UCP_DATATYPE_IOV.try_into().unwrap();
             let mut total = 0;
@@ -92,12 +96,13 @@ impl<'a> SendIovRequest<'a> {
             ..Default::default()
         };
 
-        let req = ucp_tag_send_nbx(endpoint, ptr, len, tag, &param);
+        let req = RequestPtr::from_raw(ucp_tag_send_nbx(endpoint, ptr, len, tag, &param));
         Ok(SendIovRequest {
             complete: cb_info,
             req,
             req_size,
             handle,
+            dest,
             // The iov data needs to be stored as long as the request is
             // alive
             _iov: iov,
@@ -109,9 +114,6 @@ impl<'a> SendIovRequest<'a> {
 impl<'a> Drop for SendIovRequest<'a> {
     fn drop(&mut self) {
         unsafe {
-            if rust_ucs_ptr_is_ptr(self.req) != 0 {
-                ucp_request_free(self.req);
-            }
             let _ = Box::from_raw(self.complete);
         }
     }
@@ -121,8 +123,9 @@ impl<'a> Request for SendIovRequest<'a> {
     /// Make progress on the send request
     unsafe fn progress(&mut self) -> Result<RequestStatus> {
         info!("Running progress() on SendRequest");
-        let worker = self.handle.borrow().worker;
-        request_progress(worker, self.req, self.complete)
+        let worker = Rc::clone(&self.handle.borrow().worker);
+        request_progress(&worker, &self.req, self.complete)
+            .map_err(|err| self.handle.borrow().peer_error(self.dest, err))
     }
 
     /// Return the size of the send request
@@ -139,11 +142,13 @@ impl<'a> Request for SendIovRequest<'a> {
 pub struct RecvIovRequest<'a> {
     /// Boolean indicating completion (allocated with Box)
     complete: *mut bool,
-    req: *mut c_void,
+    req: RequestPtr,
     /// Amount of data sent in the request (in bytes)
     req_size: usize,
     /// Handle to ucx objects
     handle: Rc<RefCell<Handle>>,
+    /// Source and communicator the receive depends on
+    watch: Watch,
     /// iovecs, if used for this request
     _iov: Option<Vec<ucp_dt_iov>>,
     marker: PhantomData<&'a mut ()>,
@@ -156,8 +161,10 @@ impl<'a> RecvIovRequest<'a> {
         // data: Data<'a>,
         data: &'a [MutIov],
         tag: Tag,
+        tag_mask: Tag,
+        watch: Watch,
     ) -> Result<RecvIovRequest<'a>> {
-        let worker = handle.borrow().worker;
+        let worker = handle.borrow().worker.as_raw();
         let (ptr, len, req_size, datatype, iov) = {
             let datatype = UCP_DATATYPE_IOV.try_into().unwrap();
             let mut total = 0;
@@ -192,12 +199,13 @@ impl<'a> RecvIovRequest<'a> {
             ..Default::default()
         };
 
-        let req = ucp_tag_recv_nbx(worker, ptr, len, tag, 0, &param);
+        let req = RequestPtr::from_raw(ucp_tag_recv_nbx(worker, ptr, len, tag, tag_mask, &param));
         Ok(RecvIovRequest {
             complete: cb_info,
             req,
             req_size,
             handle,
+            watch,
             // The iov data needs to be stored as long as the request is
             // alive
             _iov: iov,
@@ -209,8 +217,15 @@ impl<'a> RecvIovRequest<'a> {
 impl<'a> Drop for RecvIovRequest<'a> {
     fn drop(&mut self) {
         unsafe {
-            if rust_ucs_ptr_is_ptr(self.req) != 0 {
-                ucp_request_free(self.req);
+            // The receive writes into the borrowed buffers, so it can't go on
+            // in the background once they may be freed, e.g. after an error
+            // returned early
+            if !*self.complete && self.req.status() == UCS_INPROGRESS {
+                let worker = Rc::clone(&self.handle.borrow().worker);
+                self.req.cancel(&worker);
+                while self.req.status() == UCS_INPROGRESS {
+                    worker.progress();
+                }
             }
             let _ = Box::from_raw(self.complete);
         }
@@ -221,8 +236,17 @@ impl<'a> Request for RecvIovRequest<'a> {
     /// Make progress on the send request
     unsafe fn progress(&mut self) -> Result<RequestStatus> {
         info!("Running progress() on SendRequest");
-        let worker = self.handle.borrow().worker;
-        request_progress(worker, self.req, self.complete)
+        let worker = Rc::clone(&self.handle.borrow().worker);
+        let status = request_progress(&worker, &self.req, self.complete)?;
+        if let RequestStatus::InProgress = status {
+            // Nothing more is coming from a failed process or on a revoked
+            // communicator
+            if let Err(err) = self.handle.borrow().peers.check(&self.watch) {
+                self.req.cancel(&worker);
+                return Err(err);
+            }
+        }
+        Ok(status)
     }
 
     /// Return the size of the send request
@@ -239,11 +263,13 @@ impl<'a> Request for RecvIovRequest<'a> {
 pub struct SendRequest<'a> {
     /// Boolean indicating completion (allocated with Box)
     complete: *mut bool,
-    req: *mut c_void,
+    req: RequestPtr,
     /// Amount of data sent in the request (in bytes)
     req_size: usize,
     /// Handle to ucx objects
     handle: Rc<RefCell<Handle>>,
+    /// World rank of the destination
+    dest: usize,
     /// Data reference
     _data: Data<'a>,
     /// iovecs, if used for this request
@@ -254,16 +280,17 @@ impl<'a> SendRequest<'a> {
     #[allow(clippy::uninit_assumed_init)]
     pub(crate) unsafe fn new(
         handle: Rc<RefCell<Handle>>,
+        dest: usize,
         data: Data<'a>,
         tag: Tag,
     ) -> Result<SendRequest<'a>> {
-        let endpoint = handle.borrow().endpoint.unwrap();
+        let endpoint = handle.borrow_mut().endpoint(dest)?;
         let (ptr, len, req_size, datatype, iov) = match &data {
             Data::Contiguous(buf) => (
                 buf.as_ptr() as *const _,
                 buf.len(),
                 buf.len(),
-                rust_ucp_dt_make_contig(1).try_into().unwrap(),
+                ucp::dt_make_contig(1),
                 None,
             ),
             Data::Chunked(chunks) => {
@@ -302,12 +329,13 @@ impl<'a> SendRequest<'a> {
             ..Default::default()
         };
 
-        let req = ucp_tag_send_nbx(endpoint, ptr, len, tag, &param);
+        let req = RequestPtr::from_raw(ucp_tag_send_nbx(endpoint, ptr, len, tag, &param));
         Ok(SendRequest {
             complete: cb_info,
             req,
             req_size,
             handle,
+            dest,
             _data: data,
             // The iov data needs to be stored as long as the request is
             // alive
@@ -319,9 +347,6 @@ impl<'a> SendRequest<'a> {
 impl<'a> Drop for SendRequest<'a> {
     fn drop(&mut self) {
         unsafe {
-            if rust_ucs_ptr_is_ptr(self.req) != 0 {
-                ucp_request_free(self.req);
-            }
             let _ = Box::from_raw(self.complete);
         }
     }
@@ -329,46 +354,80 @@ impl<'a> Drop for SendRequest<'a> {
 
 /// Progress the request and return whether it completed or not.
 unsafe fn request_progress(
-    worker: ucp_worker_h,
-    req: *mut c_void,
+    worker: &Worker,
+    req: &RequestPtr,
     complete: *mut bool,
 ) -> Result<RequestStatus> {
-    ucp_worker_progress(worker);
+    worker.progress();
 
     if *complete {
         return Ok(RequestStatus::Complete);
     }
 
-    if rust_ucs_ptr_is_ptr(req) == 0 {
-        let status = rust_ucs_ptr_status(req);
-        if status != UCS_OK {
-            return Err(Error::FailedRequest(status));
+    match req.status() {
+        UCS_INPROGRESS => Ok(RequestStatus::InProgress),
+        UCS_OK => {
+            *complete = true;
+            Ok(RequestStatus::Complete)
         }
-        *complete = true;
-        return Ok(RequestStatus::Complete);
+        status => Err(Error::FailedRequest(status)),
     }
+}
 
-    if rust_ucs_ptr_is_err(req) != 0 {
-        return Err(Error::FailedRequest(rust_ucs_ptr_status(req)));
-    }
+/// Start an operation with `f` and block until it completes. `f` gets a
+/// parameter struct with the completion callback already set, which it can
+/// extend with other fields.
+pub(crate) unsafe fn wait_nbx<F>(worker: &Worker, f: F) -> Result<()>
+where
+    F: FnOnce(&ucp_request_param_t) -> ucs_status_ptr_t,
+{
+    wait_nbx_until(worker, None, f)
+}
 
-    let status = rust_ucs_ptr_status(req);
-    if status == UCS_INPROGRESS {
-        Ok(RequestStatus::InProgress)
-    } else {
-        if status != UCS_OK {
-            return Err(Error::FailedRequest(status));
+/// Like `wait_nbx()`, but give up with `RequestTimeout` once `deadline` has
+/// passed. The request is then left to complete in the background, so it
+/// must not refer to any buffers.
+pub(crate) unsafe fn wait_nbx_until<F>(
+    worker: &Worker,
+    deadline: Option<Instant>,
+    f: F,
+) -> Result<()>
+where
+    F: FnOnce(&ucp_request_param_t) -> ucs_status_ptr_t,
+{
+    let complete: *mut bool = Box::into_raw(Box::new(false));
+    let param = ucp_request_param_t {
+        op_attr_mask: UCP_OP_ATTR_FIELD_CALLBACK | UCP_OP_ATTR_FIELD_USER_DATA,
+        cb: ucp_request_param_t__bindgen_ty_1 {
+            send: Some(send_nbx_callback),
+        },
+        user_data: complete as *mut _,
+        ..Default::default()
+    };
+    let req = RequestPtr::from_raw(f(&param));
+    let result = loop {
+        match request_progress(worker, &req, complete) {
+            Ok(RequestStatus::InProgress) => (),
+            Ok(RequestStatus::Complete) => break Ok(()),
+            Err(err) => break Err(err),
         }
-        Ok(RequestStatus::Complete)
-    }
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            // The callback can still run, so the flag is leaked
+            return Err(Error::RequestTimeout);
+        }
+    };
+    drop(req);
+    let _ = Box::from_raw(complete);
+    result
 }
 
 impl<'a> Request for SendRequest<'a> {
     /// Make progress on the send request
     unsafe fn progress(&mut self) -> Result<RequestStatus> {
         info!("Running progress() on SendRequest");
-        let worker = self.handle.borrow().worker;
-        request_progress(worker, self.req, self.complete)
+        let worker = Rc::clone(&self.handle.borrow().worker);
+        request_progress(&worker, &self.req, self.complete)
+            .map_err(|err| self.handle.borrow().peer_error(self.dest, err))
     }
 
     /// Return the size of the send request
@@ -382,6 +441,103 @@ impl<'a> Request for SendRequest<'a> {
     }
 }
 
+/// Remote atomic operation, fetching the previous value.
+pub struct AtomicRequest<'a, T> {
+    /// Boolean indicating completion (allocated with Box)
+    complete: *mut bool,
+    req: RequestPtr,
+    /// Operand and reply buffer, which need to stay put until completion
+    buffers: Box<(T, T)>,
+    /// Handle to ucx objects
+    handle: Rc<RefCell<Handle>>,
+    marker: PhantomData<&'a ()>,
+}
+
+impl<'a, T: FlatBuffer + Copy> AtomicRequest<'a, T> {
+    /// Start an atomic operation on `addr` of the target. For compare and
+    /// swap `value` is compared against and `reply` is swapped in.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) unsafe fn new(
+        handle: Rc<RefCell<Handle>>,
+        ep: ucp_ep_h,
+        opcode: ucp_atomic_op_t,
+        value: T,
+        reply: T,
+        addr: u64,
+        rkey: ucp_rkey_h,
+    ) -> AtomicRequest<'a, T> {
+        let mut buffers = Box::new((value, reply));
+        let cb_info: *mut bool = Box::into_raw(Box::new(false));
+        let param = ucp_request_param_t {
+            op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE
+                | UCP_OP_ATTR_FIELD_CALLBACK
+                | UCP_OP_ATTR_FIELD_USER_DATA
+                | UCP_OP_ATTR_FIELD_REPLY_BUFFER,
+            datatype: ucp::dt_make_contig(std::mem::size_of::<T>()),
+            cb: ucp_request_param_t__bindgen_ty_1 {
+                send: Some(send_nbx_callback),
+            },
+            user_data: cb_info as *mut _,
+            reply_buffer: &mut buffers.1 as *mut T as *mut _,
+            ..Default::default()
+        };
+        let req = RequestPtr::from_raw(ucp_atomic_op_nbx(
+            ep,
+            opcode,
+            &buffers.0 as *const T as *const _,
+            1,
+            addr,
+            rkey,
+            &param,
+        ));
+        AtomicRequest {
+            complete: cb_info,
+            req,
+            buffers,
+            handle,
+            marker: PhantomData,
+        }
+    }
+
+    /// Return the previous value at the target, once the request is
+    /// complete.
+    pub fn value(&self) -> Option<T> {
+        if unsafe { *self.complete } {
+            Some(self.buffers.1)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, T> Drop for AtomicRequest<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = Box::from_raw(self.complete);
+        }
+    }
+}
+
+impl<'a, T: FlatBuffer + Copy> Request for AtomicRequest<'a, T> {
+    /// Make progress on the atomic operation
+    unsafe fn progress(&mut self) -> Result<RequestStatus> {
+        let worker = Rc::clone(&self.handle.borrow().worker);
+        request_progress(&worker, &self.req, self.complete)
+    }
+
+    /// Return the size of the operand
+    fn size(&self) -> Option<usize> {
+        Some(self.buffers.0.size())
+    }
+
+    /// Return the bytes of the previous value, once complete
+    fn data(&mut self) -> Option<Vec<u8>> {
+        self.value().map(|value| unsafe {
+            std::slice::from_raw_parts(value.ptr(), value.size()).to_vec()
+        })
+    }
+}
+
 enum RecvProbeRequestState {
     /// Probing for the message
     Probe,
@@ -395,30 +551,46 @@ pub struct RecvProbeRequest {
     handle: Rc<RefCell<Handle>>,
     state: RecvProbeRequestState,
     tag: Tag,
+    tag_mask: Tag,
     complete: *mut bool,
-    req: *mut c_void,
+    req: RequestPtr,
     data: Option<Vec<u8>>,
+    /// Rank of the sender, once the message has been probed
+    source: Option<usize>,
+    /// Source and communicator the receive depends on
+    watch: Watch,
 }
 
 impl RecvProbeRequest {
-    pub(crate) fn new(handle: Rc<RefCell<Handle>>, tag: Tag) -> RecvProbeRequest {
+    pub(crate) fn new(
+        handle: Rc<RefCell<Handle>>,
+        tag: Tag,
+        tag_mask: Tag,
+        watch: Watch,
+    ) -> RecvProbeRequest {
         RecvProbeRequest {
             handle,
             state: RecvProbeRequestState::Probe,
             tag,
+            tag_mask,
             complete: Box::into_raw(Box::new(false)),
-            req: std::ptr::null_mut(),
+            req: RequestPtr::completed(),
             data: None,
+            source: None,
+            watch,
         }
     }
+
+    /// Return the rank of the sender in its communicator, once the message
+    /// has been matched.
+    pub fn source(&self) -> Option<usize> {
+        self.source
+    }
 }
 
 impl Drop for RecvProbeRequest {
     fn drop(&mut self) {
         unsafe {
-            if rust_ucs_ptr_is_ptr(self.req) != 0 {
-                ucp_request_free(self.req);
-            }
             let _ = Box::from_raw(self.complete);
         }
     }
@@ -427,44 +599,44 @@ impl Drop for RecvProbeRequest {
 impl Request for RecvProbeRequest {
     /// Progress the request. This will need to be called multiple times until
     /// error or `Ok(RequestStatus::Complete)` is returned.
-    #[allow(clippy::uninit_assumed_init)]
     unsafe fn progress(&mut self) -> Result<RequestStatus> {
-        let worker = self.handle.borrow().worker;
+        let worker = Rc::clone(&self.handle.borrow().worker);
         match self.state {
             RecvProbeRequestState::Probe => {
-                ucp_worker_progress(worker);
-                let mut info = MaybeUninit::<ucp_tag_recv_info_t>::uninit();
+                worker.progress();
                 // Probe for the message
-                let message = ucp_tag_probe_nb(worker, self.tag, 0, 1, info.as_mut_ptr());
-                if !message.is_null() {
+                if let Some((message, info)) = worker.tag_probe(self.tag, self.tag_mask) {
                     // Message probed, go ahead and allocate everything and
                     // start the receive.
                     self.state = RecvProbeRequestState::Wait;
-                    let info = info.assume_init();
+                    self.source = Some(tag::source(info.sender_tag));
                     let _ = self.data.insert(vec![0; info.length]);
                     let param = ucp_request_param_t {
                         op_attr_mask: UCP_OP_ATTR_FIELD_CALLBACK | UCP_OP_ATTR_FIELD_DATATYPE | UCP_OP_ATTR_FIELD_USER_DATA | UCP_OP_ATTR_FLAG_NO_IMM_CMPL,
-                        datatype: rust_ucp_dt_make_contig(1).try_into().unwrap(),
+                        datatype: ucp::dt_make_contig(1),
                         cb: ucp_request_param_t__bindgen_ty_1 {
                             recv: Some(tag_recv_nbx_callback),
                         },
                         user_data: self.complete as *mut _,
                         ..Default::default()
                     };
-                    self.req = ucp_tag_msg_recv_nbx(
-                        worker,
+                    self.req = RequestPtr::from_raw(ucp_tag_msg_recv_nbx(
+                        worker.as_raw(),
                         self.data.as_mut().unwrap().as_mut_ptr() as *mut _,
                         info.length,
                         message,
                         &param,
-                    );
+                    ));
+                } else {
+                    // Nothing more is coming from a failed process or on a
+                    // revoked communicator
+                    self.handle.borrow().peers.check(&self.watch)?;
                 }
                 Ok(RequestStatus::InProgress)
             }
             RecvProbeRequestState::Wait => {
                 // Wait until request completion
-                let worker = self.handle.borrow().worker;
-                match request_progress(worker, self.req, self.complete)? {
+                match request_progress(&worker, &self.req, self.complete)? {
                     RequestStatus::Complete => {
                         self.state = RecvProbeRequestState::Complete;
                         Ok(RequestStatus::Complete)
@@ -0,0 +1,157 @@
+//! Receiving messages of several types on one tag.
+//!
+//! Typed messages carry the hashed type ID of their type in front of the
+//! encoded value, so a receiver can probe a message, look at the ID and only
+//! then pick the type to decode it as. This lets one tag carry a small
+//! protocol of message kinds. Flat and `ChunkSerDe` data use the type ID
+//! headers of `FlatBuffer` and `ChunkSerDe`, so messages sent by the
+//! benchmarks' `FlatController` and `IovecController` can be routed too.
+use crate::{
+    communicator::Communicator,
+    message::{split_type_id, Chunked, Flat, Message},
+    Error, Iov, Request, RequestStatus, Result, Tag,
+};
+use flat::FlatBuffer;
+use iovec::ChunkSerDe;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// Message received without knowing its type up front.
+pub struct AnyMessage {
+    source: usize,
+    type_id: u64,
+    data: Vec<u8>,
+}
+
+impl AnyMessage {
+    /// Rank of the sender
+    pub fn source(&self) -> usize {
+        self.source
+    }
+
+    /// Hashed type ID the message was sent with
+    pub fn type_id(&self) -> u64 {
+        self.type_id
+    }
+
+    /// Return true if the message was sent as a `T`.
+    pub fn is<T: Message + 'static>(&self) -> bool {
+        self.type_id == T::type_id()
+    }
+
+    /// Decode the message as a `T`, failing with `MessageTypeMismatch` if it
+    /// was sent as something else.
+    pub fn decode<T: Message + 'static>(&self) -> Result<T> {
+        if !self.is::<T>() {
+            return Err(Error::MessageTypeMismatch);
+        }
+        T::decode(&self.data)
+    }
+}
+
+impl Communicator {
+    /// Send `msg` with its type ID, so that it can be received with
+    /// `recv_any()` or a channel of the same type. Blocks until the send has
+    /// completed.
+    pub fn send_message<T: Message + 'static>(&self, dest: usize, tag: Tag, msg: &T) -> Result<()> {
+        let header = T::type_id();
+        let data = msg.encode()?;
+        let iovs = [
+            Iov((&header as *const u64) as *const u8, size_of::<u64>()),
+            Iov(data.as_ptr(), data.len()),
+        ];
+        // Blocking, so the buffers outlive the send
+        unsafe { self.send(dest, &iovs, tag)? };
+        Ok(())
+    }
+
+    /// Receive the next typed message on `tag`, whatever its type. Passing
+    /// `None` as the source receives from any process.
+    pub fn recv_any(&self, source: Option<usize>, tag: Tag) -> Result<AnyMessage> {
+        let mut req = self.irecv_probe(source, tag)?;
+        unsafe { while let RequestStatus::InProgress = req.progress()? {} }
+        let source = req.source().ok_or(Error::InternalError)?;
+        let data = req.data().ok_or(Error::InternalError)?;
+        let (type_id, data) = split_type_id(&data)?;
+        Ok(AnyMessage {
+            source,
+            type_id,
+            data: data.to_vec(),
+        })
+    }
+}
+
+/// Type-erased handler, called with the source rank and the encoded value
+type Handler = Box<dyn FnMut(usize, &[u8]) -> Result<()>>;
+
+/// Set of handlers for the message types that can arrive on a tag.
+#[derive(Default)]
+pub struct TypeRouter {
+    handlers: HashMap<u64, Handler>,
+}
+
+impl TypeRouter {
+    pub fn new() -> TypeRouter {
+        TypeRouter::default()
+    }
+
+    /// Register `handler` for messages of type `T`, replacing any previous
+    /// handler for the type. The handler is called with the source rank and
+    /// the decoded message.
+    pub fn register<T, F>(&mut self, mut handler: F)
+    where
+        T: Message + 'static,
+        F: FnMut(usize, T) + 'static,
+    {
+        self.handlers.insert(
+            T::type_id(),
+            Box::new(move |source, data: &[u8]| {
+                handler(source, T::decode(data)?);
+                Ok(())
+            }),
+        );
+    }
+
+    /// Register `handler` for flat data with elements of type `T`, as sent
+    /// with `Flat<T>` or by `FlatController`.
+    pub fn register_flat<T, F>(&mut self, mut handler: F)
+    where
+        T: FlatBuffer + Copy + Default,
+        F: FnMut(usize, Vec<T>) + 'static,
+    {
+        self.register(move |source, data: Flat<T>| handler(source, data.0));
+    }
+
+    /// Register `handler` for `ChunkSerDe` data of type `T`, as sent with
+    /// `Chunked<T>` or by `IovecController`.
+    pub fn register_chunked<T, F>(&mut self, mut handler: F)
+    where
+        T: ChunkSerDe + 'static,
+        F: FnMut(usize, Vec<T>) + 'static,
+    {
+        self.register(move |source, data: Chunked<T>| handler(source, data.0));
+    }
+
+    /// Run the handler for the type of `msg`. Messages of an unregistered
+    /// type give `MessageTypeMismatch`.
+    pub fn dispatch(&mut self, msg: &AnyMessage) -> Result<()> {
+        let handler = self
+            .handlers
+            .get_mut(&msg.type_id)
+            .ok_or(Error::MessageTypeMismatch)?;
+        handler(msg.source, &msg.data)
+    }
+
+    /// Receive the next message on `tag` and run the handler for its type.
+    /// Returns the rank of the sender.
+    pub fn recv_any(
+        &mut self,
+        comm: &Communicator,
+        source: Option<usize>,
+        tag: Tag,
+    ) -> Result<usize> {
+        let msg = comm.recv_any(source, tag)?;
+        self.dispatch(&msg)?;
+        Ok(msg.source)
+    }
+}
@@ -0,0 +1,295 @@
+//! Request/response calls between processes, built on active messages.
+//!
+//! Every call carries a correlation ID, which the reply echoes back, so any
+//! number of calls can be outstanding at once. Methods are registered as
+//! closures on the serving process and run from `Rpc::progress()`, which
+//! both servers and waiting clients call.
+use crate::{communicator::Communicator, message::Message, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Active message handler ID for calls
+const CALL_ID: u32 = 0;
+/// Active message handler ID for replies
+const REPLY_ID: u32 = 1;
+
+/// Method of a service, tying a method ID to its request and response types.
+pub trait Method {
+    /// ID the method is registered under
+    const ID: u32;
+    type Request: Message;
+    type Response: Message;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Call {
+    id: u64,
+    method: u32,
+    payload: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Reply {
+    /// Encoded response
+    Ok(Vec<u8>),
+    /// Error code returned by the method
+    Failed(i32),
+    /// No method registered under the ID
+    UnknownMethod(u32),
+    /// The request couldn't be decoded
+    BadRequest,
+    /// The response couldn't be encoded
+    BadResponse,
+}
+
+/// Type-erased method, called with the source rank and the encoded request
+type Handler = Rc<RefCell<dyn FnMut(usize, &[u8]) -> Reply>>;
+
+#[derive(Default)]
+struct RpcState {
+    /// ID of the next call made by this process
+    next_id: u64,
+    /// Calls received and not served yet, with their source rank
+    calls: VecDeque<(usize, Call)>,
+    /// Replies to this process's calls by call ID
+    replies: HashMap<u64, Reply>,
+    /// Calls that timed out or were dropped, whose replies are discarded
+    abandoned: HashSet<u64>,
+    /// Registered methods by ID
+    methods: HashMap<u32, Handler>,
+}
+
+/// RPC endpoint that can both serve methods and call them on other
+/// processes.
+pub struct Rpc {
+    comm: Communicator,
+    state: Rc<RefCell<RpcState>>,
+}
+
+impl Rpc {
+    /// Create an RPC endpoint on a duplicate of `comm`, so that its messages
+    /// can't interfere with other traffic. This is collective over all
+    /// processes in the communicator.
+    pub fn new(comm: &Communicator) -> Result<Rpc> {
+        let comm = comm.dup()?;
+        let state = Rc::new(RefCell::new(RpcState::default()));
+        {
+            let state = Rc::clone(&state);
+            comm.register_handler(CALL_ID, move |source, call: Call| {
+                state.borrow_mut().calls.push_back((source, call));
+            });
+        }
+        {
+            let state = Rc::clone(&state);
+            comm.register_handler(REPLY_ID, move |_, (id, reply): (u64, Reply)| {
+                let mut state = state.borrow_mut();
+                if !state.abandoned.remove(&id) {
+                    state.replies.insert(id, reply);
+                }
+            });
+        }
+        Ok(Rpc { comm, state })
+    }
+
+    /// Communicator the endpoint runs on
+    pub fn comm(&self) -> &Communicator {
+        &self.comm
+    }
+
+    /// Register `handler` as method `method`, replacing any previous one.
+    /// Returning an error code from the handler makes the call fail with
+    /// `Error::RpcFailure` on the caller.
+    pub fn register<Req, Resp, F>(&self, method: u32, mut handler: F)
+    where
+        Req: Message,
+        Resp: Message,
+        F: FnMut(usize, Req) -> std::result::Result<Resp, i32> + 'static,
+    {
+        let handler: Handler = Rc::new(RefCell::new(move |source, payload: &[u8]| {
+            let req = match Req::decode(payload) {
+                Ok(req) => req,
+                Err(_) => return Reply::BadRequest,
+            };
+            match handler(source, req).map(|resp| resp.encode()) {
+                Ok(Ok(resp)) => Reply::Ok(resp),
+                Ok(Err(_)) => Reply::BadResponse,
+                Err(code) => Reply::Failed(code),
+            }
+        }));
+        self.state.borrow_mut().methods.insert(method, handler);
+    }
+
+    /// Register `handler` for the method `M`.
+    pub fn register_method<M, F>(&self, handler: F)
+    where
+        M: Method,
+        F: FnMut(usize, M::Request) -> std::result::Result<M::Response, i32> + 'static,
+    {
+        self.register(M::ID, handler)
+    }
+
+    /// Call method `method` on `rank` with `req`. The returned call has to be
+    /// waited on for the response.
+    pub fn call<Req, Resp>(
+        &self,
+        rank: usize,
+        method: u32,
+        req: &Req,
+    ) -> Result<PendingCall<'_, Resp>>
+    where
+        Req: Message,
+        Resp: Message,
+    {
+        let id = {
+            let mut state = self.state.borrow_mut();
+            state.next_id += 1;
+            state.next_id
+        };
+        let call = Call {
+            id,
+            method,
+            payload: req.encode()?,
+        };
+        self.comm.am_send(rank, CALL_ID, &call)?;
+        Ok(PendingCall {
+            rpc: self,
+            id,
+            done: false,
+            marker: PhantomData,
+        })
+    }
+
+    /// Call the method `M` on `rank`.
+    pub fn call_method<M: Method>(
+        &self,
+        rank: usize,
+        req: &M::Request,
+    ) -> Result<PendingCall<'_, M::Response>> {
+        self.call(rank, M::ID, req)
+    }
+
+    /// Receive messages and serve all calls that have arrived. Calls of a
+    /// method whose handler is running, because it waits for a call of its
+    /// own, stay queued until it returns. Returns the number of calls
+    /// served.
+    pub fn progress(&self) -> Result<usize> {
+        self.comm.am_progress()?;
+        let mut busy = vec![];
+        let mut served = 0;
+        let result = loop {
+            let (source, call) = match self.state.borrow_mut().calls.pop_front() {
+                Some(call) => call,
+                None => break Ok(served),
+            };
+            let handler = self.state.borrow().methods.get(&call.method).cloned();
+            // The handler may make calls itself, so the state can't be
+            // borrowed while it runs
+            let reply = match handler.as_ref().map(|handler| handler.try_borrow_mut()) {
+                Some(Ok(mut handler)) => (*handler)(source, &call.payload),
+                Some(Err(_)) => {
+                    busy.push((source, call));
+                    continue;
+                }
+                None => Reply::UnknownMethod(call.method),
+            };
+            if let Err(err) = self.comm.am_send(source, REPLY_ID, &(call.id, reply)) {
+                break Err(err);
+            }
+            served += 1;
+        };
+        // Put the calls back ahead of anything that arrived in the meantime
+        let mut state = self.state.borrow_mut();
+        for call in busy.into_iter().rev() {
+            state.calls.push_front(call);
+        }
+        result
+    }
+}
+
+/// Call that's waiting for its reply.
+pub struct PendingCall<'a, Resp> {
+    rpc: &'a Rpc,
+    id: u64,
+    /// Whether the reply has been taken
+    done: bool,
+    marker: PhantomData<Resp>,
+}
+
+impl<'a, Resp: Message> PendingCall<'a, Resp> {
+    /// Check whether the reply has arrived, without blocking.
+    pub fn test(&mut self) -> Result<Option<Resp>> {
+        if self.done {
+            return Err(Error::InternalError);
+        }
+        self.rpc.progress()?;
+        let reply = self.rpc.state.borrow_mut().replies.remove(&self.id);
+        match reply {
+            Some(reply) => {
+                self.done = true;
+                match reply {
+                    Reply::Ok(resp) => Resp::decode(&resp).map(Some),
+                    Reply::Failed(code) => Err(Error::RpcFailure(code)),
+                    Reply::UnknownMethod(method) => Err(Error::UnknownMethod(method)),
+                    Reply::BadRequest => Err(Error::DeserializeError),
+                    Reply::BadResponse => Err(Error::BadResponse),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Wait for the reply, giving up with `Error::RequestTimeout` after
+    /// `timeout` if there is one.
+    pub fn wait(mut self, timeout: Option<Duration>) -> Result<Resp> {
+        let start = Instant::now();
+        loop {
+            if let Some(resp) = self.test()? {
+                return Ok(resp);
+            }
+            if timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+                return Err(Error::RequestTimeout);
+            }
+        }
+    }
+}
+
+impl<'a, Resp> Drop for PendingCall<'a, Resp> {
+    fn drop(&mut self) {
+        if !self.done {
+            let mut state = self.rpc.state.borrow_mut();
+            // Discard the reply, whether it's already here or not
+            if state.replies.remove(&self.id).is_none() {
+                state.abandoned.insert(self.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_world;
+
+    #[test]
+    fn nested_call() {
+        let context = init_world("127.0.0.1:0".parse().unwrap(), 0, 1).unwrap();
+        let rpc = Rc::new(Rpc::new(&context.world()).unwrap());
+        rpc.register(2, |_, x: u64| Ok(x + 1));
+        let weak = Rc::downgrade(&rpc);
+        rpc.register(1, move |_, x: u64| {
+            // Serving this call also sees the second call of method 1, which
+            // has to wait until this one returns
+            let rpc = weak.upgrade().ok_or(-1)?;
+            let call = rpc.call::<u64, u64>(0, 2, &x).map_err(|_| -1)?;
+            call.wait(None).map(|y| y * 10).map_err(|_| -1)
+        });
+        let first = rpc.call::<u64, u64>(0, 1, &1).unwrap();
+        let second = rpc.call::<u64, u64>(0, 1, &2).unwrap();
+        assert_eq!(first.wait(None).unwrap(), 20);
+        assert_eq!(second.wait(None).unwrap(), 30);
+    }
+}
@@ -0,0 +1,78 @@
+//! Starting new processes from a running job.
+//!
+//! The spawned processes form their own world, bootstrapped from environment
+//! variables with `init_env()`, and then connect back to a port opened by
+//! the parent, giving an inter-communicator on both sides.
+use crate::{communicator::Communicator, Context, Error, Result};
+use std::ffi::OsStr;
+use std::net::TcpListener;
+use std::process::Command;
+
+/// Address rank 0 of the world listens on for the address exchange
+pub(crate) const ADDR_VAR: &str = "SAFE_MPI_ADDR";
+/// Rank of the process in its world
+pub(crate) const RANK_VAR: &str = "SAFE_MPI_RANK";
+/// Number of processes in the world
+pub(crate) const SIZE_VAR: &str = "SAFE_MPI_SIZE";
+/// Port name of the parent, for spawned processes
+const PARENT_VAR: &str = "SAFE_MPI_PARENT";
+
+impl Context {
+    /// Start `n` processes running `program` with `args` on this host and
+    /// return an inter-communicator to them, with the world as the local
+    /// group. The new processes have to call `init_env()` and then
+    /// `Context::parent()`. This is collective over all processes in the
+    /// world, and rank 0 starts the processes. They are waited for when the
+    /// context is dropped.
+    pub fn spawn<S: AsRef<OsStr>>(
+        &self,
+        program: impl AsRef<OsStr>,
+        args: &[S],
+        n: usize,
+    ) -> Result<Communicator> {
+        let world = self.world();
+        let mut spawned = Ok(None);
+        if world.rank() == 0 {
+            spawned = self.open_port().and_then(|port| {
+                let addr = free_local_addr()?;
+                for rank in 0..n {
+                    let child = Command::new(&program)
+                        .args(args)
+                        .env(ADDR_VAR, &addr)
+                        .env(RANK_VAR, rank.to_string())
+                        .env(SIZE_VAR, n.to_string())
+                        .env(PARENT_VAR, port.name())
+                        .spawn()
+                        .map_err(|err| Error::IoFailure(err.kind()))?;
+                    self.handle.borrow_mut().children.push(child);
+                }
+                Ok(Some(port))
+            });
+        }
+        let port = spawned.as_ref().ok().and_then(|port| port.as_ref());
+        match world.accept(port, 0) {
+            Err(err) => spawned.and(Err(err)),
+            comm => comm,
+        }
+    }
+
+    /// Return the inter-communicator to the parent job if this process was
+    /// started by `Context::spawn()`. This is collective over all processes
+    /// in the world.
+    pub fn parent(&self) -> Result<Option<Communicator>> {
+        match std::env::var(PARENT_VAR) {
+            Ok(port_name) => self.world().connect(&port_name, 0).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Find a free local address for the spawned processes' address exchange.
+/// The port could in theory be taken again before they bind it.
+fn free_local_addr() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").map_err(|err| Error::IoFailure(err.kind()))?;
+    let addr = listener
+        .local_addr()
+        .map_err(|err| Error::IoFailure(err.kind()))?;
+    Ok(addr.to_string())
+}
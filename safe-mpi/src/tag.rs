@@ -0,0 +1,69 @@
+//! Layout of the 64-bit tag that is actually passed to UCP.
+//!
+//! User tags only get the low 32 bits, the rest is used to encode the source
+//! rank, the communicator's context ID and to separate internal library
+//! traffic from user messages:
+//!
+//! ```text
+//! | 63       | 48..62     | 32..47      | 0..31    |
+//! | internal | context ID | source rank | user tag |
+//! ```
+use crate::{Error, Result, Tag};
+
+/// Set for messages sent by the library itself (collectives, etc.)
+const INTERNAL_BIT: Tag = 1 << 63;
+/// Shift of the source rank
+const SOURCE_SHIFT: u32 = 32;
+/// Number of bits available for the source rank
+pub(crate) const SOURCE_BITS: u32 = 16;
+/// Mask for the source rank (after shifting)
+const SOURCE_MASK: Tag = (1 << SOURCE_BITS) - 1;
+/// Shift of the context ID
+const CONTEXT_SHIFT: u32 = 48;
+/// Number of bits available for context IDs
+pub(crate) const CONTEXT_BITS: u32 = 15;
+/// Mask for the context ID (after shifting)
+const CONTEXT_MASK: Tag = (1 << CONTEXT_BITS) - 1;
+/// Mask for the user tag
+const USER_MASK: Tag = 0xffff_ffff;
+
+/// Context ID used by the world communicator
+pub(crate) const WORLD_CONTEXT: Tag = 0;
+
+/// Build the UCP tag for a user message sent from `source`.
+pub(crate) fn user(context: Tag, source: usize, tag: Tag) -> Result<Tag> {
+    if tag & !USER_MASK != 0 {
+        return Err(Error::InvalidTag(tag));
+    }
+    Ok(encode_context(context) | encode_source(source) | tag)
+}
+
+/// Build the UCP tag for an internal message sent from `source`.
+pub(crate) fn internal(context: Tag, source: usize, tag: Tag) -> Tag {
+    INTERNAL_BIT | encode_context(context) | encode_source(source) | (tag & USER_MASK)
+}
+
+/// Return the source rank encoded in a UCP tag.
+pub(crate) fn source(tag: Tag) -> usize {
+    ((tag >> SOURCE_SHIFT) & SOURCE_MASK) as usize
+}
+
+/// Mask to use for receives. If `source_matters` is false then messages from
+/// any source will be matched.
+pub(crate) fn mask(source_matters: bool) -> Tag {
+    if source_matters {
+        Tag::MAX
+    } else {
+        !(SOURCE_MASK << SOURCE_SHIFT)
+    }
+}
+
+#[inline]
+fn encode_context(context: Tag) -> Tag {
+    (context & CONTEXT_MASK) << CONTEXT_SHIFT
+}
+
+#[inline]
+fn encode_source(source: usize) -> Tag {
+    ((source as Tag) & SOURCE_MASK) << SOURCE_SHIFT
+}
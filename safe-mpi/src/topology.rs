@@ -0,0 +1,370 @@
+//! Cartesian and graph process topologies, and neighborhood collectives.
+//!
+//! A topology is attached to a new communicator and only describes which
+//! processes are neighbors, the communication itself is still plain
+//! point-to-point tag matching.
+use crate::{communicator::Communicator, Error, MutIov, Request, RequestStatus, Result, Tag};
+use flat::FlatBuffer;
+use std::rc::Rc;
+
+/// Internal tag for neighborhood collectives. For Cartesian topologies the
+/// low bits hold the index of the neighbor slot that the message is for.
+const NEIGHBOR_TAG: Tag = 1 << 30;
+
+/// Virtual topology attached to a communicator
+pub(crate) enum Topology {
+    /// Grid of processes, ranks are assigned in row-major order
+    Cart {
+        /// Number of processes along each dimension
+        dims: Vec<usize>,
+        /// Whether each dimension wraps around
+        periodic: Vec<bool>,
+    },
+    /// Directed graph, only the edges of this process are stored
+    Graph {
+        /// Ranks this process receives from
+        sources: Vec<usize>,
+        /// Ranks this process sends to
+        destinations: Vec<usize>,
+    },
+}
+
+/// Neighbors of a process in a topology, `None` is used past the edge of a
+/// non-periodic Cartesian dimension.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Neighbors {
+    /// Ranks this process receives from
+    pub sources: Vec<Option<usize>>,
+    /// Ranks this process sends to
+    pub destinations: Vec<Option<usize>>,
+}
+
+impl Communicator {
+    /// Create a communicator with a Cartesian topology of `dims` processes.
+    /// Ranks are laid out in row-major order and processes with a rank past
+    /// the size of the grid get `None`. This is collective over all processes
+    /// in the communicator.
+    pub fn cart_create(&self, dims: &[usize], periodic: &[bool]) -> Result<Option<Communicator>> {
+        if dims.len() != periodic.len() {
+            return Err(Error::LengthMismatch);
+        }
+        let total: usize = dims.iter().product();
+        if dims.is_empty() || total == 0 || total > self.size() {
+            return Err(Error::InvalidTopology);
+        }
+        let color = (self.rank() < total).then_some(0);
+        Ok(self.split(color, 0)?.map(|mut comm| {
+            comm.topology = Some(Rc::new(Topology::Cart {
+                dims: dims.to_vec(),
+                periodic: periodic.to_vec(),
+            }));
+            comm
+        }))
+    }
+
+    /// Return the number of processes along each dimension of the Cartesian
+    /// topology.
+    pub fn cart_dims(&self) -> Result<&[usize]> {
+        self.cart().map(|(dims, _)| dims)
+    }
+
+    /// Return the coordinates of `rank` in the Cartesian topology.
+    pub fn cart_coords(&self, rank: usize) -> Result<Vec<usize>> {
+        let (dims, _) = self.cart()?;
+        if rank >= self.size() {
+            return Err(Error::InvalidRank(rank));
+        }
+        Ok(cart_coords(dims, rank))
+    }
+
+    /// Return the rank at `coords` in the Cartesian topology. Coordinates
+    /// wrap around in periodic dimensions; out of range coordinates in other
+    /// dimensions give `None`.
+    pub fn cart_rank(&self, coords: &[isize]) -> Result<Option<usize>> {
+        let (dims, periodic) = self.cart()?;
+        if coords.len() != dims.len() {
+            return Err(Error::LengthMismatch);
+        }
+        Ok(cart_rank(dims, periodic, coords))
+    }
+
+    /// Return the (source, destination) ranks for a shift of `disp` along
+    /// dimension `dim`, i.e. the processes at `-disp` and `+disp` from this
+    /// one. `None` means the shift falls off a non-periodic edge.
+    pub fn cart_shift(&self, dim: usize, disp: isize) -> Result<(Option<usize>, Option<usize>)> {
+        let (dims, periodic) = self.cart()?;
+        if dim >= dims.len() {
+            return Err(Error::InvalidTopology);
+        }
+        Ok(cart_shift(dims, periodic, self.rank(), dim, disp))
+    }
+
+    /// Create a communicator with a distributed graph topology, where each
+    /// process only passes its own edges: `sources` are the ranks it receives
+    /// from and `destinations` the ranks it sends to. The edges have to be
+    /// consistent across processes. This is collective over all processes in
+    /// the communicator.
+    pub fn dist_graph_create_adjacent(
+        &self,
+        sources: &[usize],
+        destinations: &[usize],
+    ) -> Result<Communicator> {
+        if let Some(rank) = sources
+            .iter()
+            .chain(destinations)
+            .find(|rank| **rank >= self.size())
+        {
+            return Err(Error::InvalidRank(*rank));
+        }
+        let mut comm = self.dup()?;
+        comm.topology = Some(Rc::new(Topology::Graph {
+            sources: sources.to_vec(),
+            destinations: destinations.to_vec(),
+        }));
+        Ok(comm)
+    }
+
+    /// Return the neighbors of this process in the topology.
+    ///
+    /// For Cartesian topologies both lists hold the process at -1 and then +1
+    /// along each dimension.
+    pub fn neighbors(&self) -> Result<Neighbors> {
+        match self.topology.as_deref() {
+            Some(Topology::Cart { dims, .. }) => {
+                let mut neighbors = vec![];
+                for dim in 0..dims.len() {
+                    let (minus, plus) = self.cart_shift(dim, 1)?;
+                    neighbors.push(minus);
+                    neighbors.push(plus);
+                }
+                Ok(Neighbors {
+                    sources: neighbors.clone(),
+                    destinations: neighbors,
+                })
+            }
+            Some(Topology::Graph {
+                sources,
+                destinations,
+            }) => Ok(Neighbors {
+                sources: sources.iter().map(|rank| Some(*rank)).collect(),
+                destinations: destinations.iter().map(|rank| Some(*rank)).collect(),
+            }),
+            None => Err(Error::MissingTopology),
+        }
+    }
+
+    /// Send `data` to every destination and gather the data from every
+    /// source, in the order given by `neighbors()`. Blocks for missing
+    /// neighbors are left as default values. All processes must pass the
+    /// same number of elements.
+    pub fn neighbor_allgather<T: FlatBuffer + Copy + Default>(&self, data: &[T]) -> Result<Vec<T>> {
+        let neighbors = self.neighbors()?;
+        let blocks = vec![data; neighbors.destinations.len()];
+        self.neighbor_exchange(&blocks, data.len())
+    }
+
+    /// Send block `i` of `data` (`count` elements each) to destination `i`
+    /// and gather a block from every source, in the order given by
+    /// `neighbors()`. Blocks for missing neighbors are left as default
+    /// values.
+    pub fn neighbor_alltoall<T: FlatBuffer + Copy + Default>(
+        &self,
+        data: &[T],
+        count: usize,
+    ) -> Result<Vec<T>> {
+        let neighbors = self.neighbors()?;
+        let outdegree = neighbors.destinations.len();
+        if data.len() != outdegree * count {
+            return Err(Error::LengthMismatch);
+        }
+        let blocks: Vec<&[T]> = (0..outdegree)
+            .map(|i| &data[i * count..(i + 1) * count])
+            .collect();
+        self.neighbor_exchange(&blocks, count)
+    }
+
+    /// Send `blocks[i]` to destination `i` and receive `count` elements from
+    /// each source.
+    fn neighbor_exchange<T: FlatBuffer + Copy + Default>(
+        &self,
+        blocks: &[&[T]],
+        count: usize,
+    ) -> Result<Vec<T>> {
+        let Neighbors {
+            sources,
+            destinations,
+        } = self.neighbors()?;
+        // With a Cartesian topology the same process can be a neighbor more
+        // than once (e.g. periodic dimensions of size 2), so the tag says
+        // which slot the message goes in. A message sent to the +1 neighbor
+        // is received from the -1 neighbor and vice versa. Graph messages
+        // just match in order.
+        let cart = matches!(self.topology.as_deref(), Some(Topology::Cart { .. }));
+        let recv_tag = |slot: usize| {
+            if cart {
+                NEIGHBOR_TAG | slot as Tag
+            } else {
+                NEIGHBOR_TAG
+            }
+        };
+        let send_tag = |slot: usize| recv_tag(if cart { slot ^ 1 } else { slot });
+
+        let mut out = vec![T::default(); sources.len() * count];
+        if count == 0 {
+            return Ok(out);
+        }
+        unsafe {
+            // Post all receives before sending to avoid deadlock. If a send
+            // fails, dropping the requests cancels the receives before `out`
+            // goes.
+            let iovs: Vec<[MutIov; 1]> = out
+                .chunks_mut(count)
+                .map(|block| [MutIov(block.ptr_mut(), block.size())])
+                .collect();
+            let mut reqs = vec![];
+            for (slot, (source, iov)) in sources.iter().zip(iovs.iter()).enumerate() {
+                if let Some(source) = source {
+                    reqs.push(self.irecv_internal(*source, recv_tag(slot), iov)?);
+                }
+            }
+            for (slot, (dest, block)) in destinations.iter().zip(blocks).enumerate() {
+                if let Some(dest) = dest {
+                    self.send_internal(*dest, send_tag(slot), block)?;
+                }
+            }
+            for req in reqs.iter_mut() {
+                while let RequestStatus::InProgress = req.progress()? {}
+            }
+            drop(reqs);
+        }
+        Ok(out)
+    }
+
+    /// Return the dimensions and periodicity of the Cartesian topology.
+    fn cart(&self) -> Result<(&[usize], &[bool])> {
+        match self.topology.as_deref() {
+            Some(Topology::Cart { dims, periodic }) => Ok((dims, periodic)),
+            _ => Err(Error::MissingTopology),
+        }
+    }
+}
+
+/// Return the coordinates of `rank` in a grid of `dims` processes.
+fn cart_coords(dims: &[usize], rank: usize) -> Vec<usize> {
+    let mut coords = vec![0; dims.len()];
+    let mut rem = rank;
+    for (coord, dim) in coords.iter_mut().zip(dims).rev() {
+        *coord = rem % dim;
+        rem /= dim;
+    }
+    coords
+}
+
+/// Return the rank at `coords` in a grid of `dims` processes, or `None` if
+/// they're out of range in a non-periodic dimension.
+fn cart_rank(dims: &[usize], periodic: &[bool], coords: &[isize]) -> Option<usize> {
+    let mut rank = 0;
+    for ((coord, dim), periodic) in coords.iter().zip(dims).zip(periodic) {
+        let dim = *dim as isize;
+        let coord = if *periodic {
+            coord.rem_euclid(dim)
+        } else if (0..dim).contains(coord) {
+            *coord
+        } else {
+            return None;
+        };
+        rank = rank * dim as usize + coord as usize;
+    }
+    Some(rank)
+}
+
+/// Return the ranks at `-disp` and `+disp` from `rank` along dimension
+/// `dim`.
+fn cart_shift(
+    dims: &[usize],
+    periodic: &[bool],
+    rank: usize,
+    dim: usize,
+    disp: isize,
+) -> (Option<usize>, Option<usize>) {
+    let mut coords: Vec<isize> = cart_coords(dims, rank)
+        .iter()
+        .map(|coord| *coord as isize)
+        .collect();
+    let coord = coords[dim];
+    coords[dim] = coord - disp;
+    let source = cart_rank(dims, periodic, &coords);
+    coords[dim] = coord + disp;
+    let dest = cart_rank(dims, periodic, &coords);
+    (source, dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coords_row_major() {
+        let dims = [2, 3];
+        let coords: Vec<Vec<usize>> = (0..6).map(|rank| cart_coords(&dims, rank)).collect();
+        assert_eq!(coords, [[0, 0], [0, 1], [0, 2], [1, 0], [1, 1], [1, 2]]);
+        assert_eq!(cart_coords(&[4], 3), [3]);
+    }
+
+    #[test]
+    fn rank_inverts_coords() {
+        let dims = [3, 2, 4];
+        let periodic = [false, true, false];
+        for rank in 0..24 {
+            let coords: Vec<isize> = cart_coords(&dims, rank)
+                .iter()
+                .map(|coord| *coord as isize)
+                .collect();
+            assert_eq!(cart_rank(&dims, &periodic, &coords), Some(rank));
+        }
+    }
+
+    #[test]
+    fn rank_past_edges() {
+        let dims = [2, 3];
+        // Periodic dimension wraps, the other one doesn't
+        let periodic = [false, true];
+        assert_eq!(cart_rank(&dims, &periodic, &[1, 3]), Some(3));
+        assert_eq!(cart_rank(&dims, &periodic, &[1, -1]), Some(5));
+        assert_eq!(cart_rank(&dims, &periodic, &[0, -7]), Some(2));
+        assert_eq!(cart_rank(&dims, &periodic, &[2, 0]), None);
+        assert_eq!(cart_rank(&dims, &periodic, &[-1, 0]), None);
+    }
+
+    #[test]
+    fn shift_non_periodic() {
+        let dims = [4];
+        let periodic = [false];
+        assert_eq!(cart_shift(&dims, &periodic, 0, 0, 1), (None, Some(1)));
+        assert_eq!(cart_shift(&dims, &periodic, 2, 0, 1), (Some(1), Some(3)));
+        assert_eq!(cart_shift(&dims, &periodic, 3, 0, 1), (Some(2), None));
+        assert_eq!(cart_shift(&dims, &periodic, 1, 0, 2), (None, Some(3)));
+        assert_eq!(cart_shift(&dims, &periodic, 1, 0, -1), (Some(2), Some(0)));
+    }
+
+    #[test]
+    fn shift_periodic() {
+        let dims = [4];
+        let periodic = [true];
+        assert_eq!(cart_shift(&dims, &periodic, 0, 0, 1), (Some(3), Some(1)));
+        assert_eq!(cart_shift(&dims, &periodic, 3, 0, 1), (Some(2), Some(0)));
+        assert_eq!(cart_shift(&dims, &periodic, 1, 0, 5), (Some(0), Some(2)));
+        // Both neighbors are the same process in a dimension of size 2
+        assert_eq!(cart_shift(&[2], &periodic, 0, 0, 1), (Some(1), Some(1)));
+    }
+
+    #[test]
+    fn shift_along_one_dimension() {
+        // Rank 4 is at (1, 1) in a 2x3 grid
+        let dims = [2, 3];
+        let periodic = [true, false];
+        assert_eq!(cart_shift(&dims, &periodic, 4, 0, 1), (Some(1), Some(1)));
+        assert_eq!(cart_shift(&dims, &periodic, 4, 1, 1), (Some(3), Some(5)));
+        assert_eq!(cart_shift(&dims, &periodic, 5, 1, 1), (Some(4), None));
+    }
+}
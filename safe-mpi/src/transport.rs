@@ -0,0 +1,309 @@
+//! Introspection of the UCX transports used to reach other processes.
+//!
+//! The transports and devices of an endpoint come from `ucp_ep_query()`.
+//! Which lane is used for what and the rendezvous thresholds aren't
+//! available in structured form, so they're parsed from the configuration
+//! that `ucp_ep_print_info()` prints, e.g.
+//!
+//! ```text
+//! #         lane[0]:  8:rc_mlx5/mlx5_0:1.0 md[4]  -> md[4]/ib/sysdev[255] rma_bw#0 am am_bw#0
+//! #        tag_send: 0..<egr/short>..227..<egr/bcopy>..263060..<rndv>..(inf)
+//! ```
+use crate::{communicator::Communicator, Error, Result, ThreadLevel};
+use nix::libc;
+use std::ffi::CStr;
+use std::fmt;
+use std::mem::size_of;
+use std::os::raw::c_char;
+use ucx2_sys::{
+    ucp_ep_attr_t, ucp_ep_h, ucp_ep_print_info, ucp_ep_query, ucp_transport_entry_t,
+    ucp_transports_t, ucp_worker_attr_t, ucp_worker_h, ucp_worker_query,
+    UCP_EP_ATTR_FIELD_TRANSPORTS, UCP_WORKER_ATTR_FIELD_NAME, UCP_WORKER_ATTR_FIELD_THREAD_MODE,
+    UCS_OK,
+};
+
+/// Maximum number of transports to ask `ucp_ep_query()` for, at least the
+/// number of lanes UCX supports
+const MAX_TRANSPORTS: usize = 16;
+
+/// Transports connecting this process to a peer.
+#[derive(Debug, Clone, Default)]
+pub struct TransportInfo {
+    /// Name of the local worker
+    pub worker: String,
+    /// Thread level the worker was created with
+    pub thread_level: ThreadLevel,
+    /// Transports of the endpoint, with the devices they use
+    pub transports: Vec<Transport>,
+    /// Lanes of the endpoint, in lane order
+    pub lanes: Vec<Lane>,
+    /// Message size from which each send operation, e.g. `tag_send`, uses
+    /// the rendezvous protocol
+    pub rndv_thresholds: Vec<(String, usize)>,
+}
+
+/// Transport used by an endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transport {
+    /// Transport name, e.g. `rc_mlx5`, `tcp` or `posix`
+    pub name: String,
+    /// Device name, e.g. `mlx5_0:1` or `memory`
+    pub device: String,
+}
+
+/// Lane of an endpoint, a transport used for some of the operations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lane {
+    /// Transport and device of the lane
+    pub transport: Transport,
+    /// Operations using the lane, e.g. `am`, `rma_bw#0` or `rkey_ptr`
+    pub usage: Vec<String>,
+}
+
+impl fmt::Display for TransportInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "worker: {} ({:?})", self.worker, self.thread_level)?;
+        for transport in &self.transports {
+            writeln!(f, "transport: {}", transport)?;
+        }
+        for (i, lane) in self.lanes.iter().enumerate() {
+            writeln!(
+                f,
+                "lane[{}]: {} {}",
+                i,
+                lane.transport,
+                lane.usage.join(" ")
+            )?;
+        }
+        for (op, threshold) in &self.rndv_thresholds {
+            writeln!(f, "rndv threshold {}: {}", op, threshold)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.name, self.device)
+    }
+}
+
+impl Communicator {
+    /// Return the transports used to reach `peer`, creating the endpoint to
+    /// it if there isn't one yet.
+    pub fn transport_info(&self, peer: usize) -> Result<TransportInfo> {
+        let peer = self.peer_world_rank(peer)?;
+        let (worker, ep) = {
+            let mut handle = self.handle.borrow_mut();
+            (handle.worker.as_raw(), handle.endpoint(peer)?)
+        };
+        unsafe {
+            let (worker, thread_level) = query_worker(worker)?;
+            let (lanes, rndv_thresholds) = parse_ep_info(&print_ep_info(ep)?);
+            Ok(TransportInfo {
+                worker,
+                thread_level,
+                transports: query_transports(ep)?,
+                lanes,
+                rndv_thresholds,
+            })
+        }
+    }
+}
+
+/// Return the name and thread level of `worker`.
+unsafe fn query_worker(worker: ucp_worker_h) -> Result<(String, ThreadLevel)> {
+    let mut attr = ucp_worker_attr_t {
+        field_mask: (UCP_WORKER_ATTR_FIELD_NAME | UCP_WORKER_ATTR_FIELD_THREAD_MODE).into(),
+        ..Default::default()
+    };
+    let status = ucp_worker_query(worker, &mut attr);
+    if status != UCS_OK {
+        return Err(Error::QueryFailure(status));
+    }
+    Ok((
+        to_string(attr.name.as_ptr()),
+        ThreadLevel::from_thread_mode(attr.thread_mode),
+    ))
+}
+
+/// Return the transports of `ep`.
+unsafe fn query_transports(ep: ucp_ep_h) -> Result<Vec<Transport>> {
+    let mut entries = [ucp_transport_entry_t::default(); MAX_TRANSPORTS];
+    let mut attr = ucp_ep_attr_t {
+        field_mask: UCP_EP_ATTR_FIELD_TRANSPORTS.into(),
+        transports: ucp_transports_t {
+            entries: entries.as_mut_ptr(),
+            num_entries: MAX_TRANSPORTS as u32,
+            entry_size: size_of::<ucp_transport_entry_t>(),
+        },
+        ..Default::default()
+    };
+    let status = ucp_ep_query(ep, &mut attr);
+    if status != UCS_OK {
+        return Err(Error::QueryFailure(status));
+    }
+    // UCX sets the number of entries it actually filled in
+    let count = (attr.transports.num_entries as usize).min(MAX_TRANSPORTS);
+    Ok(entries[..count]
+        .iter()
+        .map(|entry| Transport {
+            name: to_string(entry.transport_name),
+            device: to_string(entry.device_name),
+        })
+        .collect())
+}
+
+/// Return what `ucp_ep_print_info()` prints for `ep`.
+unsafe fn print_ep_info(ep: ucp_ep_h) -> Result<String> {
+    let mut buf: *mut c_char = std::ptr::null_mut();
+    let mut len = 0;
+    let stream = libc::open_memstream(&mut buf, &mut len);
+    if stream.is_null() {
+        return Err(Error::InternalError);
+    }
+    ucp_ep_print_info(ep, stream as *mut _);
+    // Closing the stream finishes the buffer
+    libc::fclose(stream);
+    if buf.is_null() {
+        return Err(Error::InternalError);
+    }
+    let info = String::from_utf8_lossy(std::slice::from_raw_parts(buf as *const u8, len)).into();
+    libc::free(buf as *mut _);
+    Ok(info)
+}
+
+/// Parse the lanes and the rendezvous thresholds out of the endpoint
+/// configuration.
+fn parse_ep_info(info: &str) -> (Vec<Lane>, Vec<(String, usize)>) {
+    let mut lanes = vec![];
+    let mut rndv_thresholds = vec![];
+    for line in info.lines() {
+        let line = line.trim_start_matches('#').trim();
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        if key.starts_with("lane[") {
+            if let Some(lane) = parse_lane(value) {
+                lanes.push(lane);
+            }
+        } else if let Some(threshold) = parse_rndv_threshold(value) {
+            rndv_thresholds.push((key.to_string(), threshold));
+        }
+    }
+    (lanes, rndv_thresholds)
+}
+
+/// Parse the description of a lane, `<index>:<transport>/<device> md[..]
+/// -> <remote> <usage>...`, where the remote side may be missing.
+fn parse_lane(value: &str) -> Option<Lane> {
+    let tokens: Vec<&str> = value.split_whitespace().collect();
+    let resource = tokens.first()?;
+    // Skip the resource index
+    let resource = match resource.split_once(':') {
+        Some((index, rest)) if index.chars().all(|c| c.is_ascii_digit()) => rest,
+        _ => resource,
+    };
+    let (name, device) = resource.split_once('/')?;
+    // Usage follows the remote side of the lane, or the memory domain if
+    // there's no remote side
+    let usage = match tokens.iter().position(|token| *token == "->") {
+        Some(arrow) => tokens.get(arrow + 2..).unwrap_or(&[]),
+        None => match tokens.iter().position(|token| token.starts_with("md[")) {
+            Some(md) => &tokens[md + 1..],
+            None => &tokens[1..],
+        },
+    };
+    Some(Lane {
+        transport: Transport {
+            name: name.to_string(),
+            device: device.to_string(),
+        },
+        usage: usage.iter().map(|token| token.to_string()).collect(),
+    })
+}
+
+/// Parse the size from which a protocol range like
+/// `0..<egr/short>..227..<egr/bcopy>..263060..<rndv>..(inf)` uses
+/// rendezvous.
+fn parse_rndv_threshold(value: &str) -> Option<usize> {
+    let parts: Vec<&str> = value.split("..").collect();
+    let rndv = parts.iter().position(|part| *part == "<rndv>")?;
+    parts.get(rndv.checked_sub(1)?)?.parse().ok()
+}
+
+/// Copy a C string from UCX, which may be null.
+unsafe fn to_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(ptr).to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LANE: &str = "#         lane[0]:  8:rc_mlx5/mlx5_0:1.0 md[4]  -> md[4]/ib/sysdev[255] \
+                        rma_bw#0 am am_bw#0";
+    const TAG_SEND: &str =
+        "#        tag_send: 0..<egr/short>..227..<egr/bcopy>..263060..<rndv>..(inf)";
+
+    fn transport(name: &str, device: &str) -> Transport {
+        Transport {
+            name: name.to_string(),
+            device: device.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_sample() {
+        let info = format!("#\n{}\n{}\n#\n", LANE, TAG_SEND);
+        let (lanes, rndv_thresholds) = parse_ep_info(&info);
+        assert_eq!(
+            lanes,
+            [Lane {
+                transport: transport("rc_mlx5", "mlx5_0:1.0"),
+                usage: vec!["rma_bw#0".into(), "am".into(), "am_bw#0".into()],
+            }]
+        );
+        assert_eq!(rndv_thresholds, [("tag_send".to_string(), 263060)]);
+    }
+
+    #[test]
+    fn lane_without_resource_index() {
+        let lane = parse_lane("tcp/eth0 md[1] -> md[1]/tcp am").unwrap();
+        assert_eq!(lane.transport, transport("tcp", "eth0"));
+        assert_eq!(lane.usage, ["am"]);
+    }
+
+    #[test]
+    fn lane_without_arrow() {
+        let lane = parse_lane("2:posix/memory md[0] rkey_ptr").unwrap();
+        assert_eq!(lane.transport, transport("posix", "memory"));
+        assert_eq!(lane.usage, ["rkey_ptr"]);
+        let lane = parse_lane("2:posix/memory rkey_ptr").unwrap();
+        assert_eq!(lane.usage, ["rkey_ptr"]);
+    }
+
+    #[test]
+    fn lane_without_transport() {
+        assert_eq!(parse_lane(""), None);
+        assert_eq!(parse_lane("8:rc_mlx5 md[4]"), None);
+    }
+
+    #[test]
+    fn range_without_rndv() {
+        assert_eq!(
+            parse_rndv_threshold("0..<egr/short>..227..<egr/bcopy>..(inf)"),
+            None
+        );
+        assert_eq!(parse_rndv_threshold("0..<rndv>..(inf)"), Some(0));
+        assert_eq!(parse_rndv_threshold("<rndv>..(inf)"), None);
+        let (lanes, rndv_thresholds) = parse_ep_info("#   am_send: 0..<egr/short>..(inf)\n");
+        assert!(lanes.is_empty());
+        assert!(rndv_thresholds.is_empty());
+    }
+}
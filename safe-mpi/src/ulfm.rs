@@ -0,0 +1,356 @@
+//! Fault tolerance following the MPI ULFM proposal.
+//!
+//! After a process has failed, the survivors `revoke()` the communicator so
+//! that everyone stops using it, `agree()` on whether to continue and
+//! `shrink()` it to a new communicator without the failed processes. Unlike
+//! everything else, `agree()` and `shrink()` work on revoked communicators
+//! and keep going when processes fail during the call.
+//!
+//! The agreement uses a coordinator, the lowest rank not known to have
+//! failed. Every process sends its contribution to the coordinator, which
+//! combines them and sends out the decision, and every process passes the
+//! decision on to all others before returning. If the coordinator fails the
+//! others move on to the next one, which adopts a decision it receives from
+//! anyone instead of making its own. This takes O(n²) messages, so it's meant
+//! for recovery and not for the fast path.
+//!
+//! Passing decisions on leaves duplicates behind, and the tags only have room
+//! for the low bits of the sequence number of an agreement. Every message
+//! therefore starts with the full sequence number, anything left over from an
+//! earlier agreement is dropped on receive, and the tags of the last few
+//! agreements are drained at the start of each one.
+use crate::{
+    callbacks::revoke_recv_callback,
+    communicator::{first_free_context, Communicator},
+    failure::{PeerState, Watch},
+    request::{wait_nbx, RecvProbeRequest, Request, RequestStatus, SendIovRequest},
+    tag,
+    util::to_u64s,
+    Error, Iov, Result, Tag,
+};
+use flat::FlatBuffer;
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use ucx2_sys::{
+    ucp_am_handler_param_t, ucp_am_send_nbx, ucp_worker_h, ucp_worker_set_am_recv_handler,
+    UCP_AM_HANDLER_PARAM_FIELD_ARG, UCP_AM_HANDLER_PARAM_FIELD_CB, UCP_AM_HANDLER_PARAM_FIELD_ID,
+    UCS_OK,
+};
+
+/// UCP active message ID of revoke messages
+const REVOKE_AM_ID: u32 = 1;
+/// Internal tag of agreement messages, with the sequence number of the
+/// agreement above the lowest bit, which is set for decisions
+const AGREE_TAG: Tag = 1 << 26;
+/// Mask for the sequence number of an agreement
+const SEQ_MASK: Tag = (1 << 24) - 1;
+/// Number of earlier agreements whose tags are drained of duplicates
+const DRAIN_WINDOW: u64 = 8;
+
+/// Register the UCP handler for revoke messages on `worker`. `peers` has to
+/// outlive the worker.
+pub(crate) unsafe fn register(worker: ucp_worker_h, peers: &Rc<PeerState>) -> Result<()> {
+    let params = ucp_am_handler_param_t {
+        field_mask: (UCP_AM_HANDLER_PARAM_FIELD_ID
+            | UCP_AM_HANDLER_PARAM_FIELD_CB
+            | UCP_AM_HANDLER_PARAM_FIELD_ARG)
+            .into(),
+        id: REVOKE_AM_ID,
+        cb: Some(revoke_recv_callback),
+        arg: Rc::as_ptr(peers) as *mut c_void,
+        ..Default::default()
+    };
+    let status = ucp_worker_set_am_recv_handler(worker, &params);
+    if status != UCS_OK {
+        return Err(Error::AmHandlerFailure(status));
+    }
+    Ok(())
+}
+
+impl Communicator {
+    /// Revoke the communicator on all processes. Pending and new operations
+    /// on it then fail with `Error::Revoked`, except for `agree()` and
+    /// `shrink()`. Processes that have failed are skipped. This isn't
+    /// collective, any process can call it.
+    pub fn revoke(&self) -> Result<()> {
+        let context = self.context_id();
+        let peers = Rc::clone(&self.handle.borrow().peers);
+        peers.revoke(context);
+        let me = self.group[self.rank];
+        let members = self
+            .group
+            .iter()
+            .chain(self.remote.iter().flat_map(|remote| remote.iter()));
+        for rank in members.copied().filter(|rank| *rank != me) {
+            if peers.is_failed(rank) {
+                continue;
+            }
+            match self.send_revoke(rank, context) {
+                Ok(()) | Err(Error::PeerFailed(_)) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Return true if the communicator has been revoked, by this or any
+    /// other process.
+    pub fn is_revoked(&self) -> bool {
+        self.handle.borrow().peers.is_revoked(self.context_id())
+    }
+
+    /// Return the logical and of `flag` over all processes that haven't
+    /// failed. All surviving processes get the same result, even if
+    /// processes fail during the call. This is collective over all processes
+    /// in the communicator that are still alive.
+    pub fn agree(&self, flag: bool) -> Result<bool> {
+        let (_, result) = self.ft_agree(&[u64::from(flag)])?;
+        Ok(result[0] != 0)
+    }
+
+    /// Return a new communicator with only the processes that haven't
+    /// failed, keeping their order. This is collective over all processes in
+    /// the communicator that are still alive.
+    pub fn shrink(&self) -> Result<Communicator> {
+        let free = self.handle.borrow().free_contexts.clone();
+        let (alive, free) = self.ft_agree(&free)?;
+        let id = first_free_context(&free)?;
+        self.claim_context(id);
+        let rank = alive
+            .iter()
+            .position(|rank| *rank == self.rank)
+            .ok_or(Error::InternalError)?;
+        let group = alive.iter().map(|rank| self.group[*rank]).collect();
+        Ok(self.with_group(id, Rc::new(group), rank))
+    }
+
+    /// Return `Error::Revoked` if the communicator has been revoked.
+    pub(crate) fn check_revoked(&self) -> Result<()> {
+        if self.is_revoked() {
+            Err(Error::Revoked)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send a revoke message for `context` to the process with world rank
+    /// `rank`.
+    fn send_revoke(&self, rank: usize, context: Tag) -> Result<()> {
+        let (worker, ep) = {
+            let mut handle = self.handle.borrow_mut();
+            (Rc::clone(&handle.worker), handle.endpoint(rank)?)
+        };
+        unsafe {
+            wait_nbx(&worker, |param| {
+                ucp_am_send_nbx(
+                    ep,
+                    REVOKE_AM_ID,
+                    &context as *const Tag as *const _,
+                    size_of::<Tag>(),
+                    std::ptr::null(),
+                    0,
+                    param,
+                )
+            })
+        }
+        .map_err(|err| self.handle.borrow().peer_error(rank, err))
+    }
+
+    /// Agree on the processes that are still alive and the bitwise and of
+    /// `payload` over them. Returns the ranks of the processes and the
+    /// combined payload.
+    fn ft_agree(&self, payload: &[u64]) -> Result<(Vec<usize>, Vec<u64>)> {
+        if self.is_inter() {
+            return Err(Error::InterCommunicator);
+        }
+        let seq = self.handle.borrow().peers.next_agreement(self.context_id());
+        for earlier in seq.saturating_sub(DRAIN_WINDOW)..seq {
+            let tag = agree_tag(earlier);
+            self.drain(tag)?;
+            self.drain(tag | 1)?;
+        }
+        let contrib_tag = agree_tag(seq);
+        let decision_tag = contrib_tag | 1;
+        let size = self.size();
+        let words = size.div_ceil(64);
+
+        // Contributions and decisions are the sequence number, a bitmap of the
+        // processes taking part and the payload
+        let mut local = vec![0u64; 1 + words];
+        local[0] = seq;
+        for rank in (0..size).filter(|rank| !self.has_failed(*rank)) {
+            local[1 + rank / 64] |= 1 << (rank % 64);
+        }
+        local.extend_from_slice(payload);
+
+        let mut decision = self.ft_irecv(None, decision_tag);
+        let mut coordinator = None;
+        let (source, result) = loop {
+            let current = (0..size)
+                .find(|rank| !self.has_failed(*rank))
+                .ok_or(Error::InternalError)?;
+            if current == self.rank {
+                break self.coordinate(contrib_tag, local, &mut decision)?;
+            }
+            if coordinator != Some(current) {
+                coordinator = Some(current);
+                match self.ft_send(current, contrib_tag, &local) {
+                    Ok(()) | Err(Error::PeerFailed(_)) => (),
+                    Err(err) => return Err(err),
+                }
+            }
+            if let Some(data) = self.poll_decision(seq, decision_tag, &mut decision)? {
+                break (decision.source(), data);
+            }
+        };
+        if result.len() != 1 + words + payload.len() {
+            return Err(Error::MessageCountMismatch);
+        }
+
+        // Pass the decision on, in case whoever sent it fails before everyone
+        // has it
+        let alive: Vec<usize> = (0..size)
+            .filter(|rank| result[1 + rank / 64] & (1 << (rank % 64)) != 0)
+            .collect();
+        for rank in alive.iter().copied() {
+            if rank == self.rank || Some(rank) == source || self.has_failed(rank) {
+                continue;
+            }
+            match self.ft_send(rank, decision_tag, &result) {
+                Ok(()) | Err(Error::PeerFailed(_)) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok((alive, result[1 + words..].to_vec()))
+    }
+
+    /// Collect the contributions of all other processes and combine them
+    /// with `local`. If a decision arrives in the meantime, because a
+    /// previous coordinator got to send some before failing, that's
+    /// returned along with its sender instead. `acc` starts with the
+    /// sequence number, which contributions have to match.
+    fn coordinate(
+        &self,
+        tag: Tag,
+        mut acc: Vec<u64>,
+        decision: &mut RecvProbeRequest,
+    ) -> Result<(Option<usize>, Vec<u64>)> {
+        let seq = acc[0];
+        let mut pending = vec![];
+        for rank in 0..self.size() {
+            if rank == self.rank {
+                continue;
+            }
+            if self.has_failed(rank) {
+                acc[1 + rank / 64] &= !(1 << (rank % 64));
+            } else {
+                pending.push((rank, self.ft_irecv(Some(rank), tag)));
+            }
+        }
+        while !pending.is_empty() {
+            if let Some(data) = self.poll_decision(seq, tag | 1, decision)? {
+                return Ok((decision.source(), data));
+            }
+            let mut i = 0;
+            while i < pending.len() {
+                let (rank, req) = &mut pending[i];
+                match unsafe { req.progress() } {
+                    Ok(RequestStatus::InProgress) => i += 1,
+                    Ok(RequestStatus::Complete) => {
+                        let data = to_u64s(&req.data().ok_or(Error::InternalError)?);
+                        if data.first() != Some(&seq) {
+                            // Left over from an earlier agreement
+                            *req = self.ft_irecv(Some(*rank), tag);
+                            continue;
+                        }
+                        for (a, b) in acc.iter_mut().zip(data) {
+                            *a &= b;
+                        }
+                        pending.swap_remove(i);
+                    }
+                    Err(Error::PeerFailed(_)) => {
+                        acc[1 + *rank / 64] &= !(1 << (*rank % 64));
+                        pending.swap_remove(i);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok((None, acc))
+    }
+
+    /// Progress the receive of a decision for agreement `seq`. Decisions left
+    /// over from an earlier agreement with the same tag are dropped and the
+    /// receive is started again.
+    fn poll_decision(
+        &self,
+        seq: u64,
+        tag: Tag,
+        decision: &mut RecvProbeRequest,
+    ) -> Result<Option<Vec<u64>>> {
+        if let RequestStatus::Complete = unsafe { decision.progress()? } {
+            let data = to_u64s(&decision.data().ok_or(Error::InternalError)?);
+            if data.first() == Some(&seq) {
+                return Ok(Some(data));
+            }
+            *decision = self.ft_irecv(None, tag);
+        }
+        Ok(None)
+    }
+
+    /// Receive and drop everything that has already arrived with agreement
+    /// tag `tag`, such as decisions passed on by several processes.
+    fn drain(&self, tag: Tag) -> Result<()> {
+        loop {
+            let mut req = self.ft_irecv(None, tag);
+            unsafe {
+                req.progress()?;
+                if req.source().is_none() {
+                    return Ok(());
+                }
+                while let RequestStatus::InProgress = req.progress()? {}
+            }
+        }
+    }
+
+    /// Return true if the process with rank `rank` is known to have failed.
+    fn has_failed(&self, rank: usize) -> bool {
+        self.handle.borrow().peers.is_failed(self.group[rank])
+    }
+
+    /// Blocking send of agreement data, which works on revoked
+    /// communicators.
+    fn ft_send(&self, dest: usize, tag: Tag, data: &[u64]) -> Result<()> {
+        let tag = tag::internal(self.context_id(), self.rank, tag);
+        unsafe {
+            let iov = [Iov(data.ptr(), data.size())];
+            let mut req =
+                SendIovRequest::new(Rc::clone(&self.handle), self.group[dest], &iov, tag)?;
+            while let RequestStatus::InProgress = req.progress()? {}
+        }
+        Ok(())
+    }
+
+    /// Start a receive of agreement data, which works on revoked
+    /// communicators. Receives from a single source fail once it has.
+    fn ft_irecv(&self, source: Option<usize>, tag: Tag) -> RecvProbeRequest {
+        let tag = tag::internal(self.context_id(), source.unwrap_or(0), tag);
+        let watch = Watch {
+            peer: source.map(|source| self.group[source]),
+            context: None,
+        };
+        RecvProbeRequest::new(
+            Rc::clone(&self.handle),
+            tag,
+            tag::mask(source.is_some()),
+            watch,
+        )
+    }
+}
+
+/// Return the tag of contributions to agreement `seq`, the tag of its
+/// decision has the lowest bit set.
+fn agree_tag(seq: u64) -> Tag {
+    AGREE_TAG | ((seq & SEQ_MASK) << 1)
+}
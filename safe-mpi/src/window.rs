@@ -0,0 +1,340 @@
+//! One-sided communication through RMA windows.
+//!
+//! Every process registers a block of memory with `ucp_mem_map()` and the
+//! packed remote keys are exchanged so that any process can then `put()` into
+//! or `get()` from any other process's block. Accesses are given in elements
+//! and checked against the size of the target's window, so a bad offset is an
+//! error instead of a write to some random remote address.
+use crate::{
+    communicator::Communicator,
+    request::{wait_nbx, AtomicRequest},
+    Error, Features, Handle, Request, RequestStatus, Result,
+};
+use flat::FlatBuffer;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::mem::{size_of, size_of_val};
+use std::rc::Rc;
+use ucx2_sys::{
+    ucp::{MemHandle, RemoteKey, UcpContext, Worker},
+    ucp_atomic_op_t, ucp_ep_flush_nbx, ucp_ep_h, ucp_get_nbx, ucp_mem_map_params_t, ucp_put_nbx,
+    ucp_rkey_h, ucp_worker_flush_nbx, UCP_ATOMIC_OP_ADD, UCP_ATOMIC_OP_CSWAP,
+    UCP_MEM_MAP_PARAM_FIELD_ADDRESS, UCP_MEM_MAP_PARAM_FIELD_LENGTH,
+};
+
+/// Offset of the elements in the window memory, which starts with the lock
+/// word
+const DATA_OFFSET: u64 = size_of::<u64>() as u64;
+/// Set in the lock word while an exclusive lock is held, the rest of the
+/// word counts shared locks
+const EXCLUSIVE: u64 = 1 << 63;
+
+/// Kind of lock for passive target synchronization
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LockType {
+    /// No other process can hold a lock on the target at the same time
+    Exclusive,
+    /// Other processes can hold shared locks on the target at the same time
+    Shared,
+}
+
+/// Window memory of one process
+struct Remote {
+    /// Address of the start of the window memory (the lock word)
+    addr: u64,
+    /// Number of elements
+    len: usize,
+    rkey: RemoteKey,
+}
+
+/// Memory exposed for one-sided access by all processes in a communicator.
+///
+/// Remote accesses are only guaranteed to have completed after `flush()`,
+/// `fence()` or `unlock()`. A window should be released with `free()`, so
+/// that other processes are done with it before its memory is unregistered.
+pub struct Window<T> {
+    // Fields are dropped in order: the keys and the registration go before
+    // the memory, and the endpoints they were used with last
+    /// Window memory of every process, indexed by rank
+    remotes: Vec<Remote>,
+    memh: MemHandle,
+    /// Backing memory: the lock word followed by the elements
+    memory: Vec<u64>,
+    /// Number of local elements
+    len: usize,
+    /// Locks held by this process, indexed by target rank
+    locks: Vec<Option<LockType>>,
+    marker: PhantomData<T>,
+    comm: Communicator,
+}
+
+impl<T: FlatBuffer + Copy + Default> Window<T> {
+    /// Allocate a window of `len` elements, initialized to the default value,
+    /// on every process. The length can differ between processes. Types
+    /// aligned to more than 8 bytes give `UnsupportedType`, and a context
+    /// without the `RMA` and `AMO64` features gives `MissingFeature`. This is
+    /// collective over all processes in the communicator.
+    pub fn allocate(comm: &Communicator, len: usize) -> Result<Window<T>> {
+        // Locks are 64-bit atomics on the lock word
+        comm.handle
+            .borrow()
+            .check_features(Features::RMA | Features::AMO64)?;
+        // The u64 backing memory has to be aligned enough for the elements
+        if std::mem::align_of::<T>() > std::mem::align_of::<u64>() {
+            return Err(Error::UnsupportedType);
+        }
+        let words = 1 + (len * size_of::<T>()).div_ceil(size_of::<u64>());
+        let context = Rc::clone(&comm.handle.borrow().context);
+        let mut memory = vec![0u64; words];
+        unsafe {
+            let data = (memory.as_mut_ptr() as *mut u8).add(DATA_OFFSET as usize) as *mut T;
+            for i in 0..len {
+                data.add(i).write(T::default());
+            }
+        }
+        let memh = unsafe { mem_map(&context, &mut memory)? };
+        let mut win = Window {
+            remotes: vec![],
+            memh,
+            memory,
+            len,
+            locks: vec![None; comm.size()],
+            marker: PhantomData,
+            comm: comm.clone(),
+        };
+
+        // Exchange addresses, lengths and keys
+        let mut rkey = win.memh.pack_rkey().map_err(Error::RemoteKeyFailure)?;
+        let info = comm.allgather(&[win.memory.as_ptr() as u64, len as u64, rkey.len() as u64])?;
+        let max = info.chunks(3).map(|info| info[2]).max().unwrap_or(0) as usize;
+        rkey.resize(max, 0);
+        let rkeys = comm.allgather(&rkey)?;
+        for (rank, info) in info.chunks(3).enumerate() {
+            // Every process contributed the key it packed itself
+            let rkey = unsafe { win.unpack_rkey(rank, &rkeys[rank * max..])? };
+            win.remotes.push(Remote {
+                addr: info[0],
+                len: info[1] as usize,
+                rkey,
+            });
+        }
+        Ok(win)
+    }
+
+    /// Number of local elements
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the local window is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Local elements of the window
+    pub fn local(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.data_ptr(), self.len) }
+    }
+
+    /// Mutable local elements of the window
+    pub fn local_mut(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.data_ptr() as *mut T, self.len) }
+    }
+
+    /// Write `data` to the window of `target`, starting at element `offset`.
+    /// This returns once `data` can be reused; use `flush()` to wait for it
+    /// to arrive.
+    pub fn put(&self, target: usize, offset: usize, data: &[T]) -> Result<()> {
+        let (ep, addr, rkey) = self.target(target, offset, data.len())?;
+        unsafe {
+            wait_nbx(&self.worker(), |param| {
+                ucp_put_nbx(
+                    ep,
+                    data.as_ptr() as *const _,
+                    size_of_val(data),
+                    addr,
+                    rkey,
+                    param,
+                )
+            })
+        }
+    }
+
+    /// Read elements from the window of `target`, starting at element
+    /// `offset`, into `buf`.
+    pub fn get(&self, target: usize, offset: usize, buf: &mut [T]) -> Result<()> {
+        let (ep, addr, rkey) = self.target(target, offset, buf.len())?;
+        unsafe {
+            wait_nbx(&self.worker(), |param| {
+                ucp_get_nbx(
+                    ep,
+                    buf.as_mut_ptr() as *mut _,
+                    size_of_val(buf),
+                    addr,
+                    rkey,
+                    param,
+                )
+            })
+        }
+    }
+
+    /// Wait for all operations on `target` to complete remotely.
+    pub fn flush(&self, target: usize) -> Result<()> {
+        let ep = self.endpoint(target)?;
+        unsafe { wait_nbx(&self.worker(), |param| ucp_ep_flush_nbx(ep, param)) }
+    }
+
+    /// Wait for all operations from this process to complete remotely.
+    pub fn flush_all(&self) -> Result<()> {
+        let worker = self.worker();
+        unsafe { wait_nbx(&worker, |param| ucp_worker_flush_nbx(worker.as_raw(), param)) }
+    }
+
+    /// Complete all operations and synchronize with all other processes, so
+    /// that every access before the fence is visible everywhere after it.
+    /// This is collective over all processes in the communicator.
+    pub fn fence(&self) -> Result<()> {
+        self.flush_all()?;
+        self.comm.barrier()
+    }
+
+    /// Lock the window of `target`, blocking until the lock is available.
+    pub fn lock(&mut self, target: usize, lock_type: LockType) -> Result<()> {
+        if self
+            .locks
+            .get(target)
+            .ok_or(Error::InvalidRank(target))?
+            .is_some()
+        {
+            return Err(Error::LockState);
+        }
+        match lock_type {
+            LockType::Exclusive => {
+                while self.lock_op(target, UCP_ATOMIC_OP_CSWAP, 0, EXCLUSIVE)? != 0 {}
+            }
+            LockType::Shared => {
+                while self.lock_op(target, UCP_ATOMIC_OP_ADD, 1, 0)? & EXCLUSIVE != 0 {
+                    // Back off until the exclusive lock is released
+                    self.lock_op(target, UCP_ATOMIC_OP_ADD, 1u64.wrapping_neg(), 0)?;
+                }
+            }
+        }
+        self.locks[target] = Some(lock_type);
+        Ok(())
+    }
+
+    /// Complete all operations on `target` and release the lock on it.
+    pub fn unlock(&mut self, target: usize) -> Result<()> {
+        let lock_type = self
+            .locks
+            .get(target)
+            .ok_or(Error::InvalidRank(target))?
+            .ok_or(Error::LockState)?;
+        self.flush(target)?;
+        let held = match lock_type {
+            LockType::Exclusive => EXCLUSIVE,
+            LockType::Shared => 1,
+        };
+        self.lock_op(target, UCP_ATOMIC_OP_ADD, held.wrapping_neg(), 0)?;
+        self.locks[target] = None;
+        Ok(())
+    }
+
+    /// Complete all operations and release the window. This is collective
+    /// over all processes in the communicator.
+    pub fn free(self) -> Result<()> {
+        self.fence()
+    }
+
+    /// Return the endpoint, address and key for accessing `count` elements
+    /// at `offset` in the window of `target`.
+    pub(crate) fn target(
+        &self,
+        target: usize,
+        offset: usize,
+        count: usize,
+    ) -> Result<(ucp_ep_h, u64, ucp_rkey_h)> {
+        let remote = self.remotes.get(target).ok_or(Error::InvalidRank(target))?;
+        match offset.checked_add(count) {
+            Some(end) if end <= remote.len => (),
+            _ => return Err(Error::OutOfBounds),
+        }
+        let addr = remote.addr + DATA_OFFSET + (offset * size_of::<T>()) as u64;
+        Ok((self.endpoint(target)?, addr, remote.rkey.as_raw()))
+    }
+
+    /// Run an atomic operation on the lock word of `target`, returning the
+    /// previous value.
+    fn lock_op(
+        &self,
+        target: usize,
+        opcode: ucp_atomic_op_t,
+        value: u64,
+        reply: u64,
+    ) -> Result<u64> {
+        let remote = self.remotes.get(target).ok_or(Error::InvalidRank(target))?;
+        let ep = self.endpoint(target)?;
+        unsafe {
+            let mut req = AtomicRequest::new(
+                Rc::clone(&self.comm.handle),
+                ep,
+                opcode,
+                value,
+                reply,
+                remote.addr,
+                remote.rkey.as_raw(),
+            );
+            while let RequestStatus::InProgress = req.progress()? {}
+            req.value().ok_or(Error::InternalError)
+        }
+    }
+}
+
+impl<T> Window<T> {
+    pub(crate) fn handle(&self) -> Rc<RefCell<Handle>> {
+        Rc::clone(&self.comm.handle)
+    }
+
+    fn data_ptr(&self) -> *const T {
+        unsafe { (self.memory.as_ptr() as *const u8).add(DATA_OFFSET as usize) as *const T }
+    }
+
+    fn worker(&self) -> Rc<Worker> {
+        Rc::clone(&self.comm.handle.borrow().worker)
+    }
+
+    /// Return the endpoint the key of `rank` was unpacked for, which stays
+    /// open as long as the window, even after `Context::finalize()`.
+    fn endpoint(&self, rank: usize) -> Result<ucp_ep_h> {
+        let remote = self.remotes.get(rank).ok_or(Error::InvalidRank(rank))?;
+        let world = self.comm.peer_world_rank(rank)?;
+        let handle = self.comm.handle.borrow();
+        handle.peers.check_aborted()?;
+        if handle.peers.is_failed(world) {
+            return Err(Error::PeerFailed(world));
+        }
+        Ok(remote.rkey.endpoint().as_raw())
+    }
+
+    /// Unpack the remote key of `rank` for use with the endpoint to it.
+    ///
+    /// # Safety
+    ///
+    /// `rkey` has to be a key packed by `rank`.
+    unsafe fn unpack_rkey(&self, rank: usize, rkey: &[u8]) -> Result<RemoteKey> {
+        let rank = self.comm.peer_world_rank(rank)?;
+        let mut handle = self.comm.handle.borrow_mut();
+        RemoteKey::unpack(handle.connect(rank)?, rkey).map_err(Error::RemoteKeyFailure)
+    }
+}
+
+/// Register `memory` with UCP.
+unsafe fn mem_map(context: &Rc<UcpContext>, memory: &mut [u64]) -> Result<MemHandle> {
+    let params = ucp_mem_map_params_t {
+        field_mask: (UCP_MEM_MAP_PARAM_FIELD_ADDRESS | UCP_MEM_MAP_PARAM_FIELD_LENGTH).into(),
+        address: memory.as_mut_ptr() as *mut _,
+        length: size_of_val(memory),
+        ..Default::default()
+    };
+    MemHandle::map(context, &params).map_err(Error::MemMapFailure)
+}
@@ -0,0 +1,508 @@
+//! Owned wrappers around the UCP handles.
+//!
+//! Every type releases its handle on drop and keeps what it was created from
+//! alive for as long as it needs it: a worker holds on to its context, an
+//! endpoint or a listener to its worker and a memory handle to its context.
+//! Statuses are returned as `Result`s with the failed `ucs_status_t` as the
+//! error. The raw handles stay available through `as_raw()` for everything
+//! that isn't wrapped here.
+use crate::{
+    rust_ucp_dt_make_contig, rust_ucp_init, rust_ucs_ptr_is_err, rust_ucs_ptr_is_ptr,
+    rust_ucs_ptr_status, ucp_cleanup, ucp_config_modify, ucp_config_read, ucp_config_release,
+    ucp_config_t, ucp_conn_request_h, ucp_context_h, ucp_datatype_t, ucp_ep_close_flags_t,
+    ucp_ep_close_nbx, ucp_ep_create, ucp_ep_h, ucp_ep_params_t, ucp_ep_rkey_unpack,
+    ucp_listener_create, ucp_listener_destroy, ucp_listener_h, ucp_listener_params_t,
+    ucp_listener_reject, ucp_mem_h, ucp_mem_map, ucp_mem_map_params_t, ucp_mem_unmap, ucp_params_t,
+    ucp_request_cancel, ucp_request_check_status, ucp_request_free, ucp_request_param_t,
+    ucp_rkey_buffer_release, ucp_rkey_destroy, ucp_rkey_h, ucp_rkey_pack, ucp_tag_message_h,
+    ucp_tag_probe_nb, ucp_tag_recv_info_t, ucp_tag_t, ucp_worker_create, ucp_worker_destroy,
+    ucp_worker_get_address, ucp_worker_h, ucp_worker_params_t, ucp_worker_progress,
+    ucp_worker_release_address, ucs_status_ptr_t, ucs_status_string, ucs_status_t,
+    UCP_EP_CLOSE_FLAG_FORCE, UCP_OP_ATTR_FIELD_FLAGS, UCS_ERR_INVALID_PARAM, UCS_ERR_TIMED_OUT,
+    UCS_INPROGRESS, UCS_OK,
+};
+use std::ffi::{CStr, CString};
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub type Result<T> = std::result::Result<T, ucs_status_t>;
+
+/// How long dropping an endpoint waits for it to be closed
+const FORCE_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Turn `status` into a `Result`.
+pub fn check(status: ucs_status_t) -> Result<()> {
+    if status == UCS_OK {
+        Ok(())
+    } else {
+        Err(status)
+    }
+}
+
+/// Return the description of `status`.
+pub fn status_string(status: ucs_status_t) -> String {
+    unsafe {
+        CStr::from_ptr(ucs_status_string(status))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Return the datatype of contiguous elements of `size` bytes
+/// (`ucp_dt_make_contig()`).
+pub fn dt_make_contig(size: usize) -> ucp_datatype_t {
+    unsafe { rust_ucp_dt_make_contig(size) as ucp_datatype_t }
+}
+
+/// UCX configuration, read from the `UCX_*` environment variables.
+pub struct Config {
+    raw: *mut ucp_config_t,
+}
+
+impl Config {
+    /// Read the configuration from the environment.
+    pub fn read() -> Result<Config> {
+        let mut raw = MaybeUninit::<*mut ucp_config_t>::uninit();
+        unsafe {
+            check(ucp_config_read(
+                std::ptr::null(),
+                std::ptr::null(),
+                raw.as_mut_ptr(),
+            ))?;
+            Ok(Config {
+                raw: raw.assume_init(),
+            })
+        }
+    }
+
+    /// Set the variable `name`, without the `UCX_` prefix, to `value`.
+    pub fn modify(&mut self, name: &str, value: &str) -> Result<()> {
+        let name = CString::new(name).map_err(|_| UCS_ERR_INVALID_PARAM)?;
+        let value = CString::new(value).map_err(|_| UCS_ERR_INVALID_PARAM)?;
+        unsafe { check(ucp_config_modify(self.raw, name.as_ptr(), value.as_ptr())) }
+    }
+
+    pub fn as_raw(&self) -> *mut ucp_config_t {
+        self.raw
+    }
+}
+
+impl Drop for Config {
+    fn drop(&mut self) {
+        unsafe { ucp_config_release(self.raw) }
+    }
+}
+
+/// UCP context, cleaned up once the last worker and memory handle using it
+/// are gone.
+pub struct UcpContext {
+    raw: ucp_context_h,
+}
+
+impl UcpContext {
+    /// Create a context with `params`, using `config` instead of the
+    /// configuration from the environment if given.
+    ///
+    /// # Safety
+    ///
+    /// Pointers and callbacks in `params` have to be valid.
+    pub unsafe fn new(params: &ucp_params_t, config: Option<&Config>) -> Result<UcpContext> {
+        let mut raw = MaybeUninit::<ucp_context_h>::uninit();
+        let config = config.map_or(std::ptr::null(), |config| config.raw as *const _);
+        check(rust_ucp_init(params, config, raw.as_mut_ptr()))?;
+        Ok(UcpContext {
+            raw: raw.assume_init(),
+        })
+    }
+
+    pub fn as_raw(&self) -> ucp_context_h {
+        self.raw
+    }
+}
+
+impl Drop for UcpContext {
+    fn drop(&mut self) {
+        unsafe { ucp_cleanup(self.raw) }
+    }
+}
+
+/// UCP worker, destroyed once the last endpoint using it is gone.
+pub struct Worker {
+    raw: ucp_worker_h,
+    context: Rc<UcpContext>,
+}
+
+impl Worker {
+    /// Create a worker on `context`.
+    ///
+    /// # Safety
+    ///
+    /// Pointers in `params` have to be valid.
+    pub unsafe fn new(context: &Rc<UcpContext>, params: &ucp_worker_params_t) -> Result<Worker> {
+        let mut raw = MaybeUninit::<ucp_worker_h>::uninit();
+        check(ucp_worker_create(context.raw, params, raw.as_mut_ptr()))?;
+        Ok(Worker {
+            raw: raw.assume_init(),
+            context: Rc::clone(context),
+        })
+    }
+
+    pub fn as_raw(&self) -> ucp_worker_h {
+        self.raw
+    }
+
+    pub fn context(&self) -> &Rc<UcpContext> {
+        &self.context
+    }
+
+    /// Progress all communication on the worker, returning the number of
+    /// events that were handled.
+    pub fn progress(&self) -> u32 {
+        unsafe { ucp_worker_progress(self.raw) as u32 }
+    }
+
+    /// Return the address other processes can connect to the worker with.
+    pub fn address(&self) -> Result<Vec<u8>> {
+        let mut address = MaybeUninit::uninit();
+        let mut len = MaybeUninit::<usize>::uninit();
+        unsafe {
+            check(ucp_worker_get_address(
+                self.raw,
+                address.as_mut_ptr(),
+                len.as_mut_ptr(),
+            ))?;
+            let address = address.assume_init();
+            let out = std::slice::from_raw_parts(address as *const u8, len.assume_init()).to_vec();
+            ucp_worker_release_address(self.raw, address);
+            Ok(out)
+        }
+    }
+
+    /// Take the first message matching `tag` under `tag_mask` off the
+    /// unexpected queue (`ucp_tag_probe_nb()` with `remove` set), returning
+    /// it with its tag and length. The message has to be received with
+    /// `ucp_tag_msg_recv_nbx()`.
+    pub fn tag_probe(
+        &self,
+        tag: ucp_tag_t,
+        tag_mask: ucp_tag_t,
+    ) -> Option<(ucp_tag_message_h, ucp_tag_recv_info_t)> {
+        let mut info = MaybeUninit::<ucp_tag_recv_info_t>::uninit();
+        unsafe {
+            let message = ucp_tag_probe_nb(self.raw, tag, tag_mask, 1, info.as_mut_ptr());
+            (!message.is_null()).then(|| (message, info.assume_init()))
+        }
+    }
+
+    /// Progress the worker until `req` has completed, giving up with
+    /// `UCS_ERR_TIMED_OUT` after `timeout`. The request is then released
+    /// and left to complete in the background.
+    pub fn wait(&self, req: RequestPtr, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match req.status() {
+                UCS_INPROGRESS if Instant::now() > deadline => return Err(UCS_ERR_TIMED_OUT),
+                UCS_INPROGRESS => self.progress(),
+                status => return check(status),
+            };
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        unsafe { ucp_worker_destroy(self.raw) }
+    }
+}
+
+/// Connection to another worker. Dropping it closes the connection by
+/// force, without waiting for outstanding operations. If even that doesn't
+/// finish in time, the close is left to complete in the background.
+pub struct Endpoint {
+    raw: ucp_ep_h,
+    worker: Rc<Worker>,
+}
+
+impl Endpoint {
+    /// Create an endpoint on `worker`.
+    ///
+    /// # Safety
+    ///
+    /// Pointers and callbacks in `params`, like the remote address or the
+    /// error handler, have to be valid.
+    pub unsafe fn new(worker: &Rc<Worker>, params: &ucp_ep_params_t) -> Result<Endpoint> {
+        let mut raw = MaybeUninit::<ucp_ep_h>::uninit();
+        check(ucp_ep_create(worker.raw, params, raw.as_mut_ptr()))?;
+        Ok(Endpoint {
+            raw: raw.assume_init(),
+            worker: Rc::clone(worker),
+        })
+    }
+
+    pub fn as_raw(&self) -> ucp_ep_h {
+        self.raw
+    }
+
+    pub fn worker(&self) -> &Rc<Worker> {
+        &self.worker
+    }
+
+    /// Close the endpoint with `flags`, either `UCP_EP_CLOSE_FLAG_FORCE` or 0
+    /// to wait until everything sent on it has been delivered. Gives up with
+    /// `UCS_ERR_TIMED_OUT` after `timeout`.
+    pub fn close(self, flags: ucp_ep_close_flags_t, timeout: Duration) -> Result<()> {
+        let ep = ManuallyDrop::new(self);
+        let result = ep.close_nbx(flags, timeout);
+        // The worker reference goes away with the endpoint
+        drop(unsafe { std::ptr::read(&ep.worker) });
+        result
+    }
+
+    fn close_nbx(&self, flags: ucp_ep_close_flags_t, timeout: Duration) -> Result<()> {
+        let param = ucp_request_param_t {
+            op_attr_mask: UCP_OP_ATTR_FIELD_FLAGS,
+            flags,
+            ..Default::default()
+        };
+        unsafe {
+            self.worker.wait(
+                RequestPtr::from_raw(ucp_ep_close_nbx(self.raw, &param)),
+                timeout,
+            )
+        }
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        // A forced close can't fail in a way that could be handled here
+        let _ = self.close_nbx(UCP_EP_CLOSE_FLAG_FORCE, FORCE_CLOSE_TIMEOUT);
+    }
+}
+
+/// Listener for client-server connections on a socket address, destroyed on
+/// drop.
+pub struct Listener {
+    raw: ucp_listener_h,
+    worker: Rc<Worker>,
+}
+
+impl Listener {
+    /// Create a listener on `worker`.
+    ///
+    /// # Safety
+    ///
+    /// Pointers and callbacks in `params`, like the socket address or the
+    /// connection handler, have to be valid. The argument of the connection
+    /// handler has to stay valid until the listener is dropped.
+    pub unsafe fn new(worker: &Rc<Worker>, params: &ucp_listener_params_t) -> Result<Listener> {
+        let mut raw = MaybeUninit::<ucp_listener_h>::uninit();
+        check(ucp_listener_create(worker.raw, params, raw.as_mut_ptr()))?;
+        Ok(Listener {
+            raw: raw.assume_init(),
+            worker: Rc::clone(worker),
+        })
+    }
+
+    pub fn as_raw(&self) -> ucp_listener_h {
+        self.raw
+    }
+
+    pub fn worker(&self) -> &Rc<Worker> {
+        &self.worker
+    }
+
+    /// Reject a connection request instead of creating an endpoint for it.
+    ///
+    /// # Safety
+    ///
+    /// `request` has to come from this listener's connection handler and
+    /// can't have been accepted or rejected already.
+    pub unsafe fn reject(&self, request: ucp_conn_request_h) -> Result<()> {
+        check(ucp_listener_reject(self.raw, request))
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        unsafe { ucp_listener_destroy(self.raw) }
+    }
+}
+
+/// Memory registered with a context, unregistered on drop.
+pub struct MemHandle {
+    raw: ucp_mem_h,
+    context: Rc<UcpContext>,
+}
+
+impl MemHandle {
+    /// Register memory with `context`.
+    ///
+    /// # Safety
+    ///
+    /// The memory described by `params` has to stay valid until the handle is
+    /// dropped.
+    pub unsafe fn map(
+        context: &Rc<UcpContext>,
+        params: &ucp_mem_map_params_t,
+    ) -> Result<MemHandle> {
+        let mut raw = MaybeUninit::<ucp_mem_h>::uninit();
+        check(ucp_mem_map(context.raw, params, raw.as_mut_ptr()))?;
+        Ok(MemHandle {
+            raw: raw.assume_init(),
+            context: Rc::clone(context),
+        })
+    }
+
+    pub fn as_raw(&self) -> ucp_mem_h {
+        self.raw
+    }
+
+    /// Pack the remote key other processes can access the memory with.
+    pub fn pack_rkey(&self) -> Result<Vec<u8>> {
+        let mut buf = MaybeUninit::<*mut c_void>::uninit();
+        let mut size = MaybeUninit::<usize>::uninit();
+        unsafe {
+            check(ucp_rkey_pack(
+                self.context.raw,
+                self.raw,
+                buf.as_mut_ptr(),
+                size.as_mut_ptr(),
+            ))?;
+            let buf = buf.assume_init();
+            let rkey = std::slice::from_raw_parts(buf as *const u8, size.assume_init()).to_vec();
+            ucp_rkey_buffer_release(buf);
+            Ok(rkey)
+        }
+    }
+}
+
+impl Drop for MemHandle {
+    fn drop(&mut self) {
+        // Nothing can be done about failing to unregister
+        let _ = unsafe { ucp_mem_unmap(self.context.raw, self.raw) };
+    }
+}
+
+/// Remote key for accessing another process's memory, destroyed on drop.
+/// It keeps the endpoint it was unpacked for alive, since the key can't be
+/// used once that is closed.
+pub struct RemoteKey {
+    raw: ucp_rkey_h,
+    ep: Rc<Endpoint>,
+}
+
+impl RemoteKey {
+    /// Unpack a remote key packed by the owner of the memory, for use with
+    /// `ep`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` has to start with a key returned by `MemHandle::pack_rkey()`,
+    /// since UCX trusts its contents.
+    pub unsafe fn unpack(ep: &Rc<Endpoint>, buf: &[u8]) -> Result<RemoteKey> {
+        let mut raw = MaybeUninit::<ucp_rkey_h>::uninit();
+        check(ucp_ep_rkey_unpack(
+            ep.raw,
+            buf.as_ptr() as *const _,
+            raw.as_mut_ptr(),
+        ))?;
+        Ok(RemoteKey {
+            raw: raw.assume_init(),
+            ep: Rc::clone(ep),
+        })
+    }
+
+    pub fn as_raw(&self) -> ucp_rkey_h {
+        self.raw
+    }
+
+    pub fn endpoint(&self) -> &Rc<Endpoint> {
+        &self.ep
+    }
+}
+
+impl Drop for RemoteKey {
+    fn drop(&mut self) {
+        // The endpoint only goes after this, with the fields
+        unsafe { ucp_rkey_destroy(self.raw) }
+    }
+}
+
+/// Status pointer returned by a non-blocking operation: an actual request
+/// while the operation is in progress, or the status of an operation that
+/// completed or failed right away. Requests are freed on drop, which lets
+/// the operation go on in the background.
+pub struct RequestPtr {
+    raw: ucs_status_ptr_t,
+}
+
+impl RequestPtr {
+    /// Take ownership of the status pointer of an operation.
+    ///
+    /// # Safety
+    ///
+    /// `raw` has to be a status pointer returned by UCP that isn't owned by
+    /// anything else.
+    pub unsafe fn from_raw(raw: ucs_status_ptr_t) -> RequestPtr {
+        RequestPtr { raw }
+    }
+
+    /// Return a pointer for an operation that has already completed.
+    pub fn completed() -> RequestPtr {
+        RequestPtr {
+            raw: std::ptr::null_mut(),
+        }
+    }
+
+    /// Return true if this is an actual request (`UCS_PTR_IS_PTR()`).
+    pub fn is_ptr(&self) -> bool {
+        unsafe { rust_ucs_ptr_is_ptr(self.raw) != 0 }
+    }
+
+    /// Return true if the operation failed right away (`UCS_PTR_IS_ERR()`).
+    pub fn is_err(&self) -> bool {
+        unsafe { rust_ucs_ptr_is_err(self.raw) != 0 }
+    }
+
+    /// Return `UCS_INPROGRESS` while the operation is in progress, and its
+    /// final status afterwards (`UCS_PTR_STATUS()` for operations that
+    /// didn't need a request).
+    pub fn status(&self) -> ucs_status_t {
+        unsafe {
+            if self.is_ptr() {
+                ucp_request_check_status(self.raw)
+            } else {
+                rust_ucs_ptr_status(self.raw)
+            }
+        }
+    }
+
+    /// Cancel the operation, which then completes with `UCS_ERR_CANCELED`.
+    pub fn cancel(&self, worker: &Worker) {
+        if self.is_ptr() {
+            unsafe { ucp_request_cancel(worker.raw, self.raw) }
+        }
+    }
+
+    pub fn as_raw(&self) -> ucs_status_ptr_t {
+        self.raw
+    }
+
+    /// Give up ownership of the request without freeing it.
+    pub fn into_raw(self) -> ucs_status_ptr_t {
+        let raw = self.raw;
+        std::mem::forget(self);
+        raw
+    }
+}
+
+impl Drop for RequestPtr {
+    fn drop(&mut self) {
+        if self.is_ptr() {
+            unsafe { ucp_request_free(self.raw) }
+        }
+    }
+}